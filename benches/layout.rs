@@ -0,0 +1,106 @@
+//! Compares the typed std430 model buffers with the previous layout, where
+//! every model was packed into one array of floats and the shader rebuilt the
+//! rotation from euler angles for every ray.
+//!
+//! Run with `cargo bench --bench layout`.
+
+use std::time::{Duration, Instant};
+use cgmath::{Matrix3, Rad, Vector3};
+use hugengine::color::Color;
+use hugengine::geospace::Transform;
+use hugengine::gpu::{self, GpuLayout};
+use hugengine::material::Material;
+use hugengine::models::{generate, ModelManager, ModelProperty, ModelType};
+use hugengine::{col, define_material};
+
+const MODELS: u32 = 4096;
+const RAYS: usize = 256;
+
+fn scene() -> ModelManager {
+    let mut mm = ModelManager::new();
+    let min = Vector3::new(-50.0, 0.0, -50.0);
+    let max = Vector3::new(50.0, 10.0, 50.0);
+    for (i, tf) in generate::scatter(MODELS, 1, min, max, (0.5, 2.0)).into_iter().enumerate() {
+        mm.add_new(ModelProperty {
+            t: if i % 2 == 0 { ModelType::Sphere } else { ModelType::Plane },
+            tf: Transform { pitch: 0.3, rotate: 0.1, ..tf },
+            color: col!(),
+            material: define_material!(0.5),
+        });
+    }
+    mm
+}
+
+/// Runs `f` until a second has passed, returns the time per run
+fn bench<F: FnMut() -> f32>(name: &str, mut f: F) -> Duration {
+    let start = Instant::now();
+    let mut runs = 0;
+    let mut acc = 0.0;
+    while start.elapsed() < Duration::from_secs(1) {
+        acc += f();
+        runs += 1;
+    }
+    let per_run = start.elapsed() / runs;
+    // printing the result keeps the work from being optimized away
+    println!("{:<32} {:>12?} per run ({})", name, per_run, acc);
+    per_run
+}
+
+/// Same as `euler_transform` in the shader
+fn euler(h: f32, p: f32, r: f32) -> Matrix3<f32> {
+    Matrix3::from_angle_z(Rad(r)) * Matrix3::from_angle_x(Rad(p)) * Matrix3::from_angle_y(Rad(h))
+}
+
+fn main() {
+    let mut mm = scene();
+    let models: Vec<&ModelProperty> = mm.iter().map(|(_, m)| m).collect();
+
+    let legacy_pack = bench("pack, float array", || {
+        let mut props = Vec::new();
+        let mut index = Vec::new();
+        for model in models.iter() {
+            index.push(model.t.get_id());
+            index.push(props.len() as i32);
+            gpu::push(&mut props, *model);
+        }
+        props.len() as f32
+    });
+    drop(models);
+    let typed_pack = bench("pack, typed arrays", || mm.create_ss_buffers().index.len() as f32);
+
+    // the normal of every plane, for every ray, as the shader computes it
+    let mut legacy = Vec::new();
+    for (_, model) in mm.iter() {
+        if let ModelType::Plane = model.t {
+            gpu::push(&mut legacy, model);
+        }
+    }
+    let stride = ModelProperty::SIZE / 4;
+    let rotation = gpu::offset_of(ModelProperty::FIELDS, &["tf", "head"]).unwrap() / 4;
+    let legacy_read = bench("plane normals, euler angles", || {
+        let mut acc = 0.0;
+        for _ in 0..RAYS {
+            for plane in legacy.chunks(stride) {
+                let r = &plane[rotation..rotation + 3];
+                acc += (euler(r[0], r[1], r[2]) * Vector3::unit_y()).y;
+            }
+        }
+        acc
+    });
+
+    let planes = mm.create_ss_buffers().planes;
+    let typed_read = bench("plane normals, precomputed", || {
+        let mut acc = 0.0;
+        for _ in 0..RAYS {
+            for plane in planes.iter() {
+                // the rotated y axis is the second column
+                acc += plane.model.rotation.0[1][1];
+            }
+        }
+        acc
+    });
+
+    println!();
+    println!("packing: {:.2}x", legacy_pack.as_secs_f64() / typed_pack.as_secs_f64());
+    println!("reading: {:.2}x", legacy_read.as_secs_f64() / typed_read.as_secs_f64());
+}
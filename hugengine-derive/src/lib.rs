@@ -0,0 +1,110 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+/// Derives `hugengine::gpu::GpuLayout` for a struct with named fields. The
+/// fields are laid out in declaration order following the std430 rules, only
+/// the last field may be of variable size.
+///
+/// The glsl struct is named after the type, `#[gpu(glsl = "Name")]` renames
+/// it.
+#[proc_macro_derive(GpuLayout, attributes(gpu))]
+pub fn derive_gpu_layout(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let glsl = match glsl_name(&input) {
+        Ok(glsl) => glsl,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            _ => return error(name, "GpuLayout can only be derived for structs with named fields"),
+        },
+        _ => return error(name, "GpuLayout can only be derived for structs"),
+    };
+    if fields.is_empty() {
+        return error(name, "GpuLayout needs at least one field");
+    }
+
+    let layout = quote!(::hugengine::gpu::GpuLayout);
+    let idents: Vec<_> = fields.iter().map(|f| f.ident.as_ref().unwrap()).collect();
+    let names: Vec<_> = idents.iter().map(|i| i.to_string()).collect();
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+
+    // offset of the field `k`, the end of the previous field aligned
+    let offset = |k: usize| -> TokenStream2 {
+        let before = &types[..k];
+        let this = types[k];
+        quote!({
+            let mut offset = 0;
+            #( offset = ::hugengine::gpu::align_up(offset, <#before as #layout>::ALIGN) + <#before as #layout>::SIZE; )*
+            ::hugengine::gpu::align_up(offset, <#this as #layout>::ALIGN)
+        })
+    };
+    let offsets: Vec<_> = (0..fields.len()).map(offset).collect();
+    let end = offset(fields.len() - 1);
+    let last_type = types[fields.len() - 1];
+    let last = idents[fields.len() - 1];
+    let indices = 0..fields.len();
+    let fixed = &idents[..fields.len() - 1];
+
+    let expanded = quote! {
+        impl #impl_generics #layout for #name #ty_generics #where_clause {
+            const ALIGN: usize = {
+                let mut align = 4;
+                #( if <#types as #layout>::ALIGN > align { align = <#types as #layout>::ALIGN; } )*
+                align
+            };
+            const SIZE: usize = ::hugengine::gpu::align_up(#end + <#last_type as #layout>::SIZE, Self::ALIGN);
+            const GLSL: &'static str = #glsl;
+            const FIELDS: &'static [::hugengine::gpu::GpuField] = &[
+                #( ::hugengine::gpu::GpuField {
+                    name: #names,
+                    glsl: <#types as #layout>::GLSL,
+                    offset: #offsets,
+                    size: <#types as #layout>::SIZE,
+                    fields: <#types as #layout>::FIELDS,
+                }, )*
+            ];
+
+            fn tail_len(&self) -> usize {
+                #( debug_assert_eq!(#layout::tail_len(&self.#fixed), 0, "only the last field may vary in size"); )*
+                #layout::tail_len(&self.#last)
+            }
+
+            fn write_to(&self, out: &mut [f32]) {
+                #( #layout::write_to(&self.#idents, &mut out[Self::FIELDS[#indices].offset / 4..]); )*
+            }
+        }
+    };
+    expanded.into()
+}
+
+/// The name given by `#[gpu(glsl = "...")]`, or the name of the type
+fn glsl_name(input: &DeriveInput) -> syn::Result<String> {
+    let mut glsl = input.ident.to_string();
+    for attr in input.attrs.iter().filter(|a| a.path.is_ident("gpu")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[gpu(glsl = \"...\")]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("glsl") => match nv.lit {
+                    Lit::Str(s) => glsl = s.value(),
+                    lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+                },
+                other => return Err(syn::Error::new_spanned(other, "unknown gpu attribute")),
+            }
+        }
+    }
+    Ok(glsl)
+}
+
+fn error(name: &syn::Ident, message: &str) -> TokenStream {
+    syn::Error::new(name.span(), message).to_compile_error().into()
+}
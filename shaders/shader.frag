@@ -16,18 +16,103 @@ uniform ivec2 uScreenResolution;    // screen-resolution in pixels, int
 uniform float uCamPitch;            // Camera pitch in rad
 uniform float uCamYaw;              // Camera yaw in rad
 uniform vec3  uCamPos;              // Camera position
+uniform int   uCamProjection;       // one of the *_ID projections
+uniform float uCamFov;              // horizontal field of view in rad
+uniform float uCamAperture;         // lens diameter, 0 for a pinhole
+uniform float uCamFocusDistance;    // distance to the plane in focus
 uniform samplerCube uSkyBoxSampler; // Skybox texture sampler
-
-// NOTE: this binding is statically typed in the shaderdevprogram.
+uniform samplerCube uIrradianceSampler; // diffuse light of the skybox, see `ibl`
+uniform samplerCube uSpecularSampler;   // skybox blurred by roughness over the mip levels
+uniform sampler2D uBrdfLutSampler;      // fresnel scale and bias by n.v and roughness
+uniform int   uSelectedModel;       // slot of the model picked in shaderdev, -1 for none
+
+// shaderdev replaces the line below with generated code. It defines the
+// model type ids and other constants shared with the rust side, the model
+// structs of `models::std430` and `float sdf_custom(int id, vec3 p)` for the
+// custom sdf snippets of the scene
+// <generated>
+
+// NOTE: these bindings are statically typed in the shaderdevprogram.
 layout(std430, binding = 4) buffer ModelIndex 
 {
-    // [object0_type, object0_index, object1_type, object1_index,...]
+    // [object0_type, object0_index, object1_type, object1_index,...], the
+    // index points into the array of the type
     int uModelIndex[];
 };
 
-layout(std430, binding = 5) buffer ModelProperties 
+layout(std430, binding = 5) buffer Spheres
+{
+    Sphere uSpheres[];
+};
+
+layout(std430, binding = 6) buffer Planes
+{
+    Plane uPlanes[];
+};
+
+layout(std430, binding = 7) buffer Boxes
+{
+    Box uBoxes[];
+};
+
+layout(std430, binding = 8) buffer Sdfs
+{
+    Sdf uSdfs[];
+};
+
+// postfix programs of the sdf models, see `models::sdf`
+layout(std430, binding = 9) buffer SdfPrograms
+{
+    float uSdfPrograms[];
+};
+
+// layout of `bvh::BvhNode`. Interior nodes have count == 0 and their children
+// at left_first and left_first + 1, leaves reference `count` primitives
+// starting at left_first.
+struct BvhNode {
+    vec3 bmin;
+    int left_first;
+    vec3 bmax;
+    int count;
+};
+
+layout(std430, binding = 10) buffer BvhNodes
+{
+    BvhNode uBvhNodes[];
+};
+
+// primitives referenced by the leaves. Indices below the number of models
+// are slots in uModelIndex, the rest are indices into uInstances offset by
+// the number of models.
+layout(std430, binding = 11) buffer BvhPrimitives
+{
+    int uBvhPrims[];
+};
+
+// primitives that lack bounds (planes), always tested
+layout(std430, binding = 12) buffer BvhUnbounded
 {
-    float uModelProps[];
+    int uBvhUnbounded[];
+};
+
+// layout of `models::InstanceData`, the prototype at `slot` is drawn with
+// this transform applied on top of its own
+struct Instance {
+    vec3 position;
+    float scale;
+    vec3 rotation; // head, pitch, roll
+    int slot;
+};
+
+layout(std430, binding = 13) buffer Instances
+{
+    Instance uInstances[];
+};
+
+// struct Light is generated from `lights::LightData`
+layout(std430, binding = 14) buffer Lights
+{
+    Light uLights[];
 };
 
 // SHAPING FUNCTIONS
@@ -146,84 +231,21 @@ struct EulerRotation {
     float roll;
 };
 
-struct Properties {
-    // Transform
-    vec3 position;
-    float scale;
-    mat4 rotation;
-
-    // Color properties
-    vec3 color;
-
-    // Material properties
-    float reflectance;
-};
-
-/*  
- *  Retrieving data from the properties buffer
- */
-
-// fetch vec4 of the position for the object `i`
-vec4 get_position(int i)
-{
-    return vec4(uModelProps[i],
-                uModelProps[i+1],
-                uModelProps[i+2],
-                1.0
-           );
-}
-
-// fetch vec3 of the position for the object `i`
-vec3 get_position3(int i)
-{
-    return vec3(uModelProps[i],
-                uModelProps[i+1],
-                uModelProps[i+2]
-           );
-}
-
-float get_scale(int i) {
-    return uModelProps[i+3];
-}
-
-vec3 get_color3(int i) {
-    return vec3(uModelProps[i+7], uModelProps[i+8], uModelProps[i+9]);
-}
-
-vec4 get_color(int i) {
-    return vec4(uModelProps[i+7], uModelProps[i+8], uModelProps[i+9], 1.0);
-}
-
-mat4 get_rotation(int i) {
-    return euler_transform(
-            uModelProps[i+4],
-            uModelProps[i+5],
-            uModelProps[i+6]);
-}
-
-float get_reflectance(int i) {
-    return uModelProps[i+10];
+// The transform and surface of the model at `index` of the array of its type
+ModelData model_data(in int model_type, in int index) {
+    switch (model_type) {
+        case SPHERE_ID:
+            return uSpheres[index].model;
+        case PLANE_ID:
+            return uPlanes[index].model;
+        case BOX_ID:
+            return uBoxes[index].model;
+        default: // SDF_ID
+            return uSdfs[index].model;
+    }
 }
 
 
-// populate the Properties-struct `prop` for the object `i`
-#define fetch_props(prop, i)                \
-    prop.position = get_position3(i);       \
-    prop.scale = get_scale(i);              \
-    prop.rotation = get_rotation(i);        \
-    prop.color = vec3(                      \
-            uModelProps[i+7],               \
-            uModelProps[i+8],               \
-            uModelProps[i+9]                \
-            );                              \
-    prop.reflectance = uModelProps[i+10];   
-
-#define SPHERE_ID 0
-#define BOX_ID 1
-#define PLANE_ID 2
-
-
-
 // TODO: implement robus version, check sec3.9.4
 // Solves a quadratic equation, not accepting complex solutions.
 // Returns:
@@ -276,7 +298,7 @@ vec3 normal_sphere(in vec3 sphere, in vec3 point) {
 }
 
 bool draw_sphere(
-        in int i,
+        in ModelData m,
         in vec3 ray_o,
         in vec3 ray_d,
         in float t_max,
@@ -284,14 +306,13 @@ bool draw_sphere(
         out vec3 normal
         )
 {
-    // fetch data
-    vec3 sphere_pos = get_position3(i);
+    vec3 sphere_pos = m.position;
     float t_entry, t_exit;
     if (intersection_sphere(
                 ray_o,
                 ray_d,
                 sphere_pos,
-                get_scale(i),
+                m.scale,
                 t_max,
                 t_entry,
                 t_exit
@@ -306,16 +327,14 @@ bool draw_sphere(
     }
 }
 
-vec4 draw_box(int i, vec2 pos) {
-    Properties props;
-    fetch_props(props, i);
-    vec3 dims = vec3(uModelProps[i+11], uModelProps[i+12], uModelProps[i+13]);
+vec4 draw_box(in Box b, vec2 pos) {
+    vec3 position = b.model.position;
     if (
-        props.position.x <= pos.x && pos.x <= props.position.x + dims.x &&
-        props.position.y <= pos.y && pos.y <= props.position.y + dims.y
+        position.x <= pos.x && pos.x <= position.x + b.dims.x &&
+        position.y <= pos.y && pos.y <= position.y + b.dims.y
     ) 
     {
-        return vec4(props.color, 1.0);
+        return vec4(b.model.color, 1.0);
     } else {
         return vec4(0.0,0.0,0.0,0.0);
     }
@@ -341,7 +360,7 @@ bool intersection_plane(
 }
 
 bool draw_plane(
-        in int i,
+        in ModelData m,
         in vec3 ray_o,
         in vec3 ray_d,
         in float t_max,
@@ -349,39 +368,440 @@ bool draw_plane(
         out vec3 normal
         )
 {
-    mat4 rotm = get_rotation(i);
-
-    vec4 temp = rotm * vec4(0.0, 1.0, 0.0, 1.0); // TODO: change this to match rotation-transform
-    normal = normalize(temp.xyz);
+    normal = normalize(m.rotation * vec3(0.0, 1.0, 0.0));
     
     return intersection_plane(
             ray_o, 
             ray_d, 
-            get_position3(i),
+            m.position,
             normal,
             t_max,
             t_intersect);
 }
 
-void create_ray(in float fov_deg, out vec3 ray_o, out vec3 ray_d) {
-    float half_fov_rad = deg2rad(fov_deg / 2.0);
-    float z = uRatio / tan(half_fov_rad);
+float sd_box(in vec3 q, in vec3 b) {
+    vec3 d = abs(q) - b;
+    return length(max(d, 0.0)) + min(max(d.x, max(d.y, d.z)), 0.0);
+}
+
+// polynomial smooth minimum, blends within distance k
+float smin(in float a, in float b, in float k) {
+    float h = max(k - abs(a - b), 0.0) / k;
+    return min(a, b) - h * h * k * 0.25;
+}
+
+// Evaluates the postfix program starting at `program` at the local point
+// `p`, mirrors `sdf::evaluate`
+float sdf_evaluate(in int program, in int program_len, in vec3 p) {
+    float stack[SDF_STACK_SIZE];
+    int top = 0;
+    int pc = program;
+    int end = pc + program_len;
+
+    while (pc < end) {
+        int op = int(uSdfPrograms[pc]);
+        if (op < SDF_OP_UNION) {
+            // primitives, [op, offset.xyz, args...]
+            vec3 q = p - vec3(uSdfPrograms[pc + 1], uSdfPrograms[pc + 2], uSdfPrograms[pc + 3]);
+            int a = pc + 4;
+            float r = 0.0;
+            float d;
+            switch (op) {
+                case SDF_OP_SPHERE:
+                    d = length(q) - uSdfPrograms[a];
+                    pc += 5;
+                    break;
+                case SDF_OP_BOX:
+                    d = sd_box(q, vec3(uSdfPrograms[a], uSdfPrograms[a + 1], uSdfPrograms[a + 2]));
+                    pc += 7;
+                    break;
+                case SDF_OP_ROUND_BOX:
+                    r = uSdfPrograms[a + 3];
+                    d = sd_box(q, vec3(uSdfPrograms[a], uSdfPrograms[a + 1], uSdfPrograms[a + 2]) - r) - r;
+                    pc += 8;
+                    break;
+                case SDF_OP_TORUS:
+                    d = length(vec2(length(q.xz) - uSdfPrograms[a], q.y)) - uSdfPrograms[a + 1];
+                    pc += 6;
+                    break;
+                default: // SDF_OP_CUSTOM
+                    d = sdf_custom(int(uSdfPrograms[a]), q);
+                    pc += 6;
+                    break;
+            }
+            stack[top++] = d;
+        } else {
+            // operators, [op] or [op, k]
+            top--;
+            float a = stack[top - 1];
+            float b = stack[top];
+            float d;
+            switch (op) {
+                case SDF_OP_UNION:
+                    d = min(a, b);
+                    pc += 1;
+                    break;
+                case SDF_OP_INTERSECTION:
+                    d = max(a, b);
+                    pc += 1;
+                    break;
+                case SDF_OP_DIFFERENCE:
+                    d = max(a, -b);
+                    pc += 1;
+                    break;
+                case SDF_OP_SMOOTH_UNION:
+                    d = smin(a, b, uSdfPrograms[pc + 1]);
+                    pc += 2;
+                    break;
+                case SDF_OP_SMOOTH_INTERSECTION:
+                    d = -smin(-a, -b, uSdfPrograms[pc + 1]);
+                    pc += 2;
+                    break;
+                default: // SDF_OP_SMOOTH_DIFFERENCE
+                    d = -smin(-a, b, uSdfPrograms[pc + 1]);
+                    pc += 2;
+                    break;
+            }
+            stack[top - 1] = d;
+        }
+    }
+    return stack[0];
+}
+
+// distance in world space to the sdf model `sdf`
+float sdf_distance(in Sdf sdf, in vec3 p, in mat3 to_local) {
+    float s = sdf.model.scale;
+    return sdf_evaluate(sdf.program, sdf.program_len, to_local * (p - sdf.model.position) / s) * s;
+}
+
+// Sphere-traces the sdf model `sdf`, mirrors `sdf::march`
+bool draw_sdf(
+        in Sdf sdf,
+        in vec3 ray_o,
+        in vec3 ray_d,
+        in float t_max,
+        out float t_intersect,
+        out vec3 normal
+        )
+{
+    mat3 to_local = transpose(sdf.model.rotation);
+
+    float t = SDF_MIN_T;
+    for (int step = 0; step < SDF_MAX_STEPS; step++) {
+        if (t >= t_max) {
+            return false;
+        }
+        vec3 p = ray_o + t * ray_d;
+        float d = abs(sdf_distance(sdf, p, to_local));
+        if (d < SDF_EPSILON) {
+            vec2 h = vec2(SDF_MIN_T, 0.0);
+            t_intersect = t;
+            normal = normalize(vec3(
+                sdf_distance(sdf, p + h.xyy, to_local) - sdf_distance(sdf, p - h.xyy, to_local),
+                sdf_distance(sdf, p + h.yxy, to_local) - sdf_distance(sdf, p - h.yxy, to_local),
+                sdf_distance(sdf, p + h.yyx, to_local) - sdf_distance(sdf, p - h.yyx, to_local)
+            ));
+            return true;
+        }
+        t += d;
+    }
+    return false;
+}
+
+// Slab test, same as `Aabb::intersect`
+bool intersection_aabb(
+        in vec3 ray_o,
+        in vec3 ray_d,
+        in vec3 inv_d,
+        in vec3 bmin,
+        in vec3 bmax,
+        in float t_max,
+        out float t_near
+        )
+{
+    float t_far = 1.0 / 0.0;
+    t_near = -t_far;
+    for (int axis = 0; axis < 3; axis++) {
+        // parallel to the slab, avoids `0 * inf` when the origin is on its border
+        if (ray_d[axis] == 0.0) {
+            if (ray_o[axis] < bmin[axis] || bmax[axis] < ray_o[axis]) {
+                return false;
+            }
+            continue;
+        }
+        float t0 = (bmin[axis] - ray_o[axis]) * inv_d[axis];
+        float t1 = (bmax[axis] - ray_o[axis]) * inv_d[axis];
+        t_near = max(t_near, min(t0, t1));
+        t_far = min(t_far, max(t0, t1));
+    }
+    return t_near <= t_far && 0.0 < t_far && t_near < t_max;
+}
 
+// Intersects the model at position `slot` in uModelIndex
+bool intersect_model(
+        in int slot,
+        in vec3 ray_o,
+        in vec3 ray_d,
+        in float t_max,
+        out float t_intersect,
+        out vec3 normal
+        )
+{
+    int model_type = uModelIndex[2 * slot];
+    int index = uModelIndex[2 * slot + 1];
+
+    switch (model_type) {
+        case SPHERE_ID:
+            return draw_sphere(uSpheres[index].model, ray_o, ray_d, t_max, t_intersect, normal);
+        case PLANE_ID:
+            return draw_plane(uPlanes[index].model, ray_o, ray_d, t_max, t_intersect, normal);
+        case SDF_ID:
+            return draw_sdf(uSdfs[index], ray_o, ray_d, t_max, t_intersect, normal);
+        default:
+            return false;
+    }
+}
 
-    ray_o = vec3(uRatio * ScreenPos.x, ScreenPos.y, 0.0);
-    ray_d = normalize(ray_o - vec3(0.0,0.0,z));
+// Intersects a primitive of the bvh, either a model or an instance, mirrors
+// `Primitive::intersect`. `slot` is set to the model that was tested.
+bool intersect_primitive(
+        in int prim,
+        in vec3 ray_o,
+        in vec3 ray_d,
+        in float t_max,
+        out float t_intersect,
+        out vec3 normal,
+        out int slot
+        )
+{
+    int n_models = uModelIndex.length() / 2;
+    if (prim < n_models) {
+        slot = prim;
+        return intersect_model(prim, ray_o, ray_d, t_max, t_intersect, normal);
+    }
+
+    // move the ray into the space of the instance, distances scale with it
+    Instance inst = uInstances[prim - n_models];
+    slot = inst.slot;
+    mat3 rot = mat3(euler_transform(inst.rotation.x, inst.rotation.y, inst.rotation.z));
+    mat3 to_local = transpose(rot);
+    vec3 local_o = to_local * (ray_o - inst.position) / inst.scale;
+    vec3 local_d = to_local * ray_d;
+
+    if (intersect_model(inst.slot, local_o, local_d, t_max / inst.scale, t_intersect, normal)) {
+        t_intersect *= inst.scale;
+        normal = rot * normal;
+        return true;
+    }
+    return false;
+}
+
+// Finds the closest model along the ray by traversing the bvh, mirrors
+// `Bvh::intersect`. Returns false if nothing was hit, instances report the
+// slot of their prototype.
+bool trace_scene(
+        in vec3 ray_o,
+        in vec3 ray_d,
+        in float t_max,
+        out float t_closest,
+        out int slot_closest,
+        out vec3 normal_closest
+        )
+{
+    t_closest = t_max;
+    slot_closest = -1;
+
+    float t_intersect;
+    vec3 normal;
+    int slot;
+
+    if (uBvhNodes.length() > 0) {
+        vec3 inv_d = 1.0 / ray_d;
+        int stack[BVH_STACK_SIZE];
+        int top = 0;
+        float t_node;
+
+        if (intersection_aabb(ray_o, ray_d, inv_d, uBvhNodes[0].bmin, uBvhNodes[0].bmax, t_closest, t_node)) {
+            stack[top++] = 0;
+        }
+
+        while (top > 0) {
+            BvhNode node = uBvhNodes[stack[--top]];
+
+            if (node.count > 0) {
+                for (int i = node.left_first; i < node.left_first + node.count; i++) {
+                    if (intersect_primitive(uBvhPrims[i], ray_o, ray_d, t_closest, t_intersect, normal, slot) && t_intersect < t_closest) {
+                        t_closest = t_intersect;
+                        slot_closest = slot;
+                        normal_closest = normal;
+                    }
+                }
+                continue;
+            }
+
+            int left = node.left_first;
+            float t_left, t_right;
+            bool hit_left = intersection_aabb(ray_o, ray_d, inv_d, uBvhNodes[left].bmin, uBvhNodes[left].bmax, t_closest, t_left);
+            bool hit_right = intersection_aabb(ray_o, ray_d, inv_d, uBvhNodes[left + 1].bmin, uBvhNodes[left + 1].bmax, t_closest, t_right);
+
+            // push the far child first so that the near one is visited first
+            if (hit_left && hit_right) {
+                bool right_first = t_right < t_left;
+                stack[top++] = right_first ? left : left + 1;
+                stack[top++] = right_first ? left + 1 : left;
+            } else if (hit_left) {
+                stack[top++] = left;
+            } else if (hit_right) {
+                stack[top++] = left + 1;
+            }
+        }
+    }
+
+    for (int i = 0; i < uBvhUnbounded.length(); i++) {
+        if (intersect_primitive(uBvhUnbounded[i], ray_o, ray_d, t_closest, t_intersect, normal, slot) && t_intersect < t_closest) {
+            t_closest = t_intersect;
+            slot_closest = slot;
+            normal_closest = normal;
+        }
+    }
+
+    return slot_closest != -1;
+}
+
+// LIGHTS
+
+#define AREA_LIGHT_SAMPLES 4
+
+// pcg hash, seeded per pixel and frame in main
+uint rng_state;
+float rand() {
+    rng_state = rng_state * 747796405u + 2891336453u;
+    uint word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
+    return float((word >> 22u) ^ word) / 4294967296.0;
+}
+
+vec3 random_direction() {
+    float z = 2.0 * rand() - 1.0;
+    float a = 2.0 * PI * rand();
+    float r = sqrt(1.0 - z * z);
+    return vec3(r * cos(a), r * sin(a), z);
+}
+
+// Picks a point on the light as seen from p. Returns the radiance arriving
+// at p, l is the direction towards the light and dist the distance to it.
+vec3 light_sample(in Light light, in vec3 p, out vec3 l, out float dist) {
+    vec3 radiance = light.color * light.intensity;
+
+    if (light.kind == DIRECTIONAL_LIGHT_ID) {
+        l = -light.direction;
+        dist = 1e30;
+        return radiance;
+    }
+
+    vec3 q = light.position;
+    if (light.kind == SPHERE_LIGHT_ID) {
+        // only the half facing p is visible
+        vec3 d = random_direction();
+        q += light.radius * (dot(d, p - light.position) < 0.0 ? -d : d);
+    } else if (light.kind == RECT_LIGHT_ID) {
+        vec3 t = normalize(cross(abs(light.direction.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0), light.direction));
+        vec3 b = cross(light.direction, t);
+        q += (rand() - 0.5) * light.width * t + (rand() - 0.5) * light.height * b;
+    }
+
+    vec3 d = q - p;
+    dist = length(d);
+    l = d / dist;
+    radiance /= dist * dist;
+
+    if (light.kind == SPOT_LIGHT_ID) {
+        radiance *= smoothstep(light.cos_outer, light.cos_inner, dot(-l, light.direction));
+    } else if (light.kind == RECT_LIGHT_ID) {
+        radiance *= max(dot(-l, light.direction), 0.0);
+    }
+    return radiance;
+}
+
+// true if no model blocks the way from p towards l within dist
+bool unoccluded(in vec3 p, in vec3 l, in float dist) {
+    float t;
+    int slot;
+    vec3 normal;
+    return !trace_scene(p, l, dist, t, slot, normal);
+}
+
+// Diffuse light arriving at p with normal n from all lights, with shadows
+vec3 direct_light(in vec3 p, in vec3 n, in vec3 albedo) {
+    vec3 total = vec3(0.0);
+
+    for (int i = 0; i < uLights.length(); i++) {
+        Light light = uLights[i];
+        bool area = light.kind == SPHERE_LIGHT_ID || light.kind == RECT_LIGHT_ID;
+        int samples = area ? AREA_LIGHT_SAMPLES : 1;
+
+        vec3 sum = vec3(0.0);
+        for (int s = 0; s < samples; s++) {
+            vec3 l;
+            float dist;
+            vec3 radiance = light_sample(light, p, l, dist);
+            float cos_theta = dot(n, l);
+            if (cos_theta > 0.0 && any(greaterThan(radiance, vec3(0.0))) && unoccluded(p + n * BUMB_AMOUNT, l, dist)) {
+                sum += radiance * cos_theta;
+            }
+        }
+        total += sum / float(samples);
+    }
+    return albedo / PI * total;
+}
+
+// Ray through the current pixel, mirrors `Camera::generate_lens_ray`.
+// Returns false if the projection has no ray for the pixel.
+bool create_ray(out vec3 ray_o, out vec3 ray_d) {
+    vec2 screen = vec2(uRatio * ScreenPos.x, ScreenPos.y);
+    float half_fov = uCamFov / 2.0;
+
+    ray_o = vec3(0.0);
+    if (uCamProjection == ORTHOGRAPHIC_ID) {
+        ray_o = vec3(screen * uCamFocusDistance * tan(half_fov) / uRatio, 0.0);
+        ray_d = vec3(0.0, 0.0, -1.0);
+    } else if (uCamProjection == FISHEYE_ID) {
+        float r = length(screen);
+        float theta = r / uRatio * half_fov;
+        if (theta > PI) {
+            return false;
+        }
+        ray_d = r > 0.0 ? vec3(sin(theta) * screen / r, -cos(theta)) : vec3(0.0, 0.0, -1.0);
+    } else if (uCamProjection == EQUIRECTANGULAR_ID) {
+        float longitude = ScreenPos.x * PI;
+        float latitude = ScreenPos.y * PI / 2.0;
+        ray_d = vec3(cos(latitude) * sin(longitude), sin(latitude), -cos(latitude) * cos(longitude));
+    } else {
+        ray_d = normalize(vec3(screen, -uRatio / tan(half_fov)));
+    }
+
+    // thin lens, every ray through the lens meets on the focus plane
+    if (uCamAperture > 0.0) {
+        bool planar = uCamProjection == PERSPECTIVE_ID || uCamProjection == ORTHOGRAPHIC_ID;
+        vec3 focus = ray_o + ray_d * (planar ? uCamFocusDistance / -ray_d.z : uCamFocusDistance);
+        float radius = 0.5 * uCamAperture * sqrt(rand());
+        float angle = 2.0 * PI * rand();
+        ray_o += vec3(radius * cos(angle), radius * sin(angle), 0.0);
+        ray_d = normalize(focus - ray_o);
+    }
 
     // create rotation matrix, pitch then yaw
     mat4 rot = rotate_y(uCamYaw) * rotate_x(uCamPitch);
     mat4 trans = translation(uCamPos);
 
-    ray_d = (rot * vec4(ray_d, 1.0)).xyz;
+    ray_d = (rot * vec4(ray_d, 0.0)).xyz;
     ray_o = (trans * rot * vec4(ray_o, 1.0)).xyz;
+    return true;
 }
 
 struct ReflectionData {
+    int slot;        // slot of the model, instances report their prototype
     int model_type;  // type of object
-    int model_index; // index into properties of object this reflection represents
+    int model_index; // index into the array of the type of the object
     vec3 normal;     // normal-vector of the intersection point
     vec3 ray_dir;    // direction of ray
     vec3 ray_orig;   // ray origin
@@ -390,8 +810,13 @@ struct ReflectionData {
 
 void main()
 {
+    rng_state = uint(gl_FragCoord.x) * 1973u + uint(gl_FragCoord.y) * 9277u + uint(uTime * 1000.0) * 26699u;
+
     vec3 ray_o, ray_d;
-    create_ray(110.0, ray_o, ray_d);
+    if (!create_ray(ray_o, ray_d)) {
+        FragColor = vec4(0.0, 0.0, 0.0, 1.0);
+        return;
+    }
 
     float t_max = 1000.0;
     int reflections = 0;
@@ -399,53 +824,14 @@ void main()
     ReflectionData reflect_stack[MAX_REFLECTIONS];
 
     for (; reflections < MAX_REFLECTIONS; reflections++) {
-        float t_smallest = t_max;
-        int i_smallest = 0;
+        float t_smallest;
+        int i_smallest;
         vec3 reflection_normal;
 
-        // loop through every object
-        bool intersection_found = false;
-        for (int i = 0; i < uModelIndex.length() / 2; i++) 
-        {
-            vec3 temp_reflection;
-            int model_type = uModelIndex[2 * i];
-            int prop_index = uModelIndex[2 * i + 1];
-            float t_intersect;
-            bool flag = false;
-
-            switch (model_type) {
-                case SPHERE_ID: // sphere
-                    flag = draw_sphere(prop_index, 
-                                ray_o, 
-                                ray_d, 
-                                t_max,
-                                t_intersect,
-                                temp_reflection);
-                    break;
-                case PLANE_ID: // plane
-                    flag = draw_plane(prop_index, 
-                                ray_o, 
-                                ray_d, 
-                                t_max,
-                                t_intersect,
-                                temp_reflection);
-                    break;
-                default:
-                    break;
-            }
-
-            // any intersection happened
-            if (flag) {
-                if (t_intersect < t_smallest) {
-                    t_smallest = t_intersect;
-                    i_smallest = i;
-                    reflection_normal = temp_reflection;
-                }
-                intersection_found = true;
-            }
-        }
+        bool intersection_found = trace_scene(ray_o, ray_d, t_max, t_smallest, i_smallest, reflection_normal);
 
         if (intersection_found) {
+            reflect_stack[reflections].slot        = i_smallest;
             reflect_stack[reflections].model_type  = uModelIndex[i_smallest * 2];
             reflect_stack[reflections].model_index = uModelIndex[i_smallest * 2 + 1];
             reflect_stack[reflections].normal      = reflection_normal;
@@ -467,18 +853,40 @@ void main()
     // vec4 sunset_color = rgb(246, 109, 73);
     // FragColor = blend(sky_color, sunset_color, smoothstep(0.0, 0.5, latitude)) * smoothstep(-0.3, 0.0, latitude);
 
-    FragColor = texture(uSkyBoxSampler, ray_d);
+    // a rough surface reflects a blurred sky
+    float sky_roughness = reflections > 0 ? model_data(reflect_stack[reflections - 1].model_type, reflect_stack[reflections - 1].model_index).roughness : 0.0;
+    if (sky_roughness > 0.0) {
+        FragColor = vec4(textureLod(uSpecularSampler, ray_d, sky_roughness * float(IBL_SPECULAR_LEVELS - 1)).rgb, 1.0);
+    } else {
+        FragColor = texture(uSkyBoxSampler, ray_d);
+    }
     
     // run through stack backwards
     for (int i = reflections - 1; i >= 0; i--) {
         // get base color
-        vec4 object_color = get_color(reflect_stack[i].model_index);
+        ModelData m = model_data(reflect_stack[i].model_type, reflect_stack[i].model_index);
+        vec4 object_color = vec4(m.color, 1.0);
         vec3 surface_normal = reflect_stack[i].normal;
 
-        // apply shading
-        object_color = object_color * squash(smoothstep(-0.2, 1.0, dot(surface_normal, vec3(INV_SQRT2, INV_SQRT2, 0.0))), 0.2, 1.0);
+        // apply shading, scenes without lights get a fixed light from the side
+        if (uLights.length() > 0) {
+            vec3 p = reflect_stack[i].ray_orig + reflect_stack[i].ray_dir * reflect_stack[i].dist;
+            vec3 ambient = m.color * texture(uIrradianceSampler, surface_normal).rgb;
+            object_color = vec4(direct_light(p, surface_normal, m.color) + ambient, 1.0);
+        } else {
+            object_color = object_color * squash(smoothstep(-0.2, 1.0, dot(surface_normal, vec3(INV_SQRT2, INV_SQRT2, 0.0))), 0.2, 1.0);
+        }
 
-        // blend with previous color (sky if no prev object)
-        FragColor = blend(object_color, FragColor, get_reflectance(reflect_stack[i].model_index)); 
+        // blend with previous color (sky if no prev object), rough surfaces
+        // lose some of the reflection at grazing angles
+        float n_dot_v = max(dot(surface_normal, -reflect_stack[i].ray_dir), 0.0);
+        vec2 brdf = texture(uBrdfLutSampler, vec2(n_dot_v, m.roughness)).rg;
+        vec4 reflected = vec4(FragColor.rgb * (brdf.x + brdf.y), FragColor.a);
+        FragColor = blend(object_color, reflected, m.reflectance); 
+
+        // tint the selected model, mirrors included
+        if (reflect_stack[i].slot == uSelectedModel) {
+            FragColor.rgb = mix(FragColor.rgb, vec3(1.0, 0.6, 0.1), 0.5);
+        }
     }
 }
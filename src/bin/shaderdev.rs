@@ -2,18 +2,29 @@ extern crate glfw;
 extern crate glad_gl;
 extern crate cgmath;
 
-use glfw::{Action, Context, Key};
+use glfw::{Action, Context, Key, MouseButton};
 use glad_gl::gl;
 use std::ptr;
 use std::ffi::CString;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::os::raw::c_void;
 use std::io;
 use std::fs;
+use std::process;
 use std::time::{SystemTime, Instant, Duration};
 
-use hugengine::models::parser::parse_scene;
+use hugengine::models::{BufferChanges, ModelId, ModelManager};
+use hugengine::models::std430::Record;
+use hugengine::camera::Camera;
+use hugengine::color::Color;
+use hugengine::editor::{Drag, Edit};
+use hugengine::history::{Change, Command, History};
+use hugengine::ray::Ray;
+use hugengine::scene::{Scene, Uniform};
+use hugengine::environment::{CubeMap, Environment};
+use hugengine::ibl::{self, Ibl};
+use hugengine::image::Image;
 
 use cgmath::{Vector3, Basis3, Rotation, Rotation3, Rad, Zero, InnerSpace};
 
@@ -24,15 +35,36 @@ const UNIFORM_RATIO:         &'static str = "uRatio";
 const UNIFORM_CAMPITCH:      &'static str = "uCamPitch";
 const UNIFORM_CAMYAW:        &'static str = "uCamYaw";
 const UNIFORM_CAMPOS:        &'static str = "uCamPos";
+const UNIFORM_CAMPROJECTION: &'static str = "uCamProjection";
+const UNIFORM_CAMFOV:        &'static str = "uCamFov";
+const UNIFORM_CAMAPERTURE:   &'static str = "uCamAperture";
+const UNIFORM_CAMFOCUS:      &'static str = "uCamFocusDistance";
 const UNIFORM_SKYBOXSAMPLER: &'static str = "uSkyBoxSampler";
+const UNIFORM_IRRADIANCESAMPLER: &'static str = "uIrradianceSampler";
+const UNIFORM_SPECULARSAMPLER: &'static str = "uSpecularSampler";
+const UNIFORM_BRDFLUTSAMPLER: &'static str = "uBrdfLutSampler";
+const UNIFORM_SELECTEDMODEL: &'static str = "uSelectedModel";
 
 const CAMERA_MOVEMENTSPEED: f32 = 1.0;  // Units per second
 const CAMERA_ROTATIONSPEED: f32 = 1.0;  // Rad per second
+const CAMERA_TRANSITION: f32 = 0.5;     // Seconds to blend between cameras
+const SKY_UPDATE_INTERVAL: Duration = Duration::from_millis(100); // Shortest time between skybox updates
+const MODEL_MOVEMENTSPEED: f32 = 1.0;   // Units per second
+const MODEL_ROTATIONSPEED: f32 = 1.0;   // Rad per second
+const MODEL_SCALESPEED: f32 = 2.0;      // Factor per second
+const MODEL_REFLECTANCESPEED: f32 = 0.5; // Reflectance per second
+
+/// Colors the selected model cycles through
+const PALETTE: [&'static str; 8] = ["white", "red", "orange", "gold", "lime green", "dodger blue", "purple", "black"];
 
 const WINDOW_HEIGHT: i32 = 720;
 const WINDOW_WIDTH: i32 = 1280;
 
 const PATH_SCENE_TEMPLATE: &'static str = "scenes/template.yaml";
+const PATH_IBL_CACHE: &'static str = "target/ibl";
+
+/// Line in the fragment shader that is replaced by code generated from the scene
+const GENERATED_MARKER: &'static str = "// <generated>";
 
 // const UNIFORM_MODEL_INDEX: &'static str = "ModelIndex";
 // const UNIFORM_MODEL_PROPS: &'static str = "ModelProperties";
@@ -71,6 +103,32 @@ pub enum CompileProgramError {
     LinkProgramError(String),
 }
 
+/// Sets a uniform declared by the scene, false if the shader doesn't use it
+fn set_custom_uniform(id: gl::GLuint, varname: &str, uniform: &Uniform) -> bool {
+    let cstring = match CString::new(varname) {
+        Ok(s) => s,
+        Err(_) => {
+            return false;
+        }
+    };
+
+    unsafe {
+        let location = gl::GetUniformLocation(id, cstring.as_c_str().as_ptr());
+        if location == -1 {
+            return false;
+        }
+        gl::UseProgram(id);
+        match uniform {
+            Uniform::Int(i) => gl::Uniform1i(location, *i),
+            Uniform::Float(x) => gl::Uniform1f(location, *x),
+            Uniform::Vec2(v) => gl::Uniform2f(location, v[0], v[1]),
+            Uniform::Vec3(v) => gl::Uniform3f(location, v[0], v[1], v[2]),
+            Uniform::Vec4(v) => gl::Uniform4f(location, v[0], v[1], v[2], v[3]),
+        }
+        true
+    }
+}
+
 fn set_uniform1f(id: gl::GLuint, varname: &str, val: f32) -> bool {
     let cstring = match CString::new(varname) {
         Ok(s) => s,
@@ -152,6 +210,218 @@ fn set_uniform3f(id: gl::GLuint, varname: &str, v0: gl::GLfloat, v1: gl::GLfloat
 
 }
 
+/// Replaces the data-store of a shader storage buffer with `data`
+fn upload_ssbo<T>(buffer: gl::GLuint, data: &[T]) {
+    unsafe {
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, buffer);
+        gl::BufferData(
+            gl::SHADER_STORAGE_BUFFER,
+            mem::size_of_val(data).try_into().unwrap(),
+            data.as_ptr() as *const c_void,
+            gl::DYNAMIC_DRAW
+        );
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+    }
+}
+
+/// Overwrites part of a shader storage buffer, `offset` counts elements
+fn update_ssbo<T>(buffer: gl::GLuint, offset: usize, data: &[T]) {
+    unsafe {
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, buffer);
+        gl::BufferSubData(
+            gl::SHADER_STORAGE_BUFFER,
+            (offset * mem::size_of::<T>()).try_into().unwrap(),
+            mem::size_of_val(data).try_into().unwrap(),
+            data.as_ptr() as *const c_void,
+        );
+        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+    }
+}
+
+/// Binding point of the light buffer, `Lights` in the shader
+const LIGHT_BINDING: gl::GLuint = 14;
+
+/// Uploads the lights and the sun if they changed, or if `force` is set
+fn sync_lights(buffer: gl::GLuint, scene: &mut Scene, force: bool) {
+    if force || scene.lights.is_modified() {
+        upload_ssbo(buffer, &scene.light_data());
+    }
+}
+
+/// The shader storage buffers describing the models of the scene
+struct ModelBuffers {
+    index: gl::GLuint,
+    spheres: gl::GLuint,
+    planes: gl::GLuint,
+    boxes: gl::GLuint,
+    sdfs: gl::GLuint,
+    sdf_programs: gl::GLuint,
+    bvh_nodes: gl::GLuint,
+    bvh_prims: gl::GLuint,
+    bvh_unbounded: gl::GLuint,
+    instances: gl::GLuint,
+}
+
+impl ModelBuffers {
+    fn new() -> Self {
+        let mut ids: [gl::GLuint; 10] = [0; 10];
+        unsafe {
+            gl::GenBuffers(10, ids.as_mut_ptr());
+        }
+        ModelBuffers {
+            index: ids[0],
+            spheres: ids[1],
+            planes: ids[2],
+            boxes: ids[3],
+            sdfs: ids[4],
+            sdf_programs: ids[5],
+            bvh_nodes: ids[6],
+            bvh_prims: ids[7],
+            bvh_unbounded: ids[8],
+            instances: ids[9],
+        }
+    }
+
+    /// Binds every buffer to the binding point the shader expects
+    fn bind(&self) {
+        let bindings = [
+            (4, self.index),
+            (5, self.spheres),
+            (6, self.planes),
+            (7, self.boxes),
+            (8, self.sdfs),
+            (9, self.sdf_programs),
+            (10, self.bvh_nodes),
+            (11, self.bvh_prims),
+            (12, self.bvh_unbounded),
+            (13, self.instances),
+        ];
+        unsafe {
+            for (binding, buffer) in bindings {
+                gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, binding, buffer);
+            }
+        }
+    }
+
+    /// Re-creates every buffer
+    fn upload(&self, model_manager: &mut ModelManager) {
+        let packed = model_manager.create_ss_buffers();
+        upload_ssbo(self.index, &packed.index);
+        upload_ssbo(self.spheres, &packed.spheres);
+        upload_ssbo(self.planes, &packed.planes);
+        upload_ssbo(self.boxes, &packed.boxes);
+        upload_ssbo(self.sdfs, &packed.sdfs);
+        upload_ssbo(self.sdf_programs, &packed.sdf_programs);
+        self.upload_derived(model_manager);
+    }
+
+    /// The instances and the acceleration structure, cheap enough to always
+    /// be rebuilt
+    fn upload_derived(&self, model_manager: &ModelManager) {
+        upload_ssbo(self.instances, &model_manager.create_instance_buffer());
+
+        let bvh = model_manager.build_bvh();
+        upload_ssbo(self.bvh_nodes, bvh.nodes());
+        upload_ssbo(self.bvh_prims, bvh.primitives());
+        upload_ssbo(self.bvh_unbounded, bvh.unbounded());
+    }
+
+    /// Uploads whatever changed in the model manager, in place if possible
+    fn sync(&self, model_manager: &mut ModelManager) {
+        match model_manager.take_changes() {
+            BufferChanges::None => {}
+            BufferChanges::Rebuild => self.upload(model_manager),
+            BufferChanges::Partial(ranges) => {
+                for range in ranges {
+                    update_ssbo(self.index, 2 * range.slot, &range.index);
+                    let at = range.index[1] as usize;
+                    match range.record {
+                        Record::None => {}
+                        Record::Sphere(data) => update_ssbo(self.spheres, at, &[data]),
+                        Record::Plane(data) => update_ssbo(self.planes, at, &[data]),
+                        Record::Box(data) => update_ssbo(self.boxes, at, &[data]),
+                        Record::Sdf(data, program) => {
+                            update_ssbo(self.sdfs, at, &[data]);
+                            update_ssbo(self.sdf_programs, data.program as usize, &program);
+                        }
+                    }
+                }
+                self.upload_derived(model_manager);
+            }
+        }
+    }
+}
+
+/// Writes the faces of every mip level into the cube map texture `texobj`,
+/// floats so hdr images keep their range
+fn upload_cube_levels(texobj: gl::GLuint, levels: &[CubeMap]) {
+    unsafe {
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, texobj);
+        for (level, cube) in levels.iter().enumerate() {
+            let size = cube.size as i32;
+            for (i, face) in cube.faces.iter().enumerate() {
+                gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as u32, level as i32, gl::RGB16F as i32, size, size, 0, gl::RGB, gl::FLOAT, face.as_ptr() as *const c_void);
+            }
+        }
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+}
+
+/// Creates a cube map texture with one mip level per element of `levels`
+fn create_cube_texture(levels: &[CubeMap]) -> gl::GLuint {
+    let mut texobj: gl::GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut texobj);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, texobj);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAX_LEVEL, levels.len() as i32 - 1);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+    upload_cube_levels(texobj, levels);
+    texobj
+}
+
+/// Writes the skybox and its mipmaps
+fn upload_skybox(texobj: gl::GLuint, cube: &CubeMap) {
+    upload_cube_levels(texobj, std::slice::from_ref(cube));
+    unsafe {
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, texobj);
+        gl::GenerateMipmap(gl::TEXTURE_CUBE_MAP);
+        gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
+    }
+}
+
+fn create_texture(image: &Image) -> gl::GLuint {
+    let mut texobj: gl::GLuint = 0;
+    unsafe {
+        gl::GenTextures(1, &mut texobj);
+        gl::BindTexture(gl::TEXTURE_2D, texobj);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGB16F as i32, image.width as i32, image.height as i32, 0, gl::RGB, gl::FLOAT, image.data.as_ptr() as *const c_void);
+        gl::BindTexture(gl::TEXTURE_2D, 0);
+    }
+    texobj
+}
+
+/// Image based lighting of the skybox, read from the cache or precomputed
+fn load_ibl(cube: &CubeMap) -> Ibl {
+    let path = Ibl::cache_path(PATH_IBL_CACHE, cube);
+    Ibl::read(&path).unwrap_or_else(|_| {
+        println!("precomputing image based lighting");
+        let ibl = Ibl::compute(cube);
+        if let Err(e) = ibl.write(&path) {
+            eprintln!("can't cache the image based lighting in {}: {}", path.display(), e);
+        }
+        ibl
+    })
+}
+
 fn compile_shader(shader_type: ShaderType, source: CString) -> Result<gl::GLuint, CompileProgramError> {
     let shader_id: gl::GLuint;
     let mut status: gl::GLint = 0;
@@ -191,7 +461,7 @@ fn compile_shader(shader_type: ShaderType, source: CString) -> Result<gl::GLuint
     }
 }
 
-fn compile_program<P: AsRef<Path>>(vert_p: P, frag_p: P) -> Result<gl::GLuint, CompileProgramError> {
+fn compile_program<P: AsRef<Path>>(vert_p: P, frag_p: P, generated: &str) -> Result<gl::GLuint, CompileProgramError> {
     use CompileProgramError::*;
 
     let vert_source = match fs::read_to_string(vert_p) {
@@ -207,7 +477,7 @@ fn compile_program<P: AsRef<Path>>(vert_p: P, frag_p: P) -> Result<gl::GLuint, C
     };
 
     let frag_source = match fs::read_to_string(frag_p) {
-        Ok(s) => match CString::new(s) {
+        Ok(s) => match CString::new(s.replacen(GENERATED_MARKER, generated, 1)) {
             Ok(cstr) => cstr,
             _ => {
                 return Err(CStringInitilizationError)
@@ -269,11 +539,37 @@ fn compile_program<P: AsRef<Path>>(vert_p: P, frag_p: P) -> Result<gl::GLuint, C
     }
 }
 
+/// Ray through the cursor, given in pixels from the top left of the window
+fn cursor_ray(view: &Camera, cursor: (f64, f64), width: i32, height: i32) -> Option<Ray> {
+    let mut camera = view.clone();
+    camera.ratio = width as f32 / height as f32;
+    let u = cursor.0 as f32 / width as f32;
+    let v = 1.0 - cursor.1 as f32 / height as f32;
+    camera.generate_ray(u, v)
+}
+
 fn last_modified<P: AsRef<Path>>(path: P) -> io::Result<SystemTime> {
     let metadata = fs::metadata(path)?;
     metadata.modified()
 }
 
+/// Modification times of the files a scene is read from, `None` for files
+/// that can't be read
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| last_modified(path).ok()).collect()
+}
+
+/// Where the edited scene is saved, next to the loaded file so that its
+/// comments, expressions and generated models are kept
+fn edited_path(scene_path: &str) -> PathBuf {
+    let path = Path::new(scene_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    if stem.ends_with(".edited") {
+        return path.to_path_buf();
+    }
+    path.with_file_name(format!("{}.edited.yaml", stem))
+}
+
 // TODO: make these a fallback if not specified
 static VERT_SHADER_PATH: &'static str = r#".\shaders\shader.vert"#;
 static FRAG_SHADER_PATH: &'static str = r#".\shaders\shader.frag"#;
@@ -308,6 +604,8 @@ fn main() {
 
     window.set_key_polling(true);
     window.set_size_polling(true);
+    window.set_cursor_pos_polling(true);
+    window.set_mouse_button_polling(true);
 
     window.make_current();
 
@@ -327,8 +625,6 @@ fn main() {
     let mut vbo: gl::GLuint = 0;
     let mut vao: gl::GLuint = 0;
     let mut ebo: gl::GLuint = 0;
-    let mut index_ssbo: gl::GLuint = 0;
-    let mut props_ssbo: gl::GLuint = 0;
     let mut skybox_texobj: gl::GLuint = 0;
     
     // A square that fills the screen
@@ -344,25 +640,28 @@ fn main() {
         1, 2, 3,
     ];
 
-    let mut sides: Vec<Vec<f32>> = (0..6).into_iter().map(|_| (0..256*256*3).into_iter().map(|_| 0.0).collect()).collect();
-    sides[0].as_mut_slice().chunks_mut(3).for_each(|v| { v[0] = 1.0; });
-    sides[1].as_mut_slice().chunks_mut(3).for_each(|v| { v[0] = 0.5; });
-    sides[2].as_mut_slice().chunks_mut(3).for_each(|v| { v[1] = 1.0; });
-    sides[3].as_mut_slice().chunks_mut(3).for_each(|v| { v[1] = 0.5; });
-    sides[4].as_mut_slice().chunks_mut(3).for_each(|v| { v[2] = 1.0; });
-    sides[5].as_mut_slice().chunks_mut(3).for_each(|v| { v[2] = 0.5; });
 
     // Objects
-    let scene_source = fs::read_to_string(PATH_SCENE_TEMPLATE).expect("file exists");
-    let (mut model_manager, camera_prop) = parse_scene(scene_source.as_str()).expect("scene is correctly formatted");
-    let (model_indices, model_properties) = model_manager.create_ss_buffers();
+    let scene_path = std::env::args().nth(1).unwrap_or_else(|| PATH_SCENE_TEMPLATE.to_string());
+    let mut scene = Scene::load(&scene_path).unwrap_or_else(|e| {
+        eprintln!("can't load {}: {}", scene_path, e);
+        process::exit(1);
+    });
+    let mut generated_glsl = scene.glsl_header();
+    let cube = scene.environment.cube_map().unwrap_or_else(|e| {
+        eprintln!("can't load the skybox: {}", e);
+        process::exit(1);
+    });
+    let model_buffers = ModelBuffers::new();
+    let mut light_buffer: gl::GLuint = 0;
+    unsafe {
+        gl::GenBuffers(1, &mut light_buffer);
+    }
 
     unsafe {
         gl::GenBuffers(1, &mut vbo);
         gl::GenVertexArrays(1, &mut vao);
         gl::GenBuffers(1, &mut ebo);
-        gl::GenBuffers(1, &mut index_ssbo);
-        gl::GenBuffers(1, &mut props_ssbo);
         gl::GenTextures(1, &mut skybox_texobj);
 
         // create skybox
@@ -371,49 +670,23 @@ fn main() {
         gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
         gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
         gl::TexParameteri(gl::TEXTURE_CUBE_MAP, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-        
-        // allocate storage
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_X, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_Y, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_Y, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_Z, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-        gl::TexImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_Z, 0, gl::RGBA as i32, 256, 256, 0, gl::RGB, gl::FLOAT, 0 as *const c_void);
-
-        // write texture
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_X, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[0].as_ptr() as *const c_void);
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_X, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[1].as_ptr() as *const c_void);
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_Y, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[2].as_ptr() as *const c_void);
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_Y, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[3].as_ptr() as *const c_void);
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_POSITIVE_Z, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[4].as_ptr() as *const c_void);
-        gl::TexSubImage2D(gl::TEXTURE_CUBE_MAP_NEGATIVE_Z, 0, 0, 0, 256, 256, gl::RGB, gl::FLOAT, sides[5].as_ptr() as *const c_void);
-
-        gl::GenerateMipmap(gl::TEXTURE_CUBE_MAP);
-        
         gl::BindTexture(gl::TEXTURE_CUBE_MAP, 0);
 
         gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+    }
+    upload_skybox(skybox_texobj, &cube);
+    let mut uploaded_environment = scene.environment.clone();
+    let mut last_sky_update = Instant::now();
 
+    let mut ibl = load_ibl(&cube);
+    let irradiance_texobj = create_cube_texture(std::slice::from_ref(&ibl.irradiance));
+    let specular_texobj = create_cube_texture(&ibl.specular);
+    let brdf_lut_texobj = create_texture(&ibl.brdf_lut);
 
-
-        // store models in buffer
-        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, index_ssbo);
-        gl::BufferData(
-            gl::SHADER_STORAGE_BUFFER, 
-            (model_indices.len() * mem::size_of::<i32>()).try_into().unwrap(),
-            model_indices.as_ptr() as *const c_void, 
-            gl::STATIC_DRAW
-        );
-
-        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, props_ssbo);
-        gl::BufferData(
-            gl::SHADER_STORAGE_BUFFER, 
-            (model_properties.len() * mem::size_of::<f32>()).try_into().unwrap(),
-            model_properties.as_ptr() as *const c_void, 
-            gl::STATIC_DRAW
-        );
-
-        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+    unsafe {
+        // store models and lights in buffers
+        model_buffers.upload(&mut scene.models);
+        sync_lights(light_buffer, &mut scene, true);
 
 
         // store options in va
@@ -459,23 +732,77 @@ fn main() {
     let mut program_birth: Instant = Instant::now();
     let mut program_id: Option<gl::GLuint> = None;
 
-    let mut camera_position = Vector3::new(camera_prop.tf.x, camera_prop.tf.y, camera_prop.tf.z);
-    let mut camera_pitch: f32 = camera_prop.tf.pitch;
-    let mut camera_yaw: f32 = camera_prop.tf.head;
+    // the camera that is rendered, differs from the active one while blending
+    // from the previous camera, which is kept with the start of the blend
+    let mut view: Camera = scene.cameras.active().clone();
+    let mut transition: Option<(Camera, Instant)> = None;
+
+    // picking, the cursor in pixels from the top left of the window
+    let mut cursor = (0.0, 0.0);
+    let mut selected: Option<ModelId> = None;
+    let mut drag: Option<Drag> = None;
+    let mut palette_index = 0;
+    let mut history = History::default();
+
+    // the scene is read again whenever one of its files changes, the files
+    // it includes too
+    let mut scene_files = scene.files.clone();
+    let mut scene_times = modification_times(&scene_files);
 
     while !window.should_close() {
+        // push edited models to the gpu, recompile if custom sdf code changed
+        model_buffers.sync(&mut scene.models);
+        sync_lights(light_buffer, &mut scene, false);
+        let glsl = scene.glsl_header();
+        if glsl != generated_glsl {
+            generated_glsl = glsl;
+            vert_last_modified = SystemTime::UNIX_EPOCH;
+            last_check = SystemTime::UNIX_EPOCH;
+        }
+
         // check every second if program has been updated
         if let Ok(d) = SystemTime::now().duration_since(last_check) {
             if d > Duration::from_secs(1) {
                 last_check = SystemTime::now();
 
+                // reload the scene when it was edited, a scene with errors
+                // leaves the last good one in place
+                let times = modification_times(&scene_files);
+                if times != scene_times {
+                    // an edited skybox image keeps its path, it is loaded again
+                    let images = scene.environment.skybox.paths();
+                    let images_changed = scene_files
+                        .iter()
+                        .zip(times.iter().zip(&scene_times))
+                        .any(|(file, (new, old))| new != old && images.contains(&file));
+                    scene_times = times;
+                    match Scene::load(&scene_path) {
+                        Ok(reloaded) => {
+                            let changed = scene.reload(reloaded);
+                            if images_changed {
+                                uploaded_environment = Environment::new();
+                            }
+                            history.clear();
+                            selected = selected.filter(|id| scene.models.contains(*id));
+                            drag = None;
+                            sync_lights(light_buffer, &mut scene, true);
+                            if scene.files != scene_files {
+                                scene_files = scene.files.clone();
+                                scene_times = modification_times(&scene_files);
+                            }
+                            println!("reloaded {}, {} models changed", scene_path, changed);
+                        }
+                        Err(e) => eprintln!("can't reload {}: {}", scene_path, e),
+                    }
+                }
+
                 // check if files have been updated
                 match (last_modified(VERT_SHADER_PATH), last_modified(FRAG_SHADER_PATH)) {
                     (Ok(vert_time), Ok(frag_time)) => {
                         // recompile program if source has changed since last check
                         if (vert_time != vert_last_modified) | (frag_time != frag_last_modified) {
                             print!("compiling shaders...");
-                            match compile_program(VERT_SHADER_PATH, FRAG_SHADER_PATH) {
+                            match compile_program(VERT_SHADER_PATH, FRAG_SHADER_PATH, &generated_glsl) {
                                 // delete old program if compilation was successfull
                                 Ok(new_program_id) => {
                                     if let Some(id) = program_id {
@@ -498,13 +825,8 @@ fn main() {
                                     unsafe {
                                         gl::UseProgram(new_program_id);
 
-                                        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, index_ssbo);
-                                        gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 4, index_ssbo);
-
-                                        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, props_ssbo);
-                                        gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, 5, props_ssbo);
-
-                                        gl::BindBuffer(gl::SHADER_STORAGE_BUFFER, 0);
+                                        model_buffers.bind();
+                                        gl::BindBufferBase(gl::SHADER_STORAGE_BUFFER, LIGHT_BINDING, light_buffer);
                                     }
 
                                     program_birth = Instant::now();
@@ -551,28 +873,31 @@ fn main() {
         if delta_time > Duration::from_millis(33) {
             let mut camera_posv: Vector3<f32> = Vector3::zero();
 
-            // update camera position
-            if window.get_key(Key::W) == Action::Press {
+            // update camera position, unless the keys are part of a shortcut
+            let control = window.get_key(Key::LeftControl) == Action::Press
+                || window.get_key(Key::RightControl) == Action::Press;
+            let moving = |key| !control && window.get_key(key) == Action::Press;
+            if moving(Key::W) {
                 camera_posv.z = -1.0;
             }
 
-            if window.get_key(Key::A) == Action::Press {
+            if moving(Key::A) {
                 camera_posv.x = -1.0;
             }
 
-            if window.get_key(Key::S) == Action::Press {
+            if moving(Key::S) {
                 camera_posv.z = 1.0;
             }
 
-            if window.get_key(Key::D) == Action::Press {
+            if moving(Key::D) {
                 camera_posv.x = 1.0;
             }
 
-            if window.get_key(Key::Z) == Action::Press {
+            if moving(Key::Z) {
                 camera_posv.y = 1.0;
             }
 
-            if window.get_key(Key::X) == Action::Press {
+            if moving(Key::X) {
                 camera_posv.y = -1.0;
             }
             
@@ -585,28 +910,122 @@ fn main() {
             }
 
             // TODO: apply rotation to camera vector
+            let camera = scene.cameras.active_mut();
 
             // Camera orientation
             if window.get_key(Key::I) == Action::Press {
-                camera_pitch += dt * CAMERA_ROTATIONSPEED;
+                camera.tf.pitch += dt * CAMERA_ROTATIONSPEED;
             }
 
             if window.get_key(Key::J) == Action::Press {
-                camera_yaw += dt * CAMERA_ROTATIONSPEED;
+                camera.tf.head += dt * CAMERA_ROTATIONSPEED;
             }
 
             if window.get_key(Key::K) == Action::Press {
-                camera_pitch -= dt * CAMERA_ROTATIONSPEED;
+                camera.tf.pitch -= dt * CAMERA_ROTATIONSPEED;
             }
 
             if window.get_key(Key::L) == Action::Press {
-                camera_yaw -= dt * CAMERA_ROTATIONSPEED;
+                camera.tf.head -= dt * CAMERA_ROTATIONSPEED;
             }
 
             if mag > 0.0 {
-                let movement_rot = Basis3::from_angle_y(Rad(camera_yaw)) * Basis3::from_angle_x(Rad(camera_pitch));
+                let movement_rot = Basis3::from_angle_y(Rad(camera.tf.head)) * Basis3::from_angle_x(Rad(camera.tf.pitch));
+
+                let movement = movement_rot.rotate_vector(camera_posv);
+                camera.tf.x += movement.x;
+                camera.tf.y += movement.y;
+                camera.tf.z += movement.z;
+            }
 
-                camera_position += movement_rot.rotate_vector(camera_posv);
+            // move, turn, scale and tint the selected model while keys are held
+            if let Some(id) = selected {
+                let step = MODEL_MOVEMENTSPEED * dt;
+                let turn = MODEL_ROTATIONSPEED * dt;
+                let held = |key| window.get_key(key) == Action::Press;
+                let mut edits = Vec::new();
+
+                let movement = Vector3::new(
+                    (held(Key::Right) as i32 - held(Key::Left) as i32) as f32,
+                    (held(Key::PageUp) as i32 - held(Key::PageDown) as i32) as f32,
+                    (held(Key::Down) as i32 - held(Key::Up) as i32) as f32,
+                );
+                if movement != Vector3::zero() {
+                    edits.push(Edit::Translate(movement * step));
+                }
+                let head = (held(Key::Q) as i32 - held(Key::E) as i32) as f32;
+                let pitch = (held(Key::R) as i32 - held(Key::F) as i32) as f32;
+                if head != 0.0 || pitch != 0.0 {
+                    edits.push(Edit::Rotate { head: head * turn, pitch: pitch * turn, rotate: 0.0 });
+                }
+                let scale = (held(Key::Equal) as i32 - held(Key::Minus) as i32) as f32;
+                if scale != 0.0 {
+                    edits.push(Edit::Scale(MODEL_SCALESPEED.powf(scale * dt)));
+                }
+                let reflectance = (held(Key::RightBracket) as i32 - held(Key::LeftBracket) as i32) as f32;
+                if reflectance != 0.0 {
+                    edits.push(Edit::Reflectance(reflectance * MODEL_REFLECTANCESPEED * dt));
+                }
+
+                // holding keys down makes a single step to undo
+                if !edits.is_empty() && !history.is_grouping() {
+                    history.begin_group();
+                } else if edits.is_empty() && drag.is_none() {
+                    history.end_group();
+                }
+                for edit in &edits {
+                    if let Some(command) = edit.command(&scene.models, id) {
+                        history.apply(&mut scene, command);
+                    }
+                }
+            }
+
+            // a model that was undone away can't stay selected
+            for change in history.take_changes() {
+                if let Change::Model(id) = change {
+                    if selected == Some(id) && !scene.models.contains(id) {
+                        selected = None;
+                        drag = None;
+                    }
+                }
+            }
+
+            // ease from the previous camera to the active one
+            view = match &transition {
+                Some((from, start)) => {
+                    let t = Instant::now().duration_since(*start).as_secs_f32() / CAMERA_TRANSITION;
+                    if t < 1.0 {
+                        from.lerp(scene.cameras.active(), t * t * (3.0 - 2.0 * t))
+                    } else {
+                        transition = None;
+                        scene.cameras.active().clone()
+                    }
+                }
+                None => scene.cameras.active().clone(),
+            };
+
+            // an animated or changed sky is regenerated a few times a second
+            // at most, along with the sun and the lighting it gives
+            scene.environment.advance(dt);
+            if scene.environment != uploaded_environment && last_sky_update.elapsed() >= SKY_UPDATE_INTERVAL {
+                match scene.environment.cube_map() {
+                    Ok(cube) => {
+                        upload_skybox(skybox_texobj, &cube);
+                        if scene.environment.is_animated() {
+                            // prefiltering is too slow to follow the sun, the
+                            // reflections keep the old sky until it stops
+                            ibl.irradiance = ibl::irradiance_map(&cube);
+                        } else {
+                            ibl = load_ibl(&cube);
+                            upload_cube_levels(specular_texobj, &ibl.specular);
+                        }
+                        upload_cube_levels(irradiance_texobj, std::slice::from_ref(&ibl.irradiance));
+                        sync_lights(light_buffer, &mut scene, true);
+                    }
+                    Err(e) => eprintln!("can't load the skybox: {}", e),
+                }
+                uploaded_environment = scene.environment.clone();
+                last_sky_update = Instant::now();
             }
 
 
@@ -618,13 +1037,32 @@ fn main() {
                     let program_lifetime = Instant::now().duration_since(program_birth).as_secs_f32();
                     gl::UseProgram(id);
                     set_uniform1f(id, UNIFORM_TIME, program_lifetime);
-                    set_uniform1f(id, UNIFORM_CAMPITCH, camera_pitch);
-                    set_uniform1f(id, UNIFORM_CAMYAW, camera_yaw);
-                    set_uniform3f(id, UNIFORM_CAMPOS, camera_position.x, camera_position.y, camera_position.z);
+                    set_uniform1f(id, UNIFORM_CAMPITCH, view.tf.pitch);
+                    set_uniform1f(id, UNIFORM_CAMYAW, view.tf.head);
+                    set_uniform3f(id, UNIFORM_CAMPOS, view.tf.x, view.tf.y, view.tf.z);
+                    set_uniform1i(id, UNIFORM_CAMPROJECTION, view.projection.get_id());
+                    set_uniform1f(id, UNIFORM_CAMFOV, view.fov);
+                    set_uniform1f(id, UNIFORM_CAMAPERTURE, view.aperture);
+                    set_uniform1f(id, UNIFORM_CAMFOCUS, view.focus_distance);
+
+                    set_uniform1i(id, UNIFORM_SELECTEDMODEL, selected.map_or(-1, |m| m.index() as i32));
+
+                    for (name, uniform) in &scene.uniforms {
+                        set_custom_uniform(id, name, uniform);
+                    }
 
                     set_uniform1i(id, UNIFORM_SKYBOXSAMPLER, 0);
                     gl::ActiveTexture(gl::TEXTURE0);
                     gl::BindTexture(gl::TEXTURE_CUBE_MAP, skybox_texobj);
+                    set_uniform1i(id, UNIFORM_IRRADIANCESAMPLER, 1);
+                    gl::ActiveTexture(gl::TEXTURE1);
+                    gl::BindTexture(gl::TEXTURE_CUBE_MAP, irradiance_texobj);
+                    set_uniform1i(id, UNIFORM_SPECULARSAMPLER, 2);
+                    gl::ActiveTexture(gl::TEXTURE2);
+                    gl::BindTexture(gl::TEXTURE_CUBE_MAP, specular_texobj);
+                    set_uniform1i(id, UNIFORM_BRDFLUTSAMPLER, 3);
+                    gl::ActiveTexture(gl::TEXTURE3);
+                    gl::BindTexture(gl::TEXTURE_2D, brdf_lut_texobj);
 
                     gl::BindVertexArray(vao);
                     gl::DrawElements(gl::TRIANGLES, 6, gl::UNSIGNED_INT, 0 as *const c_void);
@@ -641,6 +1079,15 @@ fn main() {
                 glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                     window.set_should_close(true)
                 }
+                // number keys switch to the camera with that position in the scene
+                glfw::WindowEvent::Key(key, _, Action::Press, _)
+                    if (Key::Num1 as i32..=Key::Num9 as i32).contains(&(key as i32)) => {
+                    let index = (key as i32 - Key::Num1 as i32) as usize;
+                    if index != scene.cameras.active_index() && scene.cameras.set_active(index) {
+                        transition = Some((view.clone(), Instant::now()));
+                        println!("camera: {}", scene.cameras.active_name());
+                    }
+                }
                 glfw::WindowEvent::Size(w, h) => {
                     window_width = w;
                     window_height = h;
@@ -659,8 +1106,92 @@ fn main() {
                         gl::Viewport(0,0,window_width, window_height);
                     }
                 }
-                glfw::WindowEvent::CursorPos(xpos, ypos) => 
-                    println!("Cursor position: ({:?}, {:?})", xpos, ypos),
+                // the selected model cycles through the palette
+                glfw::WindowEvent::Key(Key::C, _, Action::Press, _) => {
+                    if let Some(id) = selected {
+                        palette_index = (palette_index + 1) % PALETTE.len();
+                        let color: Color = PALETTE[palette_index].parse().expect("palette colors are known");
+                        if let Some(command) = Edit::Color(color).command(&scene.models, id) {
+                            history.apply(&mut scene, command);
+                        }
+                        println!("color: {}", PALETTE[palette_index]);
+                    }
+                }
+                glfw::WindowEvent::Key(Key::Delete, _, Action::Press, _) => {
+                    if let Some(id) = selected.take() {
+                        history.apply(&mut scene, Command::remove(id));
+                    }
+                }
+                // ctrl+z undoes, ctrl+y or ctrl+shift+z redoes
+                glfw::WindowEvent::Key(key @ (Key::Z | Key::Y), _, Action::Press, modifiers)
+                    if modifiers.contains(glfw::Modifiers::Control) => {
+                    let redo = key == Key::Y || modifiers.contains(glfw::Modifiers::Shift);
+                    let done = if redo { history.redo(&mut scene) } else { history.undo(&mut scene) };
+                    if !done {
+                        println!("nothing to {}", if redo { "redo" } else { "undo" });
+                    }
+                }
+                // write the edited scene next to the file it was loaded from
+                glfw::WindowEvent::Key(Key::S, _, Action::Press, modifiers)
+                    if modifiers.contains(glfw::Modifiers::Control) => {
+                    let path = edited_path(&scene_path);
+                    // the models of included files would end up in this one
+                    let images = scene.environment.skybox.paths();
+                    if scene_files.iter().filter(|f| !images.contains(f)).count() > 1 {
+                        eprintln!("can't save {}: it includes other files, edit those instead", scene_path);
+                    } else {
+                        match scene.save(&path) {
+                            Ok(()) => {
+                                // not a change to reload, when the edited file was loaded
+                                scene_times = modification_times(&scene_files);
+                                println!("saved {}", path.display());
+                            }
+                            Err(e) => eprintln!("can't save {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                glfw::WindowEvent::CursorPos(xpos, ypos) => {
+                    cursor = (xpos, ypos);
+                    if let Some(drag) = drag.as_mut() {
+                        let edit = cursor_ray(&view, cursor, window_width, window_height)
+                            .and_then(|ray| drag.follow(&ray));
+                        if let Some(command) = edit.and_then(|edit| edit.command(&scene.models, drag.model)) {
+                            history.apply(&mut scene, command);
+                        }
+                    }
+                }
+                glfw::WindowEvent::MouseButton(MouseButton::Button1, Action::Release, _) => {
+                    drag = None;
+                    history.end_group();
+                }
+                // clicking selects the model under the cursor, or nothing, and
+                // holding the button drags it
+                glfw::WindowEvent::MouseButton(MouseButton::Button1, Action::Press, _) => {
+                    let hit = cursor_ray(&view, cursor, window_width, window_height)
+                        .and_then(|ray| scene.intersect(&ray));
+                    selected = hit.map(|hit| hit.model);
+                    let forward = view.rotation() * -Vector3::unit_z();
+                    drag = hit.map(|hit| Drag::new(hit.model, hit.position, forward));
+                    if drag.is_some() {
+                        // the whole drag is undone at once
+                        history.begin_group();
+                    }
+                    match hit.and_then(|hit| scene.models.get(hit.model).map(|model| (hit, model))) {
+                        Some((hit, model)) => {
+                            let material = scene.materials
+                                .iter()
+                                .find(|(_, m)| *m == model.material)
+                                .map_or(format!("{:?}", model.material), |(name, _)| name.clone());
+                            println!(
+                                "model {}: {} at distance {:.3}, position [{:.3}, {:.3}, {:.3}], material {}",
+                                hit.model.index(), model.t.name(), hit.t,
+                                hit.position.x, hit.position.y, hit.position.z,
+                                material,
+                            );
+                        }
+                        None => println!("nothing selected"),
+                    }
+                }
                 _ => {}
             }
         }
@@ -0,0 +1,415 @@
+use std::cmp::Ordering;
+use cgmath::Vector3;
+use crate::ray::{Hit, Ray};
+use crate::geospace::Transform;
+use cgmath::InnerSpace;
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    /// An inverted box, growing it with anything yields that thing
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn around(center: Vector3<f32>, half_extent: f32) -> Self {
+        let e = Vector3::new(half_extent, half_extent, half_extent);
+        Aabb {
+            min: center - e,
+            max: center + e,
+        }
+    }
+
+    pub fn grow(&mut self, other: &Aabb) {
+        self.min = Vector3::new(
+            self.min.x.min(other.min.x),
+            self.min.y.min(other.min.y),
+            self.min.z.min(other.min.z));
+        self.max = Vector3::new(
+            self.max.x.max(other.max.x),
+            self.max.y.max(other.max.y),
+            self.max.z.max(other.max.z));
+    }
+
+    /// Conservative bounds of the box after applying `tf`, holds for any rotation
+    pub fn transformed(&self, tf: &Transform) -> Aabb {
+        let center = tf.position() + tf.rotation() * self.centroid() * tf.scale;
+        let radius = (self.max - self.min).magnitude() * 0.5 * tf.scale.abs();
+        Aabb::around(center, radius)
+    }
+
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            0.0
+        } else {
+            2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+        }
+    }
+
+    /// Slab test, returns the entry distance (which may be negative if the
+    /// origin is inside the box). Same as `intersection_aabb` in the shader.
+    pub fn intersect(&self, ray: &Ray, inv_dir: Vector3<f32>, t_max: f32) -> Option<f32> {
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        for axis in 0..3 {
+            // parallel to the slab, avoids `0 * inf` when the origin is on its border
+            if ray.dir[axis] == 0.0 {
+                if ray.origin[axis] < self.min[axis] || self.max[axis] < ray.origin[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_dir[axis];
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_dir[axis];
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+
+        if t_near <= t_far && 0.0 < t_far && t_near < t_max {
+            Some(t_near)
+        } else {
+            None
+        }
+    }
+}
+
+/// A node of the flattened hierarchy, laid out to match `struct BvhNode` in
+/// the shader (std430, 32 bytes).
+///
+/// If `count` is zero the node is interior and its children are found at
+/// `left_first` and `left_first + 1`. Otherwise it is a leaf referencing
+/// `count` entries of the primitive list, starting at `left_first`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BvhNode {
+    pub min: [f32; 3],
+    pub left_first: i32,
+    pub max: [f32; 3],
+    pub count: i32,
+}
+
+impl BvhNode {
+    fn new(bounds: &Aabb) -> Self {
+        BvhNode {
+            min: bounds.min.into(),
+            max: bounds.max.into(),
+            left_first: 0,
+            count: 0,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb {
+            min: self.min.into(),
+            max: self.max.into(),
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+// relative cost of a traversal-step and a primitive test, used by the SAH
+const COST_TRAVERSAL: f32 = 1.0;
+const COST_INTERSECTION: f32 = 1.0;
+/// Leaves are always split above this size, regardless of the SAH
+const MAX_LEAF_SIZE: usize = 4;
+/// Size of the traversal stack, `BVH_STACK_SIZE` in the shader
+pub const STACK_SIZE: usize = 32;
+
+struct BuildItem {
+    index: i32,
+    bounds: Aabb,
+    centroid: Vector3<f32>,
+}
+
+/// Bounding volume hierarchy built with the surface area heuristic.
+///
+/// Primitives are referenced by the index they were given when the hierarchy
+/// was built. Unbounded primitives (e.g. planes) can't be placed in the tree
+/// and are kept in a separate list that is always tested.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    primitives: Vec<i32>,
+    unbounded: Vec<i32>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy from primitive indices and their bounds
+    pub fn build(primitives: &[(i32, Option<Aabb>)]) -> Self {
+        let mut items = Vec::new();
+        let mut unbounded = Vec::new();
+        for (i, b) in primitives.iter() {
+            match b {
+                Some(b) => items.push(BuildItem {
+                    index: *i,
+                    bounds: *b,
+                    centroid: b.centroid(),
+                }),
+                None => unbounded.push(*i),
+            }
+        }
+
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            primitives: Vec::with_capacity(items.len()),
+            unbounded,
+        };
+
+        if !items.is_empty() {
+            bvh.nodes.push(BvhNode::new(&Aabb::empty()));
+            bvh.subdivide(0, &mut items);
+        }
+        bvh
+    }
+
+    fn subdivide(&mut self, node: usize, items: &mut [BuildItem]) {
+        let mut bounds = Aabb::empty();
+        items.iter().for_each(|item| bounds.grow(&item.bounds));
+        self.nodes[node] = BvhNode::new(&bounds);
+
+        let n = items.len();
+        let leaf_cost = COST_INTERSECTION * n as f32 * bounds.surface_area();
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        if n > 1 {
+            let mut right_area = vec![0.0; n];
+            for axis in 0..3 {
+                sort_by_axis(items, axis);
+
+                // sweep from the right to get the area of every right-hand side
+                let mut acc = Aabb::empty();
+                for i in (1..n).rev() {
+                    acc.grow(&items[i].bounds);
+                    right_area[i] = acc.surface_area();
+                }
+
+                // then from the left, evaluating every split
+                let mut acc = Aabb::empty();
+                for i in 1..n {
+                    acc.grow(&items[i - 1].bounds);
+                    let cost = COST_TRAVERSAL * bounds.surface_area() +
+                        COST_INTERSECTION * (acc.surface_area() * i as f32 + right_area[i] * (n - i) as f32);
+                    let better = match best {
+                        Some((_, _, c)) => cost < c,
+                        None => true,
+                    };
+                    if better {
+                        best = Some((axis, i, cost));
+                    }
+                }
+            }
+        }
+
+        match best {
+            Some((axis, split, cost)) if cost < leaf_cost || n > MAX_LEAF_SIZE => {
+                sort_by_axis(items, axis);
+                let left = self.nodes.len();
+                self.nodes.push(BvhNode::new(&Aabb::empty()));
+                self.nodes.push(BvhNode::new(&Aabb::empty()));
+                self.nodes[node].left_first = left as i32;
+
+                let (left_items, right_items) = items.split_at_mut(split);
+                self.subdivide(left, left_items);
+                self.subdivide(left + 1, right_items);
+            }
+            _ => {
+                self.nodes[node].left_first = self.primitives.len() as i32;
+                self.nodes[node].count = n as i32;
+                items.iter().for_each(|item| self.primitives.push(item.index));
+            }
+        }
+    }
+
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    /// Primitive indices referenced by the leaves
+    pub fn primitives(&self) -> &[i32] {
+        &self.primitives
+    }
+
+    /// Primitive indices that are not part of the tree
+    pub fn unbounded(&self) -> &[i32] {
+        &self.unbounded
+    }
+
+    /// Finds the closest hit along `ray`, calling `test` for every candidate
+    /// primitive with the current closest distance. Traverses the tree in the
+    /// same order as `trace_scene` in the shader.
+    pub fn intersect<F>(&self, ray: &Ray, t_max: f32, mut test: F) -> Option<(usize, Hit)>
+    where
+        F: FnMut(usize, &Ray, f32) -> Option<Hit>
+    {
+        let mut closest: Option<(usize, Hit)> = None;
+        let mut t_closest = t_max;
+        let mut try_primitive = |i: i32, t_closest: &mut f32, closest: &mut Option<(usize, Hit)>| {
+            if let Some(hit) = test(i as usize, ray, *t_closest) {
+                if hit.t < *t_closest {
+                    *t_closest = hit.t;
+                    *closest = Some((i as usize, hit));
+                }
+            }
+        };
+
+        if !self.nodes.is_empty() {
+            let inv_dir = Vector3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+            let mut stack = [0usize; STACK_SIZE];
+            let mut top = 0;
+
+            if self.nodes[0].bounds().intersect(ray, inv_dir, t_closest).is_some() {
+                stack[0] = 0;
+                top = 1;
+            }
+
+            while top > 0 {
+                top -= 1;
+                let node = &self.nodes[stack[top]];
+
+                if node.is_leaf() {
+                    let first = node.left_first as usize;
+                    for &i in &self.primitives[first..first + node.count as usize] {
+                        try_primitive(i, &mut t_closest, &mut closest);
+                    }
+                    continue;
+                }
+
+                let left = node.left_first as usize;
+                let t_left = self.nodes[left].bounds().intersect(ray, inv_dir, t_closest);
+                let t_right = self.nodes[left + 1].bounds().intersect(ray, inv_dir, t_closest);
+
+                // push the far child first so that the near one is visited first
+                match (t_left, t_right) {
+                    (Some(tl), Some(tr)) => {
+                        let (near, far) = if tr < tl { (left + 1, left) } else { (left, left + 1) };
+                        stack[top] = far;
+                        stack[top + 1] = near;
+                        top += 2;
+                    }
+                    (Some(_), None) => {
+                        stack[top] = left;
+                        top += 1;
+                    }
+                    (None, Some(_)) => {
+                        stack[top] = left + 1;
+                        top += 1;
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+
+        for &i in &self.unbounded {
+            try_primitive(i, &mut t_closest, &mut closest);
+        }
+
+        closest
+    }
+}
+
+fn sort_by_axis(items: &mut [BuildItem], axis: usize) {
+    items.sort_by(|a, b| a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap_or(Ordering::Equal));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ModelId, ModelManager, ModelProperty, ModelType};
+    use crate::models::sdf::SdfNode;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::geospace::Transform;
+    use crate::{col, transform, define_material};
+    use cgmath::InnerSpace;
+
+    fn sphere_grid() -> ModelManager {
+        let mut mm = ModelManager::new();
+        for i in 0..8 {
+            for j in 0..8 {
+                mm.add_new(ModelProperty {
+                    t: ModelType::Sphere,
+                    tf: transform!(i as f32 * 3.0 - 10.0, (i * j % 5) as f32, j as f32 * -3.0, 0.5 + (i + j) as f32 * 0.1),
+                    color: col!(),
+                    material: define_material!(1.0),
+                });
+            }
+        }
+        mm.add_new(ModelProperty {
+            t: ModelType::Sdf(SdfNode::Torus { pos: Vector3::new(0.0, 0.0, 0.0), major: 2.0, minor: 0.5 }),
+            tf: transform!(0.0, 3.0, -4.0, 1.0, 0.3, 0.7, 0.0),
+            color: col!(),
+            material: define_material!(1.0),
+        });
+        mm.add_new(ModelProperty {
+            t: ModelType::Plane,
+            tf: transform!(0.0, -2.0, 0.0),
+            color: col!(),
+            material: define_material!(1.0),
+        });
+        mm
+    }
+
+    #[test]
+    fn stack_never_overflows() {
+        let bvh = sphere_grid().build_bvh();
+        fn depth(bvh: &Bvh, node: usize) -> usize {
+            let n = &bvh.nodes()[node];
+            if n.is_leaf() {
+                1
+            } else {
+                1 + depth(bvh, n.left_first as usize).max(depth(bvh, n.left_first as usize + 1))
+            }
+        }
+        assert!(depth(&bvh, 0) < STACK_SIZE);
+        assert_eq!(bvh.unbounded().len(), 1);
+        assert_eq!(bvh.primitives().len(), 65);
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut mm = sphere_grid();
+        mm.add_instances(ModelProperty {
+            t: ModelType::Sdf(SdfNode::Box { pos: Vector3::new(0.0, 0.0, 0.0), size: Vector3::new(1.0, 2.0, 1.0) }),
+            tf: transform!(0.0, 1.0, 0.0),
+            color: col!(),
+            material: define_material!(1.0),
+        }, vec![transform!(4.0, 0.0, 2.0, 0.5, 0.2, 0.0, 0.0), transform!(-4.0, -1.0, 1.0, 1.5)]);
+
+        let bvh = mm.build_bvh();
+        let primitives = mm.primitives();
+        assert_eq!(bvh.primitives().len() + bvh.unbounded().len(), primitives.iter().flatten().count());
+
+        for yaw in 0..32 {
+            for pitch in 0..16 {
+                let (y, p) = (yaw as f32 * 0.2, pitch as f32 * 0.2 - 1.6);
+                let dir = Vector3::new(y.sin() * p.cos(), p.sin(), -y.cos() * p.cos()).normalize();
+                let ray = Ray::new(Vector3::new(0.5, 1.0, 5.0), dir);
+
+                let expected = primitives.iter()
+                    .filter_map(|p| p.as_ref().and_then(|(id, p)| p.intersect(&ray, 1000.0).map(|h| (*id, h))))
+                    .fold(None, |best: Option<(ModelId, Hit)>, (id, h)| match best {
+                        Some((_, b)) if b.t <= h.t => best,
+                        _ => Some((id, h)),
+                    });
+
+                assert_eq!(mm.intersect(&bvh, &ray, 1000.0), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use cgmath::{InnerSpace, Matrix3, Rad, Vector3};
+use crate::geospace::Transform;
+use crate::ray::Ray;
+
+pub const PERSPECTIVE_ID: i32 = 0;
+pub const ORTHOGRAPHIC_ID: i32 = 1;
+pub const FISHEYE_ID: i32 = 2;
+pub const EQUIRECTANGULAR_ID: i32 = 3;
+
+/// Width of a 35mm film, relates a focal length to the field of view
+pub const FILM_WIDTH_MM: f32 = 36.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    Perspective,
+    /// parallel rays, the view is as wide as the perspective view at the
+    /// focus distance
+    Orthographic,
+    /// equidistant fisheye, the angle to the view direction grows linearly
+    /// with the distance to the center of the image, up to 360°
+    Fisheye,
+    /// full 360° panorama, ignores the field of view
+    Equirectangular,
+}
+
+impl Projection {
+    pub fn get_id(&self) -> i32 {
+        match self {
+            Projection::Perspective => PERSPECTIVE_ID,
+            Projection::Orthographic => ORTHOGRAPHIC_ID,
+            Projection::Fisheye => FISHEYE_ID,
+            Projection::Equirectangular => EQUIRECTANGULAR_ID,
+        }
+    }
+}
+
+impl TryFrom<&str> for Projection {
+    type Error = ();
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name.to_lowercase().as_str() {
+            "perspective" => Ok(Self::Perspective),
+            "orthographic" => Ok(Self::Orthographic),
+            "fisheye" => Ok(Self::Fisheye),
+            "equirectangular" => Ok(Self::Equirectangular),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A thin lens camera looking along -z, rotated by pitch then yaw. Mirrors
+/// `create_ray` in the shader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    /// position, `head` is the yaw, `scale` and `rotate` are unused
+    pub tf: Transform,
+    pub projection: Projection,
+    /// horizontal field of view (rad)
+    pub fov: f32,
+    /// diameter of the lens, zero gives a pinhole camera without depth of field
+    pub aperture: f32,
+    /// distance to the plane that is in focus
+    pub focus_distance: f32,
+    /// width:height of the image, the shader uses `uRatio` instead
+    pub ratio: f32,
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Camera {
+            tf: Transform::new(),
+            projection: Projection::Perspective,
+            fov: 110f32.to_radians(),
+            aperture: 0.0,
+            focus_distance: 10.0,
+            ratio: 1.0,
+        }
+    }
+
+    /// Sets the field of view from the focal length of a 35mm camera
+    pub fn set_focal_length(&mut self, mm: f32) {
+        self.fov = 2.0 * (FILM_WIDTH_MM / (2.0 * mm)).atan();
+    }
+
+    pub fn position(&self) -> Vector3<f32> {
+        self.tf.position()
+    }
+
+    /// Camera to world rotation, same as `rotate_y(yaw) * rotate_x(pitch)`
+    pub fn rotation(&self) -> Matrix3<f32> {
+        Matrix3::from_angle_y(Rad(self.tf.head)) * Matrix3::from_angle_x(Rad(self.tf.pitch))
+    }
+
+    /// Ray through the center of the lens, see `generate_lens_ray`
+    pub fn generate_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        self.generate_lens_ray(u, v, [0.0, 0.0])
+    }
+
+    /// Ray through the image at `(u, v)`, both in [0, 1] with (0, 0) at the
+    /// bottom left, and through the point `lens` in [0, 1)² on the lens.
+    /// Fisheye cameras have no rays for the corners beyond 360°.
+    pub fn generate_lens_ray(&self, u: f32, v: f32, lens: [f32; 2]) -> Option<Ray> {
+        // screen position as in the shader, the height spans [-1, 1]
+        let x = (2.0 * u - 1.0) * self.ratio;
+        let y = 2.0 * v - 1.0;
+        let half_fov = self.fov / 2.0;
+
+        let (origin, dir) = match self.projection {
+            Projection::Perspective => {
+                let z = self.ratio / half_fov.tan();
+                (Vector3::new(0.0, 0.0, 0.0), Vector3::new(x, y, -z).normalize())
+            }
+            Projection::Orthographic => {
+                let half_width = self.focus_distance * half_fov.tan();
+                let scale = half_width / self.ratio;
+                (Vector3::new(x * scale, y * scale, 0.0), Vector3::new(0.0, 0.0, -1.0))
+            }
+            Projection::Fisheye => {
+                let r = (x * x + y * y).sqrt();
+                let theta = r / self.ratio * half_fov;
+                if theta > std::f32::consts::PI {
+                    return None;
+                }
+                let (sin, cos) = theta.sin_cos();
+                let dir = if r > 0.0 {
+                    Vector3::new(sin * x / r, sin * y / r, -cos)
+                } else {
+                    Vector3::new(0.0, 0.0, -1.0)
+                };
+                (Vector3::new(0.0, 0.0, 0.0), dir)
+            }
+            Projection::Equirectangular => {
+                let longitude = (2.0 * u - 1.0) * std::f32::consts::PI;
+                let latitude = y * std::f32::consts::FRAC_PI_2;
+                let dir = Vector3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    -latitude.cos() * longitude.cos(),
+                );
+                (Vector3::new(0.0, 0.0, 0.0), dir)
+            }
+        };
+
+        let (origin, dir) = if self.aperture > 0.0 {
+            // rays through any point of the lens meet on the focus plane,
+            // a sphere around the camera for the wide angle projections
+            let t = match self.projection {
+                Projection::Perspective | Projection::Orthographic => self.focus_distance / -dir.z,
+                Projection::Fisheye | Projection::Equirectangular => self.focus_distance,
+            };
+            let focus = origin + dir * t;
+            let (radius, angle) = (0.5 * self.aperture * lens[0].sqrt(), 2.0 * std::f32::consts::PI * lens[1]);
+            let origin = origin + Vector3::new(radius * angle.cos(), radius * angle.sin(), 0.0);
+            (origin, (focus - origin).normalize())
+        } else {
+            (origin, dir)
+        };
+
+        let rot = self.rotation();
+        Some(Ray::new(self.position() + rot * origin, rot * dir))
+    }
+
+    /// Blends towards `other`, `t` in [0, 1]. Angles take the shorter way
+    /// around, the projection switches halfway.
+    pub fn lerp(&self, other: &Camera, t: f32) -> Camera {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        let mix_angle = |a: f32, b: f32| {
+            let tau = 2.0 * std::f32::consts::PI;
+            let d = (b - a).rem_euclid(tau);
+            a + if d > std::f32::consts::PI { d - tau } else { d } * t
+        };
+
+        let mut tf = Transform::new();
+        tf.x = mix(self.tf.x, other.tf.x);
+        tf.y = mix(self.tf.y, other.tf.y);
+        tf.z = mix(self.tf.z, other.tf.z);
+        tf.pitch = mix_angle(self.tf.pitch, other.tf.pitch);
+        tf.head = mix_angle(self.tf.head, other.tf.head);
+
+        Camera {
+            tf,
+            projection: if t < 0.5 { self.projection } else { other.projection },
+            fov: mix(self.fov, other.fov),
+            aperture: mix(self.aperture, other.aperture),
+            focus_distance: mix(self.focus_distance, other.focus_distance),
+            ratio: mix(self.ratio, other.ratio),
+        }
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new()
+    }
+}
+
+/// The named cameras of a scene, one of them is active. Never empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cameras {
+    cameras: Vec<(String, Camera)>,
+    active: usize,
+}
+
+impl Cameras {
+    /// Name of the camera of a scene without named cameras
+    pub const DEFAULT_NAME: &'static str = "default";
+
+    pub fn new(name: &str, camera: Camera) -> Self {
+        Cameras {
+            cameras: vec![(name.to_owned(), camera)],
+            active: 0,
+        }
+    }
+
+    /// Adds a camera, returns its index or `None` if the name is taken
+    pub fn add(&mut self, name: &str, camera: Camera) -> Option<usize> {
+        if self.index_of(name).is_some() {
+            return None;
+        }
+        self.cameras.push((name.to_owned(), camera));
+        Some(self.cameras.len() - 1)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.cameras.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Camera> {
+        self.cameras.get(index).map(|(_, camera)| camera)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Camera> {
+        self.cameras.get_mut(index).map(|(_, camera)| camera)
+    }
+
+    pub fn active(&self) -> &Camera {
+        &self.cameras[self.active].1
+    }
+
+    pub fn active_mut(&mut self) -> &mut Camera {
+        &mut self.cameras[self.active].1
+    }
+
+    pub fn active_index(&self) -> usize {
+        self.active
+    }
+
+    pub fn active_name(&self) -> &str {
+        &self.cameras[self.active].0
+    }
+
+    /// Returns false, leaving the active camera as is, if there is no such camera
+    pub fn set_active(&mut self, index: usize) -> bool {
+        if index < self.cameras.len() {
+            self.active = index;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Camera)> {
+        self.cameras.iter().map(|(name, camera)| (name.as_str(), camera))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cameras.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+impl Default for Cameras {
+    fn default() -> Self {
+        Cameras::new(Cameras::DEFAULT_NAME, Camera::new())
+    }
+}
+
+/// Glsl defines of the projection ids
+pub fn glsl_header() -> String {
+    let mut glsl = String::new();
+    for (name, value) in [
+        ("PERSPECTIVE_ID", PERSPECTIVE_ID),
+        ("ORTHOGRAPHIC_ID", ORTHOGRAPHIC_ID),
+        ("FISHEYE_ID", FISHEYE_ID),
+        ("EQUIRECTANGULAR_ID", EQUIRECTANGULAR_ID),
+    ] {
+        glsl.push_str(&format!("#define {} {}\n", name, value));
+    }
+    glsl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn rays_follow_projection() {
+        let mut camera = Camera::new();
+        camera.tf.x = 1.0;
+        camera.set_focal_length(18.0);
+        assert!((camera.fov - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+
+        // the center looks along -z, the right edge at fov / 2
+        let center = camera.generate_ray(0.5, 0.5).unwrap();
+        assert_close(center.origin, Vector3::new(1.0, 0.0, 0.0));
+        assert_close(center.dir, Vector3::new(0.0, 0.0, -1.0));
+        let edge = camera.generate_ray(1.0, 0.5).unwrap();
+        assert_close(edge.dir, Vector3::new(1.0, 0.0, -1.0).normalize());
+
+        // yaw turns the view to the left
+        camera.tf.head = std::f32::consts::FRAC_PI_2;
+        assert_close(camera.generate_ray(0.5, 0.5).unwrap().dir, Vector3::new(-1.0, 0.0, 0.0));
+        camera.tf.head = 0.0;
+
+        camera.projection = Projection::Orthographic;
+        let ortho = camera.generate_ray(1.0, 0.5).unwrap();
+        assert_close(ortho.origin, Vector3::new(1.0 + camera.focus_distance, 0.0, 0.0));
+        assert_close(ortho.dir, Vector3::new(0.0, 0.0, -1.0));
+
+        camera.projection = Projection::Equirectangular;
+        assert_close(camera.generate_ray(1.0, 0.5).unwrap().dir, Vector3::new(0.0, 0.0, 1.0));
+        assert_close(camera.generate_ray(0.5, 1.0).unwrap().dir, Vector3::new(0.0, 1.0, 0.0));
+
+        camera.projection = Projection::Fisheye;
+        camera.fov = 2.0 * std::f32::consts::PI;
+        assert_close(camera.generate_ray(1.0, 0.5).unwrap().dir, Vector3::new(0.0, 0.0, 1.0));
+        assert!(camera.generate_ray(1.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn lens_rays_meet_on_focus_plane() {
+        let mut camera = Camera::new();
+        camera.aperture = 0.5;
+        camera.focus_distance = 4.0;
+
+        let pinhole = camera.generate_ray(0.7, 0.2).unwrap();
+        let focus = pinhole.at(camera.focus_distance / -pinhole.dir.z);
+        for lens in [[0.0, 0.0], [0.9, 0.1], [0.5, 0.75]] {
+            let ray = camera.generate_lens_ray(0.7, 0.2, lens).unwrap();
+            assert!(ray.origin.magnitude() <= 0.25 + 1e-6);
+            assert_close(ray.at(focus.z / ray.dir.z), focus);
+        }
+    }
+
+    #[test]
+    fn switching_cameras() {
+        let mut cameras = Cameras::default();
+        let mut top = Camera::new();
+        top.tf.y = 10.0;
+        top.tf.head = -3.0;
+        top.projection = Projection::Orthographic;
+        let index = cameras.add("top", top).unwrap();
+        assert!(cameras.add("top", Camera::new()).is_none());
+        assert!(!cameras.set_active(2));
+
+        assert!(cameras.set_active(index));
+        assert_eq!(cameras.active_name(), "top");
+
+        let from = cameras.get(0).unwrap();
+        let halfway = from.lerp(cameras.active(), 0.25);
+        assert_eq!(halfway.tf.y, 2.5);
+        assert_eq!(halfway.projection, Projection::Perspective);
+        // -3 rad is closer when turning right
+        assert!(halfway.tf.head < 0.0);
+        assert_eq!(&from.lerp(cameras.active(), 1.0).tf, &cameras.active().tf);
+    }
+}
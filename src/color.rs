@@ -1,19 +1,13 @@
-use crate::NumberOfFields;
+use crate::gpu::GpuLayout;
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, GpuLayout)]
 pub struct Color { 
     pub r: f32,
     pub g: f32,
     pub b: f32
 }
 
-impl NumberOfFields for Color {
-    fn nr_fields(&self) -> usize {
-        return 3;
-    }
-}
-
 impl From<(u8, u8, u8)> for Color {
     fn from(rgb: (u8, u8, u8)) -> Color {
         Color {
@@ -172,16 +166,6 @@ impl FromStr for Color {
     }
 }
 
-impl Color {
-    pub fn iter(&self) -> std::vec::IntoIter<f32> {
-        vec!(
-            self.r,
-            self.g,
-            self.b,
-        ).into_iter()
-    }
-}
-
 #[macro_export]
 macro_rules! col {
     () => {
@@ -0,0 +1,159 @@
+use cgmath::{InnerSpace, Vector3};
+
+use crate::color::Color;
+use crate::geospace::Transform;
+use crate::history::Command;
+use crate::material::Material;
+use crate::models::{ModelId, ModelManager, ModelProperty};
+use crate::ray::Ray;
+
+/// Smallest scale an edit leaves a model with
+pub const MIN_SCALE: f32 = 1e-3;
+
+/// A change to a single model, as made interactively. Rotations are in
+/// radians and added to the angles of the transform.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    Translate(Vector3<f32>),
+    Rotate { head: f32, pitch: f32, rotate: f32 },
+    /// multiplies the scale
+    Scale(f32),
+    Color(Color),
+    /// added to the reflectance, which stays in [0, 1]
+    Reflectance(f32),
+}
+
+impl Edit {
+    pub fn apply(&self, model: &mut ModelProperty) {
+        self.apply_to(&mut model.tf, &mut model.color, &mut model.material);
+    }
+
+    fn apply_to(&self, tf: &mut Transform, color: &mut Color, material: &mut Material) {
+        match *self {
+            Edit::Translate(d) => {
+                tf.x += d.x;
+                tf.y += d.y;
+                tf.z += d.z;
+            }
+            Edit::Rotate { head, pitch, rotate } => {
+                tf.head += head;
+                tf.pitch += pitch;
+                tf.rotate += rotate;
+            }
+            Edit::Scale(factor) => tf.scale = (tf.scale * factor).max(MIN_SCALE),
+            Edit::Color(ref c) => *color = c.clone(),
+            Edit::Reflectance(delta) => {
+                material.reflectance = (material.reflectance + delta).clamp(0.0, 1.0)
+            }
+        }
+    }
+
+    /// The edit of the model as a command that can be undone, `None` if the
+    /// handle is invalid
+    pub fn command(&self, models: &ModelManager, id: ModelId) -> Option<Command> {
+        let model = models.get(id)?;
+        let (mut tf, mut color, mut material) = (model.tf.clone(), model.color.clone(), model.material.clone());
+        self.apply_to(&mut tf, &mut color, &mut material);
+        Some(match self {
+            Edit::Translate(_) | Edit::Rotate { .. } | Edit::Scale(_) => Command::SetTransform { id, tf },
+            Edit::Color(_) | Edit::Reflectance(_) => Command::SetMaterial { id, color, material },
+        })
+    }
+}
+
+/// Applies `edit` to the model, which is then uploaded with the next sync.
+/// Returns false if the handle is invalid.
+pub fn apply(models: &mut ModelManager, id: ModelId, edit: &Edit) -> bool {
+    match models.get_mut(id) {
+        Some(model) => {
+            edit.apply(model);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Moves a model with the cursor, in the plane facing the camera through
+/// the point where it was grabbed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drag {
+    pub model: ModelId,
+    normal: Vector3<f32>,
+    point: Vector3<f32>,
+}
+
+impl Drag {
+    /// `forward` is the viewing direction of the camera
+    pub fn new(model: ModelId, grabbed: Vector3<f32>, forward: Vector3<f32>) -> Self {
+        Drag { model, normal: forward.normalize(), point: grabbed }
+    }
+
+    /// The translation following the cursor ray `ray`, `None` if it misses
+    /// the plane
+    pub fn follow(&mut self, ray: &Ray) -> Option<Edit> {
+        let denom = ray.dir.dot(self.normal);
+        if denom.abs() < 1e-6 {
+            return None;
+        }
+        let t = (self.point - ray.origin).dot(self.normal) / denom;
+        if t <= 0.0 {
+            return None;
+        }
+        let point = ray.at(t);
+        let delta = point - self.point;
+        self.point = point;
+        Some(Edit::Translate(delta))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelType;
+    use crate::{col, define_material, transform};
+
+    #[test]
+    fn edits_go_through_the_manager() {
+        let mut models = ModelManager::new();
+        let id = models.add_new(ModelProperty {
+            t: ModelType::Sphere,
+            tf: transform!(),
+            color: col!(),
+            material: define_material!(0.5),
+        });
+        models.take_changes();
+
+        assert!(apply(&mut models, id, &Edit::Translate(Vector3::new(1.0, 2.0, 3.0))));
+        assert!(apply(&mut models, id, &Edit::Scale(0.0)));
+        assert!(apply(&mut models, id, &Edit::Reflectance(0.8)));
+        assert!(apply(&mut models, id, &Edit::Color(col!(red))));
+        let model = models.get(id).unwrap();
+        assert_eq!(model.tf.position(), Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(model.tf.scale, MIN_SCALE);
+        assert_eq!(model.material.reflectance, 1.0);
+        assert_eq!(model.color, col!(red));
+        assert!(models.is_modified());
+
+        models.remove(id);
+        assert!(!apply(&mut models, id, &Edit::Scale(2.0)));
+    }
+
+    #[test]
+    fn dragging_follows_the_cursor() {
+        let id = ModelManager::new().add_new(ModelProperty {
+            t: ModelType::Sphere,
+            tf: transform!(),
+            color: col!(),
+            material: define_material!(0.0),
+        });
+        let mut drag = Drag::new(id, Vector3::new(0.0, 0.0, -5.0), -Vector3::unit_z());
+        let ray = Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, -5.0).normalize());
+        match drag.follow(&ray) {
+            Some(Edit::Translate(d)) => assert!((d - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-5),
+            edit => panic!("unexpected {:?}", edit),
+        }
+        // moving on from where it was left
+        assert!(matches!(drag.follow(&ray), Some(Edit::Translate(d)) if d.magnitude() < 1e-5));
+        assert_eq!(drag.follow(&Ray::new(Vector3::new(0.0, 0.0, 0.0), Vector3::unit_z())), None);
+    }
+}
@@ -0,0 +1,288 @@
+use std::path::PathBuf;
+
+use cgmath::{InnerSpace, Vector3};
+use crate::color::Color;
+use crate::image::{self, Image, ImageError, ImageResult};
+use crate::lights::Light;
+use crate::sky::{Sky, SunPosition};
+
+/// What is seen where no model is hit
+#[derive(Debug, Clone, PartialEq)]
+pub enum Skybox {
+    /// a different color for every face of the cube, tells the directions apart
+    Debug,
+    Color(Color),
+    /// `top` straight up, blending to `bottom` straight down
+    Gradient { top: Color, bottom: Color },
+    /// square images of the same size in the order +x, -x, +y, -y, +z, -z,
+    /// oriented like the faces of a gl cube map
+    Faces(Box<[PathBuf; 6]>),
+    /// a panorama, the center of the image is straight ahead (-z)
+    Equirectangular(PathBuf),
+    /// daylight computed from the position of the sun
+    Sky(Sky),
+}
+
+impl Skybox {
+    /// The image files the skybox is made of
+    pub fn paths(&self) -> Vec<&PathBuf> {
+        match self {
+            Skybox::Faces(paths) => paths.iter().collect(),
+            Skybox::Equirectangular(path) => vec![path],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The six faces of a cube map, each `size`² rgb pixels in the order +x, -x,
+/// +y, -y, +z, -z, ready for `TEXTURE_CUBE_MAP_POSITIVE_X + i`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeMap {
+    pub size: usize,
+    pub faces: Vec<Vec<f32>>,
+}
+
+impl CubeMap {
+    /// Fills every texel with the color seen in its direction
+    pub fn from_fn<F: Fn(Vector3<f32>) -> [f32; 3]>(size: usize, f: F) -> Self {
+        let faces = (0..6)
+            .map(|face| {
+                let mut pixels = Vec::with_capacity(size * size * 3);
+                for row in 0..size {
+                    for column in 0..size {
+                        pixels.extend(f(texel_direction(face, column, row, size)));
+                    }
+                }
+                pixels
+            })
+            .collect();
+        CubeMap { size, faces }
+    }
+
+    /// Resamples a panorama, see `Skybox::Equirectangular`
+    pub fn from_equirectangular(image: &Image, size: usize) -> Self {
+        CubeMap::from_fn(size, |d| {
+            let longitude = d.x.atan2(-d.z);
+            let latitude = d.y.clamp(-1.0, 1.0).asin();
+            image.sample(
+                0.5 + longitude / (2.0 * std::f32::consts::PI),
+                0.5 - latitude / std::f32::consts::PI,
+            )
+        })
+    }
+
+    /// Bilinear lookup of the color seen in direction `d`, the filter stays
+    /// within a face
+    pub fn sample(&self, d: Vector3<f32>) -> [f32; 3] {
+        let (face, a, b) = face_coordinates(d);
+        let max = (self.size - 1) as f32;
+        let x = ((a + 1.0) * 0.5 * self.size as f32 - 0.5).clamp(0.0, max);
+        let y = ((b + 1.0) * 0.5 * self.size as f32 - 0.5).clamp(0.0, max);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.size - 1), (y0 + 1).min(self.size - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let pixels = &self.faces[face];
+        let mut rgb = [0.0; 3];
+        for (x, y, w) in [
+            (x0, y0, (1.0 - fx) * (1.0 - fy)),
+            (x1, y0, fx * (1.0 - fy)),
+            (x0, y1, (1.0 - fx) * fy),
+            (x1, y1, fx * fy),
+        ] {
+            let i = 3 * (y * self.size + x);
+            for c in 0..3 {
+                rgb[c] += w * pixels[i + c];
+            }
+        }
+        rgb
+    }
+
+    /// Half the size, every texel the average of four
+    pub fn downsample(&self) -> CubeMap {
+        let size = (self.size / 2).max(1);
+        let step = self.size / size;
+        let faces = self
+            .faces
+            .iter()
+            .map(|pixels| {
+                let mut half = vec![0.0; size * size * 3];
+                for row in 0..size * step {
+                    for column in 0..size * step {
+                        let from = 3 * (row * self.size + column);
+                        let to = 3 * (row / step * size + column / step);
+                        for c in 0..3 {
+                            half[to + c] += pixels[from + c] / (step * step) as f32;
+                        }
+                    }
+                }
+                half
+            })
+            .collect();
+        CubeMap { size, faces }
+    }
+}
+
+/// Direction through the center of a texel, following the cube map layout of
+/// the gl specification
+pub fn texel_direction(face: usize, column: usize, row: usize, size: usize) -> Vector3<f32> {
+    let a = 2.0 * (column as f32 + 0.5) / size as f32 - 1.0;
+    let b = 2.0 * (row as f32 + 0.5) / size as f32 - 1.0;
+    let d = match face {
+        0 => Vector3::new(1.0, -b, -a),
+        1 => Vector3::new(-1.0, -b, a),
+        2 => Vector3::new(a, 1.0, b),
+        3 => Vector3::new(a, -1.0, -b),
+        4 => Vector3::new(a, -b, 1.0),
+        _ => Vector3::new(-a, -b, -1.0),
+    };
+    d.normalize()
+}
+
+/// Inverse of `texel_direction`: the face `d` points at and the position on
+/// it, both coordinates in [-1, 1]
+pub fn face_coordinates(d: Vector3<f32>) -> (usize, f32, f32) {
+    let (x, y, z) = (d.x.abs(), d.y.abs(), d.z.abs());
+    if x >= y && x >= z {
+        if d.x > 0.0 { (0, -d.z / x, -d.y / x) } else { (1, d.z / x, -d.y / x) }
+    } else if y >= z {
+        if d.y > 0.0 { (2, d.x / y, d.z / y) } else { (3, d.x / y, -d.z / y) }
+    } else if d.z > 0.0 {
+        (4, d.x / z, -d.y / z)
+    } else {
+        (5, -d.x / z, -d.y / z)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Environment {
+    pub skybox: Skybox,
+}
+
+impl Environment {
+    /// Side length in pixels of the generated skyboxes and of converted
+    /// panoramas
+    pub const CUBE_SIZE: usize = 256;
+
+    pub fn new() -> Self {
+        Environment { skybox: Skybox::Debug }
+    }
+
+    /// Lets time pass for an animated sky, returns whether the skybox changed
+    pub fn advance(&mut self, seconds: f32) -> bool {
+        match &mut self.skybox {
+            Skybox::Sky(sky) => sky.sun.advance(seconds),
+            _ => false,
+        }
+    }
+
+    /// Whether `advance` changes the skybox
+    pub fn is_animated(&self) -> bool {
+        matches!(&self.skybox, Skybox::Sky(sky) if matches!(sky.sun, SunPosition::Time { hours_per_second, .. } if hours_per_second != 0.0))
+    }
+
+    /// The sun of a sky, lighting the scene like the other lights
+    pub fn sun_light(&self) -> Option<Light> {
+        match &self.skybox {
+            Skybox::Sky(sky) => Some(sky.sun_light()),
+            _ => None,
+        }
+    }
+
+    /// Builds the skybox, loading its images
+    pub fn cube_map(&self) -> ImageResult<CubeMap> {
+        let rgb = |c: &Color| [c.r, c.g, c.b];
+        let size = Environment::CUBE_SIZE;
+        Ok(match &self.skybox {
+            Skybox::Debug => {
+                let faces = (0..6)
+                    .map(|face| {
+                        let mut rgb = [0.0; 3];
+                        rgb[face / 2] = if face % 2 == 0 { 1.0 } else { 0.5 };
+                        rgb.repeat(size * size)
+                    })
+                    .collect();
+                CubeMap { size, faces }
+            }
+            Skybox::Color(c) => CubeMap::from_fn(size, |_| rgb(c)),
+            Skybox::Gradient { top, bottom } => CubeMap::from_fn(size, |d| {
+                let t = 0.5 * (d.y + 1.0);
+                let (top, bottom) = (rgb(top), rgb(bottom));
+                [0, 1, 2].map(|c| bottom[c] + (top[c] - bottom[c]) * t)
+            }),
+            Skybox::Faces(paths) => {
+                let mut faces = Vec::with_capacity(6);
+                let mut size = None;
+                for path in paths.iter() {
+                    let face = image::load(path)?;
+                    if face.width != face.height || matches!(size, Some(s) if s != face.width) {
+                        return Err(ImageError::Decode(format!(
+                            "{} is not square or differs in size from the other faces",
+                            path.display()
+                        )));
+                    }
+                    size = Some(face.width);
+                    faces.push(face.data);
+                }
+                CubeMap { size: size.unwrap_or(0), faces }
+            }
+            Skybox::Equirectangular(path) => CubeMap::from_equirectangular(&image::load(path)?, size),
+            Skybox::Sky(sky) => CubeMap::from_fn(size, |d| sky.radiance(d)),
+        })
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cube_map_directions() {
+        // the center of each face looks along its axis
+        let axes = [
+            Vector3::unit_x(), -Vector3::unit_x(),
+            Vector3::unit_y(), -Vector3::unit_y(),
+            Vector3::unit_z(), -Vector3::unit_z(),
+        ];
+        for (face, axis) in axes.iter().enumerate() {
+            assert!((texel_direction(face, 1, 1, 3) - axis).magnitude() < 1e-6);
+        }
+
+        // sampling at the center of a texel gives the texel
+        let cube = CubeMap::from_fn(4, |d| [d.x, d.y, d.z]);
+        for face in 0..6 {
+            let d = texel_direction(face, 3, 1, 4);
+            assert!((Vector3::from(cube.sample(d)) - d).magnitude() < 1e-5);
+            assert_eq!(face_coordinates(d).0, face);
+        }
+        assert_eq!(cube.downsample().size, 2);
+
+        // top row red, bottom row blue, the middle of the front column green
+        let mut panorama = Image::new(4, 3);
+        for x in 0..4 {
+            panorama.set_pixel(x, 0, [1.0, 0.0, 0.0]);
+            panorama.set_pixel(x, 2, [0.0, 0.0, 1.0]);
+        }
+        panorama.set_pixel(1, 1, [0.0, 1.0, 0.0]);
+        panorama.set_pixel(2, 1, [0.0, 1.0, 0.0]);
+
+        let cube = CubeMap::from_equirectangular(&panorama, 3);
+        let center = |face: usize| &cube.faces[face][12..15];
+        assert_eq!(center(2), &[1.0, 0.0, 0.0]);
+        assert_eq!(center(3), &[0.0, 0.0, 1.0]);
+        assert_eq!(center(5), &[0.0, 1.0, 0.0]);
+
+        let gradient = Environment {
+            skybox: Skybox::Gradient { top: Color { r: 1.0, g: 1.0, b: 1.0 }, bottom: Color { r: 0.0, g: 0.0, b: 0.0 } },
+        };
+        let cube = gradient.cube_map().unwrap();
+        let middle = 3 * (cube.size * cube.size / 2 + cube.size / 2);
+        assert!(cube.faces[2][middle] > 0.99 && cube.faces[3][middle] < 0.01);
+    }
+}
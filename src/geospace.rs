@@ -1,6 +1,7 @@
-use crate::NumberOfFields;
+use crate::gpu::GpuLayout;
+use cgmath::{Matrix3, Rad, Vector3};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, GpuLayout)]
 pub struct Transform {
     // Position
     pub x: f32,
@@ -16,25 +17,7 @@ pub struct Transform {
     pub rotate: f32
 }
 
-impl NumberOfFields for Transform {
-    fn nr_fields(&self) -> usize {
-        7
-    }
-}
-
 impl Transform {
-    pub fn iter(&self) -> std::vec::IntoIter<f32> {
-        vec!(
-            self.x,
-            self.y,
-            self.z,
-            self.scale,
-            self.head,
-            self.pitch,
-            self.rotate,
-        ).into_iter()
-    }
-
     pub fn new() -> Self {
         Transform {
             x: 0.0,
@@ -48,6 +31,17 @@ impl Transform {
             rotate: 0.0,
         }
     }
+
+    pub fn position(&self) -> Vector3<f32> {
+        Vector3::new(self.x, self.y, self.z)
+    }
+
+    /// Rotation matrix, same as `euler_transform(head, pitch, rotate)` in the shader
+    pub fn rotation(&self) -> Matrix3<f32> {
+        Matrix3::from_angle_z(Rad(self.rotate)) *
+            Matrix3::from_angle_x(Rad(self.pitch)) *
+            Matrix3::from_angle_y(Rad(self.head))
+    }
 }
 
 #[macro_export]
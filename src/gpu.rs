@@ -0,0 +1,163 @@
+use cgmath::Matrix3;
+
+pub use hugengine_derive::GpuLayout;
+
+/// A field of a `GpuLayout` type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuField {
+    pub name: &'static str,
+    /// glsl type of the field
+    pub glsl: &'static str,
+    /// std430 offset in bytes
+    pub offset: usize,
+    /// std430 size in bytes, zero for a field of variable size
+    pub size: usize,
+    /// fields of the type of the field, empty for scalars
+    pub fields: &'static [GpuField],
+}
+
+/// A type that is written to a buffer of floats, following the std430 rules
+/// for a struct of the same fields. Usually derived.
+pub trait GpuLayout {
+    /// Size in bytes, without the variable sized tail
+    const SIZE: usize;
+    /// Base alignment in bytes
+    const ALIGN: usize;
+    /// Name of the glsl type
+    const GLSL: &'static str;
+    const FIELDS: &'static [GpuField];
+
+    /// Number of floats following the fixed part, for types whose size depends
+    /// on their value
+    fn tail_len(&self) -> usize {
+        0
+    }
+
+    /// Number of floats written by `write_to`
+    fn nr_floats(&self) -> usize {
+        Self::SIZE / 4 + self.tail_len()
+    }
+
+    /// Writes to the start of `out`, which holds at least `nr_floats` floats.
+    /// Padding is left untouched.
+    fn write_to(&self, out: &mut [f32]);
+}
+
+impl GpuLayout for f32 {
+    const SIZE: usize = 4;
+    const ALIGN: usize = 4;
+    const GLSL: &'static str = "float";
+    const FIELDS: &'static [GpuField] = &[];
+
+    fn write_to(&self, out: &mut [f32]) {
+        out[0] = *self;
+    }
+}
+
+/// Written bit for bit, the shader reads it as an `int`
+impl GpuLayout for i32 {
+    const SIZE: usize = 4;
+    const ALIGN: usize = 4;
+    const GLSL: &'static str = "int";
+    const FIELDS: &'static [GpuField] = &[];
+
+    fn write_to(&self, out: &mut [f32]) {
+        out[0] = f32::from_bits(*self as u32);
+    }
+}
+
+/// A `vec3`, aligned like a `vec4`
+impl GpuLayout for [f32; 3] {
+    const SIZE: usize = 12;
+    const ALIGN: usize = 16;
+    const GLSL: &'static str = "vec3";
+    const FIELDS: &'static [GpuField] = &[];
+
+    fn write_to(&self, out: &mut [f32]) {
+        out[..3].copy_from_slice(self);
+    }
+}
+
+/// A glsl `mat3`, std430 pads every column to a `vec4`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mat3(pub [[f32; 4]; 3]);
+
+impl From<Matrix3<f32>> for Mat3 {
+    fn from(m: Matrix3<f32>) -> Self {
+        Mat3([
+            [m.x.x, m.x.y, m.x.z, 0.0],
+            [m.y.x, m.y.y, m.y.z, 0.0],
+            [m.z.x, m.z.y, m.z.z, 0.0],
+        ])
+    }
+}
+
+impl GpuLayout for Mat3 {
+    const SIZE: usize = 48;
+    const ALIGN: usize = 16;
+    const GLSL: &'static str = "mat3";
+    const FIELDS: &'static [GpuField] = &[];
+
+    fn write_to(&self, out: &mut [f32]) {
+        for (i, column) in self.0.iter().enumerate() {
+            out[4 * i..4 * i + 3].copy_from_slice(&column[..3]);
+        }
+    }
+}
+
+/// Rounds `offset` up to a multiple of `align`, a power of two
+pub const fn align_up(offset: usize, align: usize) -> usize {
+    (offset + align - 1) & !(align - 1)
+}
+
+/// Offset in bytes of a nested field, `path` names the field on every level
+pub fn offset_of(fields: &[GpuField], path: &[&str]) -> Option<usize> {
+    let (name, rest) = path.split_first()?;
+    let field = fields.iter().find(|f| f.name == *name)?;
+    if rest.is_empty() {
+        Some(field.offset)
+    } else {
+        offset_of(field.fields, rest).map(|offset| field.offset + offset)
+    }
+}
+
+/// Glsl declaration of the struct `T`, the structs of its fields have to be
+/// declared before
+pub fn glsl_struct<T: GpuLayout>() -> String {
+    let mut glsl = format!("struct {} {{\n", T::GLSL);
+    for field in T::FIELDS {
+        glsl.push_str(&format!("    {} {};\n", field.glsl, field.name));
+    }
+    glsl.push_str("};\n");
+    glsl
+}
+
+/// Appends `value` to `buffer`, growing it by `nr_floats`
+pub fn push<T: GpuLayout>(buffer: &mut Vec<f32>, value: &T) {
+    let start = buffer.len();
+    buffer.resize(start + value.nr_floats(), 0.0);
+    value.write_to(&mut buffer[start..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geospace::Transform;
+    use crate::models::ModelProperty;
+
+    #[test]
+    fn derived_layout() {
+        assert_eq!(Transform::SIZE, 28);
+        assert_eq!(Transform::ALIGN, 4);
+        assert_eq!(ModelProperty::SIZE, 48);
+
+        let names: Vec<&str> = ModelProperty::FIELDS.iter().map(|f| f.name).collect();
+        assert_eq!(names, vec!["tf", "color", "material", "t"]);
+        assert_eq!(offset_of(ModelProperty::FIELDS, &["tf", "scale"]), Some(12));
+        assert_eq!(offset_of(ModelProperty::FIELDS, &["color", "g"]), Some(32));
+        assert_eq!(offset_of(ModelProperty::FIELDS, &["material", "reflectance"]), Some(40));
+        assert_eq!(offset_of(ModelProperty::FIELDS, &["material", "roughness"]), Some(44));
+        assert_eq!(offset_of(ModelProperty::FIELDS, &["color", "a"]), None);
+    }
+}
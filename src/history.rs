@@ -0,0 +1,401 @@
+use std::collections::VecDeque;
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::geospace::Transform;
+use crate::material::Material;
+use crate::models::{ModelId, ModelProperty};
+use crate::scene::Scene;
+
+/// Number of undoable steps kept by default
+pub const HISTORY_LIMIT: usize = 100;
+
+/// A model while it is out of the scene, with the transforms of its
+/// instances if it is a prototype
+#[derive(Debug)]
+struct Stored {
+    model: ModelProperty,
+    instances: Option<Vec<Transform>>,
+}
+
+/// A model that is added or removed. Its handle is only known once it has
+/// been added, later it is restored under the same handle.
+#[derive(Debug)]
+pub struct Presence {
+    id: Option<ModelId>,
+    stored: Option<Stored>,
+}
+
+impl Presence {
+    fn insert(&mut self, scene: &mut Scene) -> bool {
+        let stored = match self.stored.take() {
+            Some(stored) => stored,
+            None => return false,
+        };
+        match self.id {
+            None => {
+                let id = match stored.instances {
+                    Some(instances) => scene.models.add_instances(stored.model, instances),
+                    None => scene.models.add_new(stored.model),
+                };
+                self.id = Some(id);
+                true
+            }
+            Some(id) => match scene.models.restore(id, stored.model, stored.instances.clone()) {
+                Ok(()) => true,
+                Err(model) => {
+                    self.stored = Some(Stored { model, instances: stored.instances });
+                    false
+                }
+            },
+        }
+    }
+
+    fn take(&mut self, scene: &mut Scene) -> bool {
+        let id = match self.id {
+            Some(id) => id,
+            None => return false,
+        };
+        let instances = scene.models.instances(id).map(|instances| instances.to_vec());
+        match scene.models.remove(id) {
+            Some(model) => {
+                self.stored = Some(Stored { model, instances });
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A reversible change to a scene. The set commands swap their value with
+/// the one in the scene, so that they hold the value to go back to once
+/// applied.
+#[derive(Debug)]
+pub enum Command {
+    Add(Presence),
+    Remove(Presence),
+    SetTransform { id: ModelId, tf: Transform },
+    SetMaterial { id: ModelId, color: Color, material: Material },
+    /// the camera at `index` in the list of cameras
+    SetCamera { index: usize, camera: Camera },
+}
+
+/// What a command touched, for whoever redraws the scene
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Model(ModelId),
+    Camera(usize),
+}
+
+impl Command {
+    pub fn add(model: ModelProperty) -> Self {
+        Command::Add(Presence { id: None, stored: Some(Stored { model, instances: None }) })
+    }
+
+    pub fn add_instances(prototype: ModelProperty, instances: Vec<Transform>) -> Self {
+        Command::Add(Presence { id: None, stored: Some(Stored { model: prototype, instances: Some(instances) }) })
+    }
+
+    pub fn remove(id: ModelId) -> Self {
+        Command::Remove(Presence { id: Some(id), stored: None })
+    }
+
+    /// The model or camera it is about, `None` for a model not added yet
+    pub fn change(&self) -> Option<Change> {
+        match *self {
+            Command::Add(ref p) | Command::Remove(ref p) => p.id.map(Change::Model),
+            Command::SetTransform { id, .. } | Command::SetMaterial { id, .. } => Some(Change::Model(id)),
+            Command::SetCamera { index, .. } => Some(Change::Camera(index)),
+        }
+    }
+
+    /// Does the command, returns false if what it refers to is gone
+    pub fn apply(&mut self, scene: &mut Scene) -> bool {
+        match self {
+            Command::Add(p) => p.insert(scene),
+            Command::Remove(p) => p.take(scene),
+            _ => self.swap(scene),
+        }
+    }
+
+    /// Undoes the command after it was applied
+    pub fn revert(&mut self, scene: &mut Scene) -> bool {
+        match self {
+            Command::Add(p) => p.take(scene),
+            Command::Remove(p) => p.insert(scene),
+            _ => self.swap(scene),
+        }
+    }
+
+    fn swap(&mut self, scene: &mut Scene) -> bool {
+        match self {
+            Command::SetTransform { id, tf } => match scene.models.get_mut(*id) {
+                Some(model) => {
+                    std::mem::swap(&mut model.tf, tf);
+                    true
+                }
+                None => false,
+            },
+            Command::SetMaterial { id, color, material } => match scene.models.get_mut(*id) {
+                Some(model) => {
+                    std::mem::swap(&mut model.color, color);
+                    std::mem::swap(&mut model.material, material);
+                    true
+                }
+                None => false,
+            },
+            Command::SetCamera { index, camera } => match scene.cameras.get_mut(*index) {
+                Some(current) => {
+                    std::mem::swap(current, camera);
+                    true
+                }
+                None => false,
+            },
+            Command::Add(_) | Command::Remove(_) => false,
+        }
+    }
+
+    /// Whether `self` sets the same thing as `other`, so that applying it
+    /// after `other` can be folded into it, which holds the value to go back to
+    fn same_target(&self, other: &Command) -> bool {
+        match (self, other) {
+            (Command::SetTransform { id: a, .. }, Command::SetTransform { id: b, .. }) => a == b,
+            (Command::SetMaterial { id: a, .. }, Command::SetMaterial { id: b, .. }) => a == b,
+            (Command::SetCamera { index: a, .. }, Command::SetCamera { index: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Applied commands that can be undone and redone. Commands applied while a
+/// group is open, like the steps of a drag, are undone together.
+#[derive(Debug)]
+pub struct History {
+    done: VecDeque<Vec<Command>>,
+    undone: Vec<Vec<Command>>,
+    limit: usize,
+    grouping: bool,
+    changes: Vec<Change>,
+}
+
+impl History {
+    /// Keeps up to `limit` steps, the oldest are forgotten first
+    pub fn new(limit: usize) -> Self {
+        History {
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            limit: limit.max(1),
+            grouping: false,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Applies the command and records it, clearing what was undone.
+    /// Returns false, recording nothing, if it could not be applied.
+    pub fn apply(&mut self, scene: &mut Scene, mut command: Command) -> bool {
+        if !command.apply(scene) {
+            return false;
+        }
+        self.notify(&command);
+        self.undone.clear();
+
+        if self.grouping {
+            if let Some(group) = self.done.back_mut() {
+                // repeated changes of one thing only keep the first old value
+                if group.iter().any(|earlier| command.same_target(earlier)) {
+                    return true;
+                }
+                group.push(command);
+                return true;
+            }
+        }
+        self.done.push_back(vec![command]);
+        if self.done.len() > self.limit {
+            self.done.pop_front();
+        }
+        true
+    }
+
+    /// Commands applied until `end_group` make up a single step
+    pub fn begin_group(&mut self) {
+        self.end_group();
+        self.done.push_back(Vec::new());
+        if self.done.len() > self.limit {
+            self.done.pop_front();
+        }
+        self.grouping = true;
+    }
+
+    pub fn end_group(&mut self) {
+        if self.grouping {
+            self.grouping = false;
+            if matches!(self.done.back(), Some(group) if group.is_empty()) {
+                self.done.pop_back();
+            }
+        }
+    }
+
+    pub fn is_grouping(&self) -> bool {
+        self.grouping
+    }
+
+    /// Reverts the last step, returns false if there was none or it could
+    /// not be reverted, in which case it is dropped
+    pub fn undo(&mut self, scene: &mut Scene) -> bool {
+        self.end_group();
+        let mut group = match self.done.pop_back() {
+            Some(group) => group,
+            None => return false,
+        };
+        let mut reverted = true;
+        for command in group.iter_mut().rev() {
+            reverted &= command.revert(scene);
+            self.notify(command);
+        }
+        if reverted {
+            self.undone.push(group);
+        }
+        reverted
+    }
+
+    /// Applies the last undone step again
+    pub fn redo(&mut self, scene: &mut Scene) -> bool {
+        self.end_group();
+        let mut group = match self.undone.pop() {
+            Some(group) => group,
+            None => return false,
+        };
+        let mut applied = true;
+        for command in group.iter_mut() {
+            applied &= command.apply(scene);
+            self.notify(command);
+        }
+        if applied {
+            self.done.push_back(group);
+        }
+        applied
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.done.iter().any(|group| !group.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    /// Forgets every step
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+        self.grouping = false;
+    }
+
+    fn notify(&mut self, command: &Command) {
+        if let Some(change) = command.change() {
+            if !self.changes.contains(&change) {
+                self.changes.push(change);
+            }
+        }
+    }
+
+    /// What changed since the last call, each model or camera once
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(HISTORY_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ModelType;
+    use crate::{col, define_material, transform};
+
+    fn ball(x: f32) -> ModelProperty {
+        ModelProperty {
+            t: ModelType::Sphere,
+            tf: transform!(x, 0.0, 0.0),
+            color: col!(white),
+            material: define_material!(0.5),
+        }
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut scene = Scene::new();
+        let mut history = History::default();
+        assert!(!history.can_undo());
+
+        assert!(history.apply(&mut scene, Command::add(ball(0.0))));
+        assert!(history.apply(&mut scene, Command::add(ball(1.0))));
+        let id = match history.take_changes()[..] {
+            [_, Change::Model(id)] => id,
+            ref other => panic!("unexpected {:?}", other),
+        };
+        history.apply(&mut scene, Command::SetTransform { id, tf: transform!(2.0, 0.0, 0.0) });
+        history.apply(&mut scene, Command::SetMaterial { id, color: col!(red), material: define_material!(1.0) });
+        history.apply(&mut scene, Command::remove(id));
+        assert_eq!(scene.models.len(), 1);
+
+        // the removed model comes back under its handle, as it was
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.models[&id].color, col!(red));
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.models[&id].material, define_material!(0.5));
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.models[&id].tf.x, 1.0);
+        assert!(history.redo(&mut scene));
+        assert_eq!(scene.models[&id].tf.x, 2.0);
+
+        // a new command drops what was undone
+        history.apply(&mut scene, Command::SetTransform { id, tf: transform!(3.0, 0.0, 0.0) });
+        assert!(!history.can_redo());
+        for _ in 0..4 {
+            assert!(history.undo(&mut scene));
+        }
+        assert_eq!(scene.models.len(), 0);
+        assert!(!history.undo(&mut scene));
+
+        let changes = history.take_changes();
+        assert!(changes.contains(&Change::Model(id)));
+        assert!(history.take_changes().is_empty());
+    }
+
+    #[test]
+    fn drags_are_undone_at_once() {
+        let mut scene = Scene::new();
+        let id = scene.models.add_new(ball(0.0));
+        let mut history = History::new(2);
+
+        history.begin_group();
+        for x in 1..10 {
+            history.apply(&mut scene, Command::SetTransform { id, tf: transform!(x as f32, 0.0, 0.0) });
+        }
+        history.end_group();
+        assert_eq!(scene.models[&id].tf.x, 9.0);
+        assert!(history.undo(&mut scene));
+        assert_eq!(scene.models[&id].tf.x, 0.0);
+        assert!(history.redo(&mut scene));
+        assert_eq!(scene.models[&id].tf.x, 9.0);
+
+        // only the last two steps are kept
+        for x in 10..13 {
+            history.apply(&mut scene, Command::SetTransform { id, tf: transform!(x as f32, 0.0, 0.0) });
+        }
+        assert!(history.undo(&mut scene) && history.undo(&mut scene));
+        assert!(!history.undo(&mut scene));
+        assert_eq!(scene.models[&id].tf.x, 10.0);
+
+        // a reused slot can't be given back
+        let removed = scene.models.remove(id).unwrap();
+        let other = scene.models.add_new(ball(5.0));
+        assert_eq!(other.index(), id.index());
+        assert!(scene.models.restore(id, removed, None).is_err());
+    }
+}
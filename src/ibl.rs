@@ -0,0 +1,453 @@
+use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::PI;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use cgmath::{InnerSpace, Vector3};
+use crate::environment::{texel_direction, CubeMap};
+use crate::image::{Image, ImageError, ImageResult};
+
+// Image based lighting, precomputed on the cpu from the skybox. The shader
+// shades with the split sum approximation: the environment convolved with the
+// ggx lobe at some roughness, times a scale and bias of the fresnel term from
+// the brdf lookup table.
+
+/// Side length of the irradiance cube map
+pub const IRRADIANCE_SIZE: usize = 32;
+/// Side length of the sharpest level of the specular cube map
+pub const SPECULAR_SIZE: usize = 128;
+/// Mip levels of the specular cube map, level `i` has the roughness
+/// `i / (SPECULAR_LEVELS - 1)`
+pub const SPECULAR_LEVELS: usize = 6;
+pub const SPECULAR_SAMPLES: usize = 128;
+pub const BRDF_LUT_SIZE: usize = 64;
+pub const BRDF_LUT_SAMPLES: usize = 256;
+
+const CACHE_MAGIC: &[u8] = b"HUGIBL1\n";
+
+/// Defines shared with the shader
+pub fn glsl_header() -> String {
+    format!("#define IBL_SPECULAR_LEVELS {}\n", SPECULAR_LEVELS)
+}
+
+/// Fills `rows` rows of `row_len` floats on every core, `fill(i, row)` writes
+/// row `i`
+fn parallel_rows<F: Fn(usize, &mut [f32]) + Sync>(rows: usize, row_len: usize, fill: F) -> Vec<f32> {
+    let mut data = vec![0.0; rows * row_len];
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let rows_per_thread = rows / threads + 1;
+    let fill = &fill;
+    thread::scope(|s| {
+        for (chunk_index, chunk) in data.chunks_mut(rows_per_thread * row_len).enumerate() {
+            s.spawn(move || {
+                for (i, row) in chunk.chunks_mut(row_len).enumerate() {
+                    fill(chunk_index * rows_per_thread + i, row);
+                }
+            });
+        }
+    });
+    data
+}
+
+/// `CubeMap::from_fn` on every core
+fn parallel_cube<F: Fn(Vector3<f32>) -> [f32; 3] + Sync>(size: usize, f: F) -> CubeMap {
+    let data = parallel_rows(6 * size, 3 * size, |i, row| {
+        let (face, r) = (i / size, i % size);
+        for (column, rgb) in row.chunks_mut(3).enumerate() {
+            rgb.copy_from_slice(&f(texel_direction(face, column, r, size)));
+        }
+    });
+    CubeMap {
+        size,
+        faces: data.chunks(3 * size * size).map(|face| face.to_vec()).collect(),
+    }
+}
+
+/// The first nine real spherical harmonics at the unit vector `d`
+fn sh9_basis(d: Vector3<f32>) -> [f32; 9] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+/// Radiance of an environment in the first three bands of spherical harmonics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sh9(pub [[f32; 3]; 9]);
+
+impl Sh9 {
+    /// Projects the cube map, every texel weighted by its solid angle
+    pub fn project(cube: &CubeMap) -> Self {
+        let size = cube.size;
+        let partial_sums: Vec<([[f32; 3]; 9], f32)> = thread::scope(|s| {
+            let handles: Vec<_> = (0..6)
+                .map(|face| {
+                    s.spawn(move || {
+                        let mut sum = [[0.0; 3]; 9];
+                        let mut weights = 0.0;
+                        for row in 0..size {
+                            for column in 0..size {
+                                let a = 2.0 * (column as f32 + 0.5) / size as f32 - 1.0;
+                                let b = 2.0 * (row as f32 + 0.5) / size as f32 - 1.0;
+                                let solid_angle = (1.0 + a * a + b * b).powf(-1.5);
+                                let basis = sh9_basis(texel_direction(face, column, row, size));
+                                let i = 3 * (row * size + column);
+                                let rgb = &cube.faces[face][i..i + 3];
+                                for (coefficients, y) in sum.iter_mut().zip(basis) {
+                                    for (coefficient, x) in coefficients.iter_mut().zip(rgb) {
+                                        *coefficient += x * y * solid_angle;
+                                    }
+                                }
+                                weights += solid_angle;
+                            }
+                        }
+                        (sum, weights)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().expect("projection thread panicked")).collect()
+        });
+
+        let mut sh = [[0.0; 3]; 9];
+        let weights: f32 = partial_sums.iter().map(|(_, w)| w).sum();
+        for (sum, _) in &partial_sums {
+            for k in 0..9 {
+                for c in 0..3 {
+                    sh[k][c] += sum[k][c] * 4.0 * PI / weights;
+                }
+            }
+        }
+        Sh9(sh)
+    }
+
+    /// Irradiance divided by pi at a surface with the normal `n`, times the
+    /// albedo this is the diffuse light reflected by the surface
+    pub fn diffuse(&self, n: Vector3<f32>) -> [f32; 3] {
+        // convolution with the clamped cosine, per band
+        const BAND: [f32; 9] = [1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.25, 0.25, 0.25, 0.25, 0.25];
+        let basis = sh9_basis(n);
+        let mut rgb = [0.0; 3];
+        for ((band, coefficients), y) in BAND.iter().zip(&self.0).zip(basis) {
+            for (x, coefficient) in rgb.iter_mut().zip(coefficients) {
+                *x += band * coefficient * y;
+            }
+        }
+        rgb.map(|x| x.max(0.0))
+    }
+}
+
+/// Diffuse light for a white surface facing each texel, see `Sh9::diffuse`.
+/// Fast enough to follow an animated sky.
+pub fn irradiance_map(cube: &CubeMap) -> CubeMap {
+    let sh = Sh9::project(cube);
+    parallel_cube(IRRADIANCE_SIZE, |n| sh.diffuse(n))
+}
+
+/// Point `i` of `n` of the hammersley set
+fn hammersley(i: usize, n: usize) -> (f32, f32) {
+    (i as f32 / n as f32, (i as u32).reverse_bits() as f32 / 4294967296.0)
+}
+
+/// Half vector around `n` distributed like the ggx normals with the
+/// roughness `alpha` squared
+fn importance_sample_ggx(xi: (f32, f32), n: Vector3<f32>, roughness: f32) -> Vector3<f32> {
+    let alpha = roughness * roughness;
+    let phi = 2.0 * PI * xi.0;
+    let cos_theta = ((1.0 - xi.1) / (1.0 + (alpha * alpha - 1.0) * xi.1)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let up = if n.z.abs() < 0.999 { Vector3::unit_z() } else { Vector3::unit_x() };
+    let tangent = up.cross(n).normalize();
+    let bitangent = n.cross(tangent);
+    (tangent * phi.cos() * sin_theta + bitangent * phi.sin() * sin_theta + n * cos_theta).normalize()
+}
+
+fn ggx_distribution(n_dot_h: f32, roughness: f32) -> f32 {
+    let alpha2 = roughness.powi(4);
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+/// Trilinear lookup into a mip chain
+fn sample_lod(chain: &[CubeMap], d: Vector3<f32>, lod: f32) -> [f32; 3] {
+    let lod = lod.clamp(0.0, (chain.len() - 1) as f32);
+    let (lower, t) = (lod.floor() as usize, lod.fract());
+    let a = chain[lower].sample(d);
+    if t == 0.0 {
+        return a;
+    }
+    let b = chain[lower + 1].sample(d);
+    [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t)
+}
+
+/// Mip levels of the environment convolved with the ggx lobe, the roughness
+/// going from 0 at level 0 to 1 at the last one. Samples are read from
+/// blurrier mips of the source where they are sparse, which keeps few
+/// samples free of fireflies.
+pub fn prefilter_specular(cube: &CubeMap, size: usize, levels: usize, samples: usize) -> Vec<CubeMap> {
+    let mut chain = vec![cube.clone()];
+    while chain.last().unwrap().size > 1 {
+        let next = chain.last().unwrap().downsample();
+        chain.push(next);
+    }
+    let texel_solid_angle = 4.0 * PI / (6.0 * (cube.size * cube.size) as f32);
+    let base_lod = (cube.size as f32 / size as f32).log2().max(0.0);
+
+    (0..levels)
+        .map(|level| {
+            let roughness = level as f32 / (levels - 1).max(1) as f32;
+            let level_size = (size >> level).max(1);
+            if roughness == 0.0 {
+                return parallel_cube(level_size, |n| sample_lod(&chain, n, base_lod));
+            }
+            parallel_cube(level_size, |n| {
+                let mut sum = [0.0; 3];
+                let mut weights = 0.0;
+                for i in 0..samples {
+                    let h = importance_sample_ggx(hammersley(i, samples), n, roughness);
+                    let n_dot_h = n.dot(h).max(0.0);
+                    let l = h * 2.0 * n_dot_h - n;
+                    let n_dot_l = n.dot(l);
+                    if n_dot_l <= 0.0 {
+                        continue;
+                    }
+                    // with n = v the pdf of l is d / 4
+                    let pdf = ggx_distribution(n_dot_h, roughness) / 4.0 + 1e-4;
+                    let sample_solid_angle = 1.0 / (samples as f32 * pdf);
+                    let lod = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+                    let rgb = sample_lod(&chain, l, lod.max(base_lod));
+                    for c in 0..3 {
+                        sum[c] += rgb[c] * n_dot_l;
+                    }
+                    weights += n_dot_l;
+                }
+                sum.map(|x| x / weights)
+            })
+        })
+        .collect()
+}
+
+/// Scale (red) and bias (green) of the fresnel reflectance at normal
+/// incidence for the ggx brdf, `n·v` along the rows and the roughness down
+/// the columns
+pub fn brdf_lut(size: usize, samples: usize) -> Image {
+    let data = parallel_rows(size, 3 * size, |row, pixels| {
+        let roughness = (row as f32 + 0.5) / size as f32;
+        // schlick-ggx for image based lighting
+        let k = roughness * roughness / 2.0;
+        let g1 = |n_dot_x: f32| n_dot_x / (n_dot_x * (1.0 - k) + k);
+
+        for (column, rgb) in pixels.chunks_mut(3).enumerate() {
+            let n_dot_v = (column as f32 + 0.5) / size as f32;
+            let v = Vector3::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+            let (mut scale, mut bias) = (0.0, 0.0);
+            for i in 0..samples {
+                let h = importance_sample_ggx(hammersley(i, samples), Vector3::unit_z(), roughness);
+                let v_dot_h = v.dot(h).max(0.0);
+                let l = h * 2.0 * v_dot_h - v;
+                if l.z <= 0.0 {
+                    continue;
+                }
+                let visibility = g1(n_dot_v) * g1(l.z) * v_dot_h / (h.z * n_dot_v);
+                let fresnel = (1.0 - v_dot_h).powi(5);
+                scale += (1.0 - fresnel) * visibility;
+                bias += fresnel * visibility;
+            }
+            rgb.copy_from_slice(&[scale / samples as f32, bias / samples as f32, 0.0]);
+        }
+    });
+    Image { width: size, height: size, data }
+}
+
+/// Everything the shader needs for image based lighting
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ibl {
+    /// diffuse light for a white surface, see `Sh9::diffuse`
+    pub irradiance: CubeMap,
+    /// mip levels, see `prefilter_specular`
+    pub specular: Vec<CubeMap>,
+    pub brdf_lut: Image,
+}
+
+impl Ibl {
+    pub fn compute(cube: &CubeMap) -> Self {
+        Ibl {
+            irradiance: irradiance_map(cube),
+            specular: prefilter_specular(cube, SPECULAR_SIZE, SPECULAR_LEVELS, SPECULAR_SAMPLES),
+            brdf_lut: brdf_lut(BRDF_LUT_SIZE, BRDF_LUT_SAMPLES),
+        }
+    }
+
+    /// File in `dir` for the lighting of `cube`, named after a hash of the
+    /// cube map and of the settings
+    pub fn cache_path<P: AsRef<Path>>(dir: P, cube: &CubeMap) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        (IRRADIANCE_SIZE, SPECULAR_SIZE, SPECULAR_LEVELS, SPECULAR_SAMPLES, BRDF_LUT_SIZE, BRDF_LUT_SAMPLES).hash(&mut hasher);
+        cube.size.hash(&mut hasher);
+        for face in &cube.faces {
+            for x in face {
+                x.to_bits().hash(&mut hasher);
+            }
+        }
+        dir.as_ref().join(format!("ibl-{:016x}.bin", hasher.finish()))
+    }
+
+    /// Reads lighting written by `write`
+    pub fn read<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        let bytes = fs::read(path)?;
+        let mut reader = CacheReader { bytes: &bytes, at: 0 };
+        if reader.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+            return Err(ImageError::Decode(String::from("not an ibl cache file")));
+        }
+        let irradiance = reader.cube_map()?;
+        let levels = reader.u32()? as usize;
+        let specular = (0..levels).map(|_| reader.cube_map()).collect::<ImageResult<Vec<CubeMap>>>()?;
+        let size = reader.u32()? as usize;
+        let data = reader.square(size)?;
+        Ok(Ibl { irradiance, specular, brdf_lut: Image { width: size, height: size, data } })
+    }
+
+    /// Writes the lighting as little endian sizes and floats, creating the
+    /// directories on the way
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        let write_floats = |out: &mut io::BufWriter<fs::File>, xs: &[f32]| -> io::Result<()> {
+            for x in xs {
+                out.write_all(&x.to_le_bytes())?;
+            }
+            Ok(())
+        };
+        let write_cube = |out: &mut io::BufWriter<fs::File>, cube: &CubeMap| -> io::Result<()> {
+            out.write_all(&(cube.size as u32).to_le_bytes())?;
+            for face in &cube.faces {
+                write_floats(out, face)?;
+            }
+            Ok(())
+        };
+
+        out.write_all(CACHE_MAGIC)?;
+        write_cube(&mut out, &self.irradiance)?;
+        out.write_all(&(self.specular.len() as u32).to_le_bytes())?;
+        for level in &self.specular {
+            write_cube(&mut out, level)?;
+        }
+        out.write_all(&(self.brdf_lut.width as u32).to_le_bytes())?;
+        write_floats(&mut out, &self.brdf_lut.data)?;
+        out.flush()
+    }
+}
+
+struct CacheReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    fn take(&mut self, n: usize) -> ImageResult<&'a [u8]> {
+        let bytes = self.bytes[self.at..].get(..n).ok_or_else(ends_early)?;
+        self.at += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> ImageResult<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn floats(&mut self, n: usize) -> ImageResult<Vec<f32>> {
+        let len = n.checked_mul(4).ok_or_else(ends_early)?;
+        Ok(self.take(len)?.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    /// The rgb floats of a square of `size`²
+    fn square(&mut self, size: usize) -> ImageResult<Vec<f32>> {
+        let n = size.checked_mul(size).and_then(|n| n.checked_mul(3)).ok_or_else(ends_early)?;
+        self.floats(n)
+    }
+
+    fn cube_map(&mut self) -> ImageResult<CubeMap> {
+        let size = self.u32()? as usize;
+        let faces = (0..6).map(|_| self.square(size)).collect::<ImageResult<Vec<Vec<f32>>>>()?;
+        Ok(CubeMap { size, faces })
+    }
+}
+
+/// A truncated cache file, or one with sizes too large for it
+fn ends_early() -> ImageError {
+    ImageError::Decode(String::from("ibl cache file ends early"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_environment_stays_constant() {
+        let gray = CubeMap::from_fn(16, |_| [0.5; 3]);
+        let sh = Sh9::project(&gray);
+        for d in [Vector3::unit_y(), Vector3::new(1.0, -1.0, 0.5).normalize()] {
+            for c in sh.diffuse(d) {
+                assert!((c - 0.5).abs() < 1e-3);
+            }
+        }
+        for level in prefilter_specular(&gray, 8, 3, 16) {
+            assert!(level.faces.iter().flatten().all(|x| (x - 0.5).abs() < 1e-3));
+        }
+    }
+
+    #[test]
+    fn diffuse_light_follows_the_sky() {
+        // bright above, dark below
+        let sky = CubeMap::from_fn(16, |d| [d.y.max(0.0); 3]);
+        let sh = Sh9::project(&sky);
+        // the exact value facing up is 2/3, sh9 is close to it
+        assert!((sh.diffuse(Vector3::unit_y())[0] - 2.0 / 3.0).abs() < 0.05);
+        assert!(sh.diffuse(-Vector3::unit_y())[0] < 0.05);
+
+        let specular = prefilter_specular(&sky, 8, 3, 32);
+        assert_eq!(specular.iter().map(|c| c.size).collect::<Vec<_>>(), vec![8, 4, 2]);
+        // rougher levels spread the light towards the horizon
+        let side = Vector3::new(1.0, 0.3, 0.0).normalize();
+        assert!(specular[2].sample(side)[0] < specular[0].sample(side)[0]);
+    }
+
+    #[test]
+    fn brdf_lut_and_cache() {
+        let lut = brdf_lut(8, 64);
+        // a smooth surface seen head on reflects everything
+        let smooth = lut.pixel(7, 0);
+        assert!((smooth[0] + smooth[1] - 1.0).abs() < 0.05);
+        assert!(lut.data.iter().all(|x| (0.0..=1.0).contains(x)));
+
+        let cube = CubeMap::from_fn(4, |d| [d.x.abs(), d.y.abs(), d.z.abs()]);
+        let ibl = Ibl {
+            irradiance: cube.clone(),
+            specular: vec![cube.clone(), cube.downsample()],
+            brdf_lut: lut,
+        };
+        let path = Ibl::cache_path(std::env::temp_dir().join("hugengine_ibl_test"), &cube);
+        assert_ne!(path, Ibl::cache_path(std::env::temp_dir().join("hugengine_ibl_test"), &ibl.specular[1]));
+        ibl.write(&path).unwrap();
+        assert_eq!(Ibl::read(&path).unwrap(), ibl);
+        fs::write(&path, b"HUGIBL1\n\x04").unwrap();
+        assert!(matches!(Ibl::read(&path), Err(ImageError::Decode(_))));
+        fs::write(&path, b"HUGIBL1\n\xff\xff\xff\xff").unwrap();
+        assert!(matches!(Ibl::read(&path), Err(ImageError::Decode(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
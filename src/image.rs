@@ -0,0 +1,403 @@
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Decode(String),
+    Unsupported(String),
+}
+
+impl From<io::Error> for ImageError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(e: png::DecodingError) -> Self {
+        Self::Decode(e.to_string())
+    }
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Decode(s) => write!(f, "bad image: {}", s),
+            Self::Unsupported(s) => write!(f, "unsupported image: {}", s),
+        }
+    }
+}
+
+impl error::Error for ImageError {}
+
+pub type ImageResult<T> = Result<T, ImageError>;
+
+/// An rgb image of floats, stored row by row from the top
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            data: vec![0.0; width * height * 3],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> [f32; 3] {
+        let i = 3 * (y * self.width + x);
+        [self.data[i], self.data[i + 1], self.data[i + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgb: [f32; 3]) {
+        let i = 3 * (y * self.width + x);
+        self.data[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    /// Bilinear lookup at `(u, v)` in [0, 1], `(0, 0)` being the top left
+    /// corner. Wraps around horizontally, clamps vertically. An empty image
+    /// is black.
+    pub fn sample(&self, u: f32, v: f32) -> [f32; 3] {
+        if self.width == 0 || self.height == 0 {
+            return [0.0; 3];
+        }
+        let x = u * self.width as f32 - 0.5;
+        let y = (v * self.height as f32 - 0.5).clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let column = |x: f32| (x as i64).rem_euclid(self.width as i64) as usize;
+        let (xa, xb) = (column(x0), column(x0 + 1.0));
+        let (ya, yb) = (y0 as usize, (y0 as usize + 1).min(self.height - 1));
+
+        let mut rgb = [0.0; 3];
+        for (x, y, w) in [
+            (xa, ya, (1.0 - fx) * (1.0 - fy)),
+            (xb, ya, fx * (1.0 - fy)),
+            (xa, yb, (1.0 - fx) * fy),
+            (xb, yb, fx * fy),
+        ] {
+            let p = self.pixel(x, y);
+            for c in 0..3 {
+                rgb[c] += w * p[c];
+            }
+        }
+        rgb
+    }
+}
+
+/// Loads an image, the format is picked by the extension
+pub fn load<P: AsRef<Path>>(path: P) -> ImageResult<Image> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        "png" => load_png(path),
+        "hdr" => decode_hdr(&fs::read(path)?),
+        "pfm" => decode_pfm(&fs::read(path)?),
+        _ => Err(ImageError::Unsupported(format!("{} is not a png, hdr or pfm image", path.display()))),
+    }
+}
+
+/// 8 and 16 bit pngs of any color type, the values are kept as stored
+pub fn load_png<P: AsRef<Path>>(path: P) -> ImageResult<Image> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let channels = info.color_type.samples();
+    let mut image = Image::new(info.width as usize, info.height as usize);
+    for (i, pixel) in buffer[..info.buffer_size()].chunks_exact(channels).enumerate() {
+        let value = |c: usize| pixel[c] as f32 / 255.0;
+        let rgb = match channels {
+            // gray, possibly with alpha
+            1 | 2 => [value(0); 3],
+            _ => [value(0), value(1), value(2)],
+        };
+        image.data[3 * i..3 * i + 3].copy_from_slice(&rgb);
+    }
+    Ok(image)
+}
+
+/// Reads a line ending in `\n` starting at `*at`, without the newline
+fn read_line<'a>(bytes: &'a [u8], at: &mut usize) -> ImageResult<&'a str> {
+    let rest = &bytes[*at..];
+    let end = rest
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| ImageError::Decode(String::from("header ends early")))?;
+    *at += end + 1;
+    std::str::from_utf8(&rest[..end]).map_err(|_| ImageError::Decode(String::from("header is not text")))
+}
+
+fn rgbe_to_rgb(rgbe: &[u8]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0; 3];
+    }
+    let f = 2f32.powi(rgbe[3] as i32 - (128 + 8));
+    [rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f]
+}
+
+/// Radiance rgbe images, with flat or run length encoded scanlines
+pub fn decode_hdr(bytes: &[u8]) -> ImageResult<Image> {
+    let bad = |s: &str| ImageError::Decode(s.to_owned());
+    let mut at = 0;
+    let magic = read_line(bytes, &mut at)?;
+    if magic != "#?RADIANCE" && magic != "#?RGBE" {
+        return Err(bad("not a radiance hdr image"));
+    }
+    loop {
+        let line = read_line(bytes, &mut at)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(ImageError::Unsupported(format!("hdr format {}", format)));
+            }
+        }
+    }
+
+    // only the usual orientations: rows from the top or from the bottom,
+    // columns from the left
+    let resolution: Vec<&str> = read_line(bytes, &mut at)?.split_whitespace().collect();
+    let (flipped, height, width) = match resolution.as_slice() {
+        [y @ ("-Y" | "+Y"), height, "+X", width] => (*y == "+Y", height.parse::<usize>(), width.parse::<usize>()),
+        _ => return Err(ImageError::Unsupported(format!("hdr resolution {}", resolution.join(" ")))),
+    };
+    let (height, width) = match (height, width) {
+        (Ok(h), Ok(w)) if h > 0 && w > 0 => (h, w),
+        (Ok(_), Ok(_)) => return Err(bad("hdr image is empty")),
+        _ => return Err(bad("hdr resolution is not a number")),
+    };
+    // a scanline takes at least 4 bytes, plus 8 for each full 65535 pixels
+    // repeated after the first one, checked before anything is allocated
+    let row_bytes = 4 + 8 * ((width - 1) / 65535);
+    if !matches!(height.checked_mul(row_bytes), Some(n) if n <= bytes.len() - at) {
+        return Err(bad("pixel data ends early"));
+    }
+    if width.checked_mul(height).and_then(|n| n.checked_mul(3)).is_none() {
+        return Err(bad("hdr image is too large"));
+    }
+
+    let mut next = || -> ImageResult<u8> {
+        let b = *bytes.get(at).ok_or_else(|| bad("pixel data ends early"))?;
+        at += 1;
+        Ok(b)
+    };
+
+    let mut image = Image::new(width, height);
+    let mut scanline = vec![0u8; width * 4];
+    for row in 0..height {
+        let first = [next()?, next()?, next()?, next()?];
+        let rle = (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+        if rle {
+            if ((first[2] as usize) << 8 | first[3] as usize) != width {
+                return Err(bad("hdr scanline has the wrong width"));
+            }
+            // the four components one after the other, in runs and dumps
+            for component in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = next()? as usize;
+                    let (count, run) = if count > 128 { (count - 128, true) } else { (count, false) };
+                    if count == 0 || x + count > width {
+                        return Err(bad("hdr run overflows the scanline"));
+                    }
+                    let value = if run { next()? } else { 0 };
+                    for _ in 0..count {
+                        scanline[4 * x + component] = if run { value } else { next()? };
+                        x += 1;
+                    }
+                }
+            }
+        } else {
+            // flat pixels, where 1, 1, 1, n repeats the previous pixel
+            let mut x = 0;
+            let mut pixel = first;
+            let mut shift = 0;
+            loop {
+                if pixel[..3] == [1, 1, 1] && x > 0 {
+                    // runs of more than two repeats are not supported
+                    if shift > 8 {
+                        return Err(bad("hdr repeat is too long"));
+                    }
+                    let count = (pixel[3] as usize) << shift;
+                    if x + count > width {
+                        return Err(bad("hdr run overflows the scanline"));
+                    }
+                    for _ in 0..count {
+                        scanline.copy_within(4 * (x - 1)..4 * x, 4 * x);
+                        x += 1;
+                    }
+                    shift += 8;
+                } else {
+                    scanline[4 * x..4 * x + 4].copy_from_slice(&pixel);
+                    x += 1;
+                    shift = 0;
+                }
+                if x == width {
+                    break;
+                }
+                pixel = [next()?, next()?, next()?, next()?];
+            }
+        }
+
+        let y = if flipped { height - 1 - row } else { row };
+        for (x, rgbe) in scanline.chunks_exact(4).enumerate() {
+            image.set_pixel(x, y, rgbe_to_rgb(rgbe));
+        }
+    }
+    Ok(image)
+}
+
+/// Portable float maps, color (`PF`) or gray (`Pf`)
+pub fn decode_pfm(bytes: &[u8]) -> ImageResult<Image> {
+    let bad = |s: &str| ImageError::Decode(s.to_owned());
+
+    // four whitespace separated tokens, then a single whitespace character
+    let mut at = 0;
+    let mut tokens = Vec::with_capacity(4);
+    while tokens.len() < 4 {
+        while matches!(bytes.get(at), Some(b) if b.is_ascii_whitespace()) {
+            at += 1;
+        }
+        let start = at;
+        while matches!(bytes.get(at), Some(b) if !b.is_ascii_whitespace()) {
+            at += 1;
+        }
+        if start == at {
+            return Err(bad("pfm header ends early"));
+        }
+        tokens.push(std::str::from_utf8(&bytes[start..at]).map_err(|_| bad("pfm header is not text"))?);
+    }
+    at += 1;
+
+    let channels = match tokens[0] {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(bad("not a pfm image")),
+    };
+    let (width, height, scale) = match (tokens[1].parse::<usize>(), tokens[2].parse::<usize>(), tokens[3].parse::<f32>()) {
+        (Ok(w), Ok(h), Ok(s)) if w > 0 && h > 0 && s != 0.0 => (w, h, s),
+        _ => return Err(bad("bad pfm size or scale")),
+    };
+
+    let size = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(channels * 4))
+        .ok_or_else(|| bad("pfm image is too large"))?;
+    let data = bytes.get(at..).and_then(|rest| rest.get(..size)).ok_or_else(|| bad("pixel data ends early"))?;
+    let floats: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            // a negative scale means little endian
+            if scale < 0.0 { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }
+        })
+        .collect();
+
+    // rows are stored from the bottom
+    let mut image = Image::new(width, height);
+    for (i, pixel) in floats.chunks_exact(channels).enumerate() {
+        let rgb = if channels == 1 { [pixel[0]; 3] } else { [pixel[0], pixel[1], pixel[2]] };
+        image.set_pixel(i % width, height - 1 - i / width, rgb);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_roundtrip_and_sampling() {
+        let path = std::env::temp_dir().join("hugengine_image_test.png");
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(io::BufWriter::new(file), 2, 1);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[255, 0, 0, 0, 0, 255]).unwrap();
+        }
+        let image = load(&path).expect("png is readable");
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixel(1, 0), [0.0, 0.0, 1.0]);
+        // halfway between the two pixels, and across the wrapped edge
+        assert_eq!(image.sample(0.5, 0.5), [0.5, 0.0, 0.5]);
+        assert_eq!(image.sample(0.0, 0.0), [0.5, 0.0, 0.5]);
+        assert!(matches!(load("missing.png"), Err(ImageError::Io(_))));
+        assert!(matches!(load("image.bmp"), Err(ImageError::Unsupported(_))));
+    }
+
+    #[test]
+    fn decode_hdr_scanlines() {
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n";
+        let mut bytes = header.to_vec();
+        // first row run length encoded: red 1.0 everywhere, green 0.5 in the
+        // first half, blue 0, exponent 129
+        bytes.extend([2, 2, 0, 8]);
+        bytes.extend([128 + 8, 128]);
+        bytes.extend([4, 64, 64, 64, 64, 128 + 4, 0]);
+        bytes.extend([128 + 8, 0]);
+        bytes.extend([128 + 8, 129]);
+        // second row flat: one pixel of 2.0, repeated with the old encoding,
+        // then zeros
+        bytes.extend([128, 128, 128, 130]);
+        bytes.extend([1, 1, 1, 3]);
+        bytes.extend([0, 0, 0, 0].repeat(4));
+
+        let image = decode_hdr(&bytes).expect("hdr is readable");
+        assert_eq!((image.width, image.height), (8, 2));
+        assert_eq!(image.pixel(0, 0), [1.0, 0.5, 0.0]);
+        assert_eq!(image.pixel(7, 0), [1.0, 0.0, 0.0]);
+        assert_eq!(image.pixel(3, 1), [2.0, 2.0, 2.0]);
+        assert_eq!(image.pixel(4, 1), [0.0, 0.0, 0.0]);
+
+        bytes[header.len() + 3] = 9;
+        assert!(matches!(decode_hdr(&bytes), Err(ImageError::Decode(_))));
+
+        // malformed sizes are errors, before anything is allocated
+        let empty = b"#?RADIANCE\n\n-Y 1 +X 0\n\x01\x01\x01\x01";
+        assert!(matches!(decode_hdr(empty), Err(ImageError::Decode(_))));
+        let huge = b"#?RADIANCE\n\n-Y 1000000000 +X 1000000000\n\x80\x80\x80\x80";
+        assert!(matches!(decode_hdr(huge), Err(ImageError::Decode(_))));
+        assert_eq!(Image::new(0, 0).sample(0.5, 0.5), [0.0; 3]);
+    }
+
+    #[test]
+    fn decode_pfm_rows_from_the_bottom() {
+        let mut bytes = b"PF\n1 2\n-1.0\n".to_vec();
+        for x in [0.0f32, 0.5, 1.0, 4.0, 8.0, 16.0] {
+            bytes.extend(x.to_le_bytes());
+        }
+        let image = decode_pfm(&bytes).expect("pfm is readable");
+        assert_eq!(image.pixel(0, 0), [4.0, 8.0, 16.0]);
+        assert_eq!(image.pixel(0, 1), [0.0, 0.5, 1.0]);
+
+        let mut bytes = b"Pf 1 1 1.0 ".to_vec();
+        bytes.extend(3.0f32.to_be_bytes());
+        assert_eq!(decode_pfm(&bytes).unwrap().pixel(0, 0), [3.0; 3]);
+        assert!(decode_pfm(&bytes[..12]).is_err());
+
+        let huge = b"PF 4294967296 4294967296 -1.0 ";
+        assert!(matches!(decode_pfm(huge), Err(ImageError::Decode(_))));
+        assert!(matches!(decode_pfm(b"PF 0 1 -1.0 "), Err(ImageError::Decode(_))));
+    }
+}
@@ -2,14 +2,25 @@ pub fn foo() -> String {
     String::from("hello")
 }
 
-trait NumberOfFields {
-    fn nr_fields(&self) -> usize;
-}
+// lets the derive macros refer to this crate by name from within
+extern crate self as hugengine;
 
+pub mod gpu;
 pub mod color;
 pub mod material;
 pub mod geospace;
 pub mod models;
+pub mod ray;
+pub mod bvh;
+pub mod lights;
+pub mod camera;
+pub mod scene;
+pub mod image;
+pub mod environment;
+pub mod ibl;
+pub mod sky;
+pub mod editor;
+pub mod history;
 
 // pub mod shader_preprocessor;
 
@@ -22,9 +33,7 @@ mod tests {
         assert_eq!(result, 4);
     }
 
-    // this test should be ran a few times, there is a hash algorithm in there
-    // that randomizes the results.
-    // #[test]
+    #[test]
     fn creating_ss_buffers() {
         use crate::models;
         use crate::models::{ModelManager, ModelType, ModelProperty};
@@ -42,7 +51,7 @@ mod tests {
             material: define_material!(1.0),
         });
 
-        assert_eq!(white_ball, 1);
+        assert!(modelmanager.contains(white_ball));
 
         // a red, opaque ball above the sphere
         let red_box = modelmanager.add_new(ModelProperty {
@@ -52,35 +61,38 @@ mod tests {
             material: define_material!(0.0),
         });
 
-        assert_eq!(red_box, 2);
+        assert!(modelmanager.contains(red_box));
 
-        let (ids, props) = modelmanager.create_ss_buffers();
+        let packed = modelmanager.create_ss_buffers();
 
-        // this is just the order that the iterator traverses the hashmap and should
-        // not be seen as "expected behaviour". What is important is that the correct
-        // info ends up where it is expected "some of the time"
-        assert_eq!(ids, vec![
+        // models are packed in the order they were added, each in the array
+        // of its type
+        assert_eq!(packed.index, vec![
+            models::SPHERE_ID,
+            0,
             models::BOX_ID,
             0,
-            models::SPHERE_ID, 
-            14,
         ]);
 
-        assert_eq!(props, vec![
-            // red box
-            0.0,1.0,0.0,    // position (positive y)
-            1.0,            // scale (1x)
-            0.0,0.0,0.0,    // rotation (none)
-            1.0,0.0,0.0,    // color (red)
-            0.0,            // no reflectance
-            1.0,1.0,1.0,    // sides 1x1x1
-            // sphere
-            0.0,0.0,0.0,    // position (origin)
-            1.0,            // scale (1x)
-            0.0,0.0,0.0,    // rotation (none)
-            1.0,1.0,1.0,    // color (white)
-            1.0,            // total reflectance
-                            // no extra fields
-        ]);
+        let identity = [
+            [1.0,0.0,0.0,0.0],
+            [0.0,1.0,0.0,0.0],
+            [0.0,0.0,1.0,0.0],
+        ];
+
+        assert_eq!(packed.spheres.len(), 1);
+        let sphere = packed.spheres[0].model;
+        assert_eq!(sphere.rotation.0, identity);     // rotation (none)
+        assert_eq!(sphere.position, [0.0,0.0,0.0]);  // position (origin)
+        assert_eq!(sphere.scale, 1.0);               // scale (1x)
+        assert_eq!(sphere.color, [1.0,1.0,1.0]);     // color (white)
+        assert_eq!(sphere.reflectance, 1.0);         // total reflectance
+
+        assert_eq!(packed.boxes.len(), 1);
+        let cuboid = packed.boxes[0];
+        assert_eq!(cuboid.model.position, [0.0,1.0,0.0]);   // position (positive y)
+        assert_eq!(cuboid.model.color, [1.0,0.0,0.0]);      // color (red)
+        assert_eq!(cuboid.model.reflectance, 0.0);          // no reflectance
+        assert_eq!(cuboid.dims, [1.0,1.0,1.0]);             // sides 1x1x1
     }
 }
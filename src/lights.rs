@@ -0,0 +1,225 @@
+use cgmath::{InnerSpace, Vector3};
+use crate::color::Color;
+use crate::gpu::{self, GpuLayout};
+use crate::col;
+
+pub const POINT_LIGHT_ID: i32 = 0;
+pub const DIRECTIONAL_LIGHT_ID: i32 = 1;
+pub const SPOT_LIGHT_ID: i32 = 2;
+pub const SPHERE_LIGHT_ID: i32 = 3;
+pub const RECT_LIGHT_ID: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LightType {
+    Point,
+    /// infinitely far away, only the direction matters
+    Directional,
+    /// a cone along the direction, with full intensity within the `inner`
+    /// angle fading out to the `outer` angle (rad, from the axis)
+    Spot { inner: f32, outer: f32 },
+    /// sphere shaped area light, gives soft shadows
+    Sphere { radius: f32 },
+    /// rectangle shaped area light, emitting along the direction
+    Rect { width: f32, height: f32 },
+}
+
+impl LightType {
+    pub fn get_id(&self) -> i32 {
+        match self {
+            LightType::Point => POINT_LIGHT_ID,
+            LightType::Directional => DIRECTIONAL_LIGHT_ID,
+            LightType::Spot { .. } => SPOT_LIGHT_ID,
+            LightType::Sphere { .. } => SPHERE_LIGHT_ID,
+            LightType::Rect { .. } => RECT_LIGHT_ID,
+        }
+    }
+}
+
+impl TryFrom<&str> for LightType {
+    type Error = ();
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name.to_lowercase().as_str() {
+            "point" => Ok(Self::Point),
+            "directional" => Ok(Self::Directional),
+            "spot" => Ok(Self::Spot { inner: 0.3, outer: 0.5 }),
+            "sphere" => Ok(Self::Sphere { radius: 1.0 }),
+            "rect" => Ok(Self::Rect { width: 1.0, height: 1.0 }),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Light {
+    pub t: LightType,
+    /// unused by directional lights
+    pub position: Vector3<f32>,
+    /// the direction the light travels, unused by point and sphere lights
+    pub direction: Vector3<f32>,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl Light {
+    pub fn new(t: LightType) -> Self {
+        Light {
+            t,
+            position: Vector3::new(0.0, 0.0, 0.0),
+            direction: Vector3::new(0.0, -1.0, 0.0),
+            color: col!(white),
+            intensity: 1.0,
+        }
+    }
+}
+
+/// A light as the shader reads it, `struct Light` in the shader
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+#[gpu(glsl = "Light")]
+pub struct LightData {
+    pub position: [f32; 3],
+    /// one of the `*_LIGHT_ID`
+    pub kind: i32,
+    /// normalized
+    pub direction: [f32; 3],
+    pub intensity: f32,
+    pub color: [f32; 3],
+    /// cosines of the spot angles
+    pub cos_inner: f32,
+    pub cos_outer: f32,
+    pub radius: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl From<&Light> for LightData {
+    fn from(light: &Light) -> Self {
+        let direction = light.direction.normalize();
+        let mut data = LightData {
+            position: light.position.into(),
+            kind: light.t.get_id(),
+            direction: direction.into(),
+            intensity: light.intensity,
+            color: [light.color.r, light.color.g, light.color.b],
+            cos_inner: 1.0,
+            cos_outer: 1.0,
+            radius: 0.0,
+            width: 0.0,
+            height: 0.0,
+        };
+        match light.t {
+            LightType::Point | LightType::Directional => {}
+            LightType::Spot { inner, outer } => {
+                data.cos_inner = inner.cos();
+                data.cos_outer = outer.cos();
+            }
+            LightType::Sphere { radius } => data.radius = radius,
+            LightType::Rect { width, height } => {
+                data.width = width;
+                data.height = height;
+            }
+        }
+        data
+    }
+}
+
+/// Handle used to reference a registred light
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LightId(usize);
+
+/// Maintains the lights of the scene, creates the data of their shader
+/// storage buffer
+#[derive(Debug, Default)]
+pub struct LightManager {
+    lights: Vec<Light>,
+    modified: bool,
+}
+
+impl LightManager {
+    pub fn new() -> Self {
+        LightManager::default()
+    }
+
+    pub fn add_new(&mut self, light: Light) -> LightId {
+        self.lights.push(light);
+        self.modified = true;
+        LightId(self.lights.len() - 1)
+    }
+
+    pub fn get(&self, id: LightId) -> Option<&Light> {
+        self.lights.get(id.0)
+    }
+
+    /// Mutable access, the light is considered changed
+    pub fn get_mut(&mut self, id: LightId) -> Option<&mut Light> {
+        self.modified = true;
+        self.lights.get_mut(id.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (LightId, &Light)> {
+        self.lights.iter().enumerate().map(|(i, light)| (LightId(i), light))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Whether anything changed since the buffer was created
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// Creates the content of the light buffer, in insertion order
+    pub fn create_ss_buffer(&mut self) -> Vec<LightData> {
+        self.modified = false;
+        self.lights.iter().map(LightData::from).collect()
+    }
+}
+
+/// Glsl declaration of `struct Light` and the light type ids
+pub fn glsl_header() -> String {
+    let mut glsl = String::new();
+    for (name, value) in [
+        ("POINT_LIGHT_ID", POINT_LIGHT_ID),
+        ("DIRECTIONAL_LIGHT_ID", DIRECTIONAL_LIGHT_ID),
+        ("SPOT_LIGHT_ID", SPOT_LIGHT_ID),
+        ("SPHERE_LIGHT_ID", SPHERE_LIGHT_ID),
+        ("RECT_LIGHT_ID", RECT_LIGHT_ID),
+    ] {
+        glsl.push_str(&format!("#define {} {}\n", name, value));
+    }
+    glsl.push_str(&gpu::glsl_struct::<LightData>());
+    glsl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn light_data_matches_std430() {
+        assert_eq!(std::mem::size_of::<LightData>(), LightData::SIZE);
+        assert_eq!(LightData::SIZE, 64);
+
+        let mut lm = LightManager::new();
+        let spot = lm.add_new(Light {
+            direction: Vector3::new(0.0, -2.0, 0.0),
+            ..Light::new(LightType::Spot { inner: 0.0, outer: std::f32::consts::FRAC_PI_2 })
+        });
+        lm.add_new(Light::new(LightType::Point));
+        assert_eq!(lm.get(spot).unwrap().t.get_id(), SPOT_LIGHT_ID);
+
+        let data = lm.create_ss_buffer();
+        assert!(!lm.is_modified());
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0].direction, [0.0, -1.0, 0.0]);
+        assert_eq!(data[0].cos_inner, 1.0);
+        assert!(data[0].cos_outer.abs() < 1e-6);
+        assert!(glsl_header().contains("struct Light {\n    vec3 position;\n    int kind;\n"));
+    }
+}
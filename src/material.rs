@@ -1,29 +1,21 @@
-use crate::NumberOfFields;
+use crate::gpu::GpuLayout;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, GpuLayout)]
 pub struct Material {
-    pub reflectance: f32
-}
-
-impl NumberOfFields for Material {
-    fn nr_fields(&self) -> usize {
-        return 1;
-    }
-}
-
-impl Material {
-    pub fn iter(&self) -> std::vec::IntoIter<f32> {
-        vec!(
-            self.reflectance,
-        ).into_iter()
-    }
+    pub reflectance: f32,
+    /// 0 for a mirror, up to 1 for a blurry reflection
+    pub roughness: f32,
 }
 
 #[macro_export]
 macro_rules! define_material {
     ($ref:expr) => {
+        define_material!($ref, 0.0)
+    };
+    ($ref:expr, $rough:expr) => {
         Material {
-            reflectance: $ref
+            reflectance: $ref,
+            roughness: $rough,
         }
-    }
+    };
 }
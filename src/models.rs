@@ -1,27 +1,56 @@
-use std::collections::HashMap;
-use std::iter::IntoIterator;
+use std::collections::BTreeSet;
 use std::ops::Index;
 use crate::geospace;
 use crate::color;
 use crate::material;
-use crate::NumberOfFields;
+use crate::gpu::{GpuField, GpuLayout};
+use crate::geospace::Transform;
+use crate::bvh::{Aabb, Bvh};
+use crate::ray::{self, Hit, Ray};
+use cgmath::{InnerSpace, Matrix, Vector3};
+use sdf::SdfNode;
+use std430::{BoxData, ModelData, PlaneData, Record, SdfData, SphereData};
+
+pub use std430::PackedModels;
 
 
 pub mod parser;
+pub mod sdf;
+pub mod generate;
+pub mod layout;
+pub mod std430;
 
 
 pub const SPHERE_ID: i32 = 0;
 pub const BOX_ID: i32 = 1;
 pub const PLANE_ID: i32 = 2;
-/// GUID used to reference a registred object
-pub type ModelId = u32;
+pub const SDF_ID: i32 = 3;
+/// Type of an empty slot, left behind by a removed model
+pub const NONE_ID: i32 = -1;
+
+/// Handle used to reference a registred object. Handles of removed objects
+/// stay invalid even when their slot is reused, thanks to the generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModelId {
+    index: u32,
+    generation: u32,
+}
+
+impl ModelId {
+    /// Slot of the model, which is also its position in the index buffer and
+    /// the object id reported by the shader
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
 
 /// Contains data about the object size, variable
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ModelType {
     Sphere, // radii of 1.0
     Box(f32, f32, f32), // dimensions
     Plane,
+    Sdf(SdfNode), // signed distance field of a shape
 }
 
 impl ModelType {
@@ -30,15 +59,18 @@ impl ModelType {
             ModelType::Sphere => SPHERE_ID,
             ModelType::Box(_,_,_) => BOX_ID,
             ModelType::Plane => PLANE_ID,
+            ModelType::Sdf(_) => SDF_ID,
         }
     }
 
-    pub fn iter(&self) -> std::vec::IntoIter<f32> {
+    /// The name used in scene files
+    pub fn name(&self) -> &'static str {
         match self {
-            ModelType::Sphere => vec!(),
-            ModelType::Box(w,h,d) => vec!(*w,*h,*d),
-            ModelType::Plane => vec!(),
-        }.into_iter()
+            ModelType::Sphere => "sphere",
+            ModelType::Box(_,_,_) => "box",
+            ModelType::Plane => "plane",
+            ModelType::Sdf(_) => "sdf",
+        }
     }
 }
 
@@ -50,91 +82,575 @@ impl TryFrom<&str> for ModelType {
              "sphere" => Ok(Self::Sphere),
              "plane" => Ok(Self::Plane),
              "box" => Ok(Self::Box(1.0,1.0,1.0)),
+             "sdf" => Ok(Self::Sdf(SdfNode::Sphere { pos: Vector3::new(0.0, 0.0, 0.0), radius: 1.0 })),
              _ => Err(()),
         }
     }
 }
 
-impl NumberOfFields for ModelType {
-    /// Returns number of extra items allocated
-    fn nr_fields(&self) -> usize {
+/// The fields that depend on the type of a model, box dimensions or the
+/// length of the sdf program followed by the program
+impl GpuLayout for ModelType {
+    const SIZE: usize = 0;
+    const ALIGN: usize = 4;
+    // runtime sized, only valid as the last member of a buffer block
+    const GLSL: &'static str = "float[]";
+    const FIELDS: &'static [GpuField] = &[];
+
+    fn tail_len(&self) -> usize {
         match self {
             ModelType::Sphere => 0,
             ModelType::Box(_,_,_) => 3,
             ModelType::Plane => 0,
+            ModelType::Sdf(shape) => 1 + shape.program_len(),
+        }
+    }
+
+    fn write_to(&self, out: &mut [f32]) {
+        match self {
+            ModelType::Sphere | ModelType::Plane => {}
+            ModelType::Box(w,h,d) => out[..3].copy_from_slice(&[*w, *h, *d]),
+            ModelType::Sdf(shape) => {
+                out[0] = shape.program_len() as f32;
+                shape.write_program(&mut out[1..]);
+            }
         }
     }
 }
 
-/// The properties fully describing each object
-#[derive(Debug, PartialEq)]
+/// The properties fully describing each object, packed in this order
+#[derive(Debug, Clone, PartialEq, GpuLayout)]
 pub struct ModelProperty {
-    pub t: ModelType,
     pub tf: geospace::Transform,
     pub color: color::Color,
     pub material: material::Material,
+    /// last, as its size varies
+    pub t: ModelType,
+}
+
+impl ModelProperty {
+    /// World space bounds, `None` for models of infinite extent
+    pub fn bounds(&self) -> Option<Aabb> {
+        let pos = self.tf.position();
+        match self.t {
+            ModelType::Sphere => Some(Aabb::around(pos, self.tf.scale.abs())),
+            // conservative, holds for any rotation and anchoring of the box
+            ModelType::Box(w, h, d) => {
+                let diagonal = Vector3::new(w, h, d).magnitude();
+                Some(Aabb::around(pos, diagonal * self.tf.scale.abs()))
+            }
+            ModelType::Plane => None,
+            ModelType::Sdf(ref shape) => Some(shape.bounds().transformed(&self.tf)),
+        }
+    }
+
+    /// Intersects the model, following the `draw_*` functions in the shader
+    pub fn intersect(&self, r: &Ray, t_max: f32) -> Option<Hit> {
+        let pos = self.tf.position();
+        match self.t {
+            ModelType::Sphere => {
+                let t = ray::intersect_sphere(r, pos, self.tf.scale, t_max)?;
+                Some(Hit {
+                    t,
+                    normal: (r.at(t) - pos).normalize(),
+                })
+            }
+            ModelType::Plane => {
+                let normal = (self.tf.rotation() * Vector3::unit_y()).normalize();
+                let t = ray::intersect_plane(r, pos, normal, t_max)?;
+                Some(Hit { t, normal })
+            }
+            ModelType::Sdf(ref shape) => {
+                let program = shape.compile();
+                let to_local = self.tf.rotation().transpose();
+                let scale = self.tf.scale;
+                let distance = |p: Vector3<f32>| sdf::evaluate(&program, to_local * (p - pos) / scale) * scale;
+
+                let t = sdf::march(r, t_max, distance)?;
+                Some(Hit {
+                    t,
+                    normal: sdf::normal(r.at(t), distance),
+                })
+            }
+            // boxes are not drawn by the shader
+            ModelType::Box(_,_,_) => None,
+        }
+    }
+}
+
+/// Per-instance data, laid out to match `struct Instance` in the shader
+/// (std430, 32 bytes). `slot` is the position of the prototype in the model
+/// index buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceData {
+    pub position: [f32; 3],
+    pub scale: f32,
+    pub rotation: [f32; 3], // head, pitch, roll
+    pub slot: i32,
 }
 
-impl NumberOfFields for ModelProperty {
-    fn nr_fields(&self) -> usize {
-        self.t.nr_fields() + self.tf.nr_fields() + 
-            self.color.nr_fields() + self.material.nr_fields()
+/// Something the shader draws, either a model or an instance of a prototype
+#[derive(Debug, Clone, Copy)]
+pub enum Primitive<'a> {
+    Model(&'a ModelProperty),
+    /// the instance transform is applied on top of the prototype's transform
+    Instance(&'a ModelProperty, &'a Transform),
+}
+
+impl<'a> Primitive<'a> {
+    pub fn bounds(&self) -> Option<Aabb> {
+        match self {
+            Primitive::Model(model) => model.bounds(),
+            Primitive::Instance(prototype, tf) => prototype.bounds().map(|b| b.transformed(tf)),
+        }
+    }
+
+    /// Same as `intersect_primitive` in the shader
+    pub fn intersect(&self, r: &Ray, t_max: f32) -> Option<Hit> {
+        match self {
+            Primitive::Model(model) => model.intersect(r, t_max),
+            Primitive::Instance(prototype, tf) => {
+                let rot = tf.rotation();
+                let to_local = rot.transpose();
+                let local = Ray::new(to_local * (r.origin - tf.position()) / tf.scale, to_local * r.dir);
+                let hit = prototype.intersect(&local, t_max / tf.scale)?;
+                Some(Hit {
+                    t: hit.t * tf.scale,
+                    normal: rot * hit.normal,
+                })
+            }
+        }
     }
 }
 
+/// A model and the bookkeeping of its slot in the registry
+#[derive(Debug)]
+struct Slot {
+    generation: u32,
+    model: Option<ModelProperty>,
+    /// only prototypes have instances
+    instances: Option<Vec<Transform>>,
+}
+
+/// Where a slot ended up the last time the buffers were created
+#[derive(Debug, Clone, Copy)]
+struct PackedRecord {
+    type_id: i32,
+    /// into the array of the type
+    index: i32,
+    /// range of the sdf program
+    program: usize,
+    program_len: usize,
+}
+
+/// A slot to overwrite, `index` goes at `2 * slot` of the index buffer and
+/// the record at `index[1]` of the buffer of its type. An sdf program goes
+/// at `program` of the program buffer.
+#[derive(Debug, PartialEq)]
+pub struct BufferRange {
+    pub slot: usize,
+    pub index: [i32; 2],
+    pub record: Record,
+}
+
+/// Changes to the model buffers since they were created
+#[derive(Debug, PartialEq)]
+pub enum BufferChanges {
+    None,
+    /// the layout changed, the buffers have to be created again
+    Rebuild,
+    /// every model kept its size, only these ranges have to be uploaded
+    Partial(Vec<BufferRange>),
+}
+
 // TODO: Maintain some upper limit on objects
 /// Maintains a registry of each object in the scene.
 /// Creates data to fill a shader storage buffer
+///
+/// Models are packed by slot. Slots are handed out in insertion order,
+/// except that a new model takes the lowest slot left free by a removed one.
+/// The same sequence of calls therefore always gives the same buffers.
+#[derive(Debug)]
 pub struct ModelManager {
-    /// Maintaining data
-    registry: HashMap<ModelId, ModelProperty>,
-    counter: ModelId,
-    modif: bool,
+    /// Maintaining data, slots of removed models are reused
+    registry: Vec<Slot>,
+    free: BTreeSet<u32>,
+    /// slots that changed since the buffers were created
+    dirty: BTreeSet<u32>,
+    /// layout of the last created buffers, indexed by slot
+    layout: Vec<PackedRecord>,
+    /// glsl snippets of custom sdf primitives, indexed by their id
+    sdf_snippets: Vec<String>,
 }
 
 impl ModelManager {
     pub fn new() -> Self {
         ModelManager {
-            registry: HashMap::new(),
-            counter: 0,
-            modif: true,
+            registry: Vec::new(),
+            free: BTreeSet::new(),
+            dirty: BTreeSet::new(),
+            layout: Vec::new(),
+            sdf_snippets: Vec::new(),
+        }
+    }
+
+    fn register_snippets(&mut self, model: &mut ModelProperty) {
+        if let ModelType::Sdf(ref mut shape) = model.t {
+            let snippets = &mut self.sdf_snippets;
+            shape.for_each_custom(&mut |code, id| {
+                *id = match snippets.iter().position(|s| s == code) {
+                    Some(i) => i as i32,
+                    None => {
+                        snippets.push(code.to_owned());
+                        snippets.len() as i32 - 1
+                    }
+                };
+            });
+        }
+    }
+
+    fn slot(&self, id: ModelId) -> Option<&Slot> {
+        self.registry
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation && slot.model.is_some())
+    }
+
+    fn slot_mut(&mut self, id: ModelId) -> Option<&mut Slot> {
+        self.registry
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation && slot.model.is_some())
+    }
+
+    pub fn add_new(&mut self, mut model: ModelProperty) -> ModelId {
+        self.register_snippets(&mut model);
+        let index = match self.free.iter().next().copied() {
+            Some(index) => {
+                self.free.remove(&index);
+                self.registry[index as usize].model = Some(model);
+                index
+            }
+            None => {
+                self.registry.push(Slot {
+                    generation: 0,
+                    model: Some(model),
+                    instances: None,
+                });
+                self.registry.len() as u32 - 1
+            }
+        };
+        self.dirty.insert(index);
+        ModelId {
+            index,
+            generation: self.registry[index as usize].generation,
         }
     }
 
-    
-    pub fn add_new(&mut self, model: ModelProperty) -> ModelId {
-        self.modif = true;
-        self.registry.insert(self.counter, model);
-        self.counter += 1;
-        self.counter
+    /// Adds a prototype that is only drawn at the given transforms. The
+    /// prototype is packed once, the instances go in a buffer of their own.
+    pub fn add_instances(&mut self, prototype: ModelProperty, transforms: Vec<Transform>) -> ModelId {
+        let id = self.add_new(prototype);
+        self.registry[id.index as usize].instances = Some(transforms);
+        id
     }
-    
-    /// Creates shader storage buffers
-    pub fn create_ss_buffers(&mut self) -> (Vec<i32>,Vec<f32>) {
-        self.modif = false;
-        let mut index = 0;
-        let mut keys = Vec::new();
-        let mut prop = Vec::new();
-        for (_, model) in self.registry.iter() {
-            keys.push(model.t.get_id());
-            keys.push(index);
 
-            model.tf.iter().for_each(|i| prop.push(i));
-            model.color.iter().for_each(|i| prop.push(i));
-            model.material.iter().for_each(|i| prop.push(i));
-            model.t.iter().for_each(|i| prop.push(i));
+    /// Removes the model, the handle (and any copy of it) becomes invalid
+    pub fn remove(&mut self, id: ModelId) -> Option<ModelProperty> {
+        let slot = self.slot_mut(id)?;
+        let model = slot.model.take();
+        slot.instances = None;
+        slot.generation += 1;
+        self.free.insert(id.index);
+        self.dirty.insert(id.index);
+        model
+    }
 
-            index += model.tf.nr_fields() as i32;
-            index += model.color.nr_fields() as i32;
-            index += model.material.nr_fields() as i32;
-            index += model.t.nr_fields() as i32;
+    /// Puts a removed model back under its old handle, which becomes valid
+    /// again. Fails, giving the model back, if the slot was reused since.
+    pub fn restore(&mut self, id: ModelId, mut model: ModelProperty, instances: Option<Vec<Transform>>) -> Result<(), ModelProperty> {
+        let reusable = self.free.contains(&id.index)
+            && self.registry.get(id.index as usize).map(|slot| slot.generation) == Some(id.generation + 1);
+        if !reusable {
+            return Err(model);
         }
+        self.register_snippets(&mut model);
+        self.free.remove(&id.index);
+        let slot = &mut self.registry[id.index as usize];
+        slot.generation = id.generation;
+        slot.model = Some(model);
+        slot.instances = instances;
+        self.dirty.insert(id.index);
+        Ok(())
+    }
+
+    pub fn contains(&self, id: ModelId) -> bool {
+        self.slot(id).is_some()
+    }
+
+    pub fn get(&self, id: ModelId) -> Option<&ModelProperty> {
+        self.slot(id).and_then(|slot| slot.model.as_ref())
+    }
+
+    /// Mutable access, the model is considered changed. Custom sdf snippets
+    /// are only registered by `add_new` and `update`.
+    pub fn get_mut(&mut self, id: ModelId) -> Option<&mut ModelProperty> {
+        self.slot(id)?;
+        self.dirty.insert(id.index);
+        self.slot_mut(id).and_then(|slot| slot.model.as_mut())
+    }
+
+    /// Replaces the model, returning the previous one. Nothing happens if the
+    /// handle is invalid.
+    pub fn update(&mut self, id: ModelId, mut model: ModelProperty) -> Option<ModelProperty> {
+        self.slot(id)?;
+        self.register_snippets(&mut model);
+        self.dirty.insert(id.index);
+        self.slot_mut(id).and_then(|slot| slot.model.replace(model))
+    }
+
+    /// Transforms of the instances of a prototype, they are considered changed
+    pub fn instances_mut(&mut self, id: ModelId) -> Option<&mut Vec<Transform>> {
+        self.slot(id)?;
+        self.dirty.insert(id.index);
+        self.slot_mut(id).and_then(|slot| slot.instances.as_mut())
+    }
+
+    pub fn instances(&self, id: ModelId) -> Option<&[Transform]> {
+        self.slot(id).and_then(|slot| slot.instances.as_deref())
+    }
 
-        (keys, prop)
+    /// Whether anything changed since the buffers were created
+    pub fn is_modified(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Creates the content of the model buffers, one index-pair per slot in
+    /// slot order. Removed models are kept as holes of type `NONE_ID` so that
+    /// every other model keeps its place.
+    pub fn create_ss_buffers(&mut self) -> PackedModels {
+        self.dirty.clear();
+        self.layout.clear();
+        let mut packed = PackedModels::default();
+        for slot in self.registry.iter() {
+            let (type_id, index, program_len) = match slot.model {
+                Some(ref model) => {
+                    let program = packed.sdf_programs.len();
+                    match pack(model, program as i32) {
+                        Record::Sphere(data) => (SPHERE_ID, push(&mut packed.spheres, data), 0),
+                        Record::Plane(data) => (PLANE_ID, push(&mut packed.planes, data), 0),
+                        Record::Box(data) => (BOX_ID, push(&mut packed.boxes, data), 0),
+                        Record::Sdf(data, program) => {
+                            packed.sdf_programs.extend_from_slice(&program);
+                            (SDF_ID, push(&mut packed.sdfs, data), program.len())
+                        }
+                        Record::None => unreachable!(),
+                    }
+                }
+                None => (NONE_ID, -1, 0),
+            };
+            packed.index.extend_from_slice(&[type_id, index]);
+            self.layout.push(PackedRecord {
+                type_id,
+                index,
+                program: packed.sdf_programs.len() - program_len,
+                program_len,
+            });
+        }
+
+        packed
+    }
+
+    /// Takes the changes since the buffers were last created. Models that
+    /// kept their type (and sdf models their program length) are rewritten in
+    /// place, anything else needs a rebuild (which is left pending until
+    /// `create_ss_buffers` is called).
+    pub fn take_changes(&mut self) -> BufferChanges {
+        if self.dirty.is_empty() {
+            return BufferChanges::None;
+        }
+
+        let mut ranges = Vec::new();
+        for &slot in self.dirty.iter() {
+            let packed = match self.layout.get(slot as usize) {
+                Some(packed) => *packed,
+                None => return BufferChanges::Rebuild,
+            };
+
+            let (index, record) = match self.registry[slot as usize].model {
+                Some(ref model) => {
+                    let record = pack(model, packed.program as i32);
+                    let program_len = match record {
+                        Record::Sdf(_, ref program) => program.len(),
+                        _ => 0,
+                    };
+                    if model.t.get_id() != packed.type_id || program_len != packed.program_len {
+                        return BufferChanges::Rebuild;
+                    }
+                    ([packed.type_id, packed.index], record)
+                }
+                // the old data stays behind, unreferenced
+                None => ([NONE_ID, -1], Record::None),
+            };
+
+            ranges.push(BufferRange {
+                slot: slot as usize,
+                index,
+                record,
+            });
+        }
+
+        self.dirty.clear();
+        BufferChanges::Partial(ranges)
+    }
+
+    /// Creates the instance buffer, in the same order as `primitives`
+    pub fn create_instance_buffer(&self) -> Vec<InstanceData> {
+        let mut instances = Vec::new();
+        for (slot, entry) in self.registry.iter().enumerate() {
+            for tf in entry.instances.iter().flatten() {
+                instances.push(InstanceData {
+                    position: [tf.x, tf.y, tf.z],
+                    scale: tf.scale,
+                    rotation: [tf.head, tf.pitch, tf.rotate],
+                    slot: slot as i32,
+                });
+            }
+        }
+        instances
+    }
+
+    /// Everything that is drawn, indexed like the primitives of the shader.
+    /// Slots come first, in the order of `create_ss_buffers`, followed by
+    /// every instance. Prototypes are not drawn themselves and are `None`, as
+    /// are the holes of removed models.
+    pub fn primitives(&self) -> Vec<Option<(ModelId, Primitive<'_>)>> {
+        let mut primitives: Vec<Option<(ModelId, Primitive)>> = self.registry
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| match (&slot.model, &slot.instances) {
+                (Some(model), None) => Some((slot_id(index, slot), Primitive::Model(model))),
+                _ => None,
+            })
+            .collect();
+
+        for (index, slot) in self.registry.iter().enumerate() {
+            if let (Some(model), Some(instances)) = (&slot.model, &slot.instances) {
+                for tf in instances {
+                    primitives.push(Some((slot_id(index, slot), Primitive::Instance(model, tf))));
+                }
+            }
+        }
+        primitives
+    }
+
+    /// Builds a hierarchy over the primitives
+    pub fn build_bvh(&self) -> Bvh {
+        let bounds: Vec<(i32, Option<Aabb>)> = self.primitives()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| p.as_ref().map(|(_, p)| (i as i32, p.bounds())))
+            .collect();
+        Bvh::build(&bounds)
+    }
+
+    /// Finds the closest model along the ray, instances report their prototype
+    pub fn intersect(&self, bvh: &Bvh, r: &Ray, t_max: f32) -> Option<(ModelId, Hit)> {
+        let primitives = self.primitives();
+        let (i, hit) = bvh.intersect(r, t_max, |i, r, t_max| {
+            primitives[i].as_ref().and_then(|(_, p)| p.intersect(r, t_max))
+        })?;
+        primitives[i].as_ref().map(|(id, _)| (*id, hit))
+    }
+
+    /// Glsl source of the custom sdf primitives, to be inserted in the shader
+    pub fn sdf_glsl(&self) -> String {
+        sdf::custom_glsl(&self.sdf_snippets)
+    }
+
+    /// Iterates over the models in the same order as they are packed
+    pub fn iter(&self) -> impl Iterator<Item = (ModelId, &ModelProperty)> {
+        self.registry
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.model.as_ref().map(|model| (slot_id(index, slot), model)))
     }
 
     pub fn len(&self) -> usize {
-        self.registry.len()
+        self.registry.len() - self.free.len()
+    }
+
+    /// Takes over the models of `other`, changing as little as possible so
+    /// that the buffers can be updated in place. The n-th model of `other`
+    /// goes in the slot of the n-th model here, which is only considered
+    /// changed if it differs, and its handle stays valid. Returns the number
+    /// of models that were changed, added or removed.
+    pub fn reconcile(&mut self, other: ModelManager) -> usize {
+        let ids: Vec<ModelId> = self.iter().map(|(id, _)| id).collect();
+        let mut incoming = other
+            .registry
+            .into_iter()
+            .filter_map(|slot| slot.model.map(|model| (model, slot.instances)));
+        let mut changed = 0;
+        for id in ids {
+            match incoming.next() {
+                Some((mut model, instances)) => {
+                    self.register_snippets(&mut model);
+                    let slot = &mut self.registry[id.index as usize];
+                    if slot.model.as_ref() != Some(&model) || slot.instances != instances {
+                        slot.model = Some(model);
+                        slot.instances = instances;
+                        self.dirty.insert(id.index);
+                        changed += 1;
+                    }
+                }
+                None => {
+                    self.remove(id);
+                    changed += 1;
+                }
+            }
+        }
+        for (model, instances) in incoming {
+            match instances {
+                Some(transforms) => self.add_instances(model, transforms),
+                None => self.add_new(model),
+            };
+            changed += 1;
+        }
+        changed
+    }
+}
+
+impl Default for ModelManager {
+    fn default() -> Self {
+        ModelManager::new()
+    }
+}
+
+/// The data of a model as it is stored in the buffer of its type, an sdf
+/// program is placed at `program`
+fn pack(model: &ModelProperty, program: i32) -> Record {
+    let data = ModelData::from(model);
+    match model.t {
+        ModelType::Sphere => Record::Sphere(SphereData { model: data }),
+        ModelType::Plane => Record::Plane(PlaneData { model: data }),
+        ModelType::Box(w, h, d) => Record::Box(BoxData { model: data, dims: [w, h, d] }),
+        ModelType::Sdf(ref shape) => {
+            let code = shape.compile();
+            Record::Sdf(SdfData { model: data, program, program_len: code.len() as i32 }, code)
+        }
+    }
+}
+
+/// Appends to `buffer`, returning the index of the item
+fn push<T>(buffer: &mut Vec<T>, item: T) -> i32 {
+    buffer.push(item);
+    buffer.len() as i32 - 1
+}
+
+fn slot_id(index: usize, slot: &Slot) -> ModelId {
+    ModelId {
+        index: index as u32,
+        generation: slot.generation,
     }
 }
 
@@ -142,20 +658,121 @@ impl Index<&ModelId> for ModelManager {
     type Output = ModelProperty;
 
     fn index(&self, index: &ModelId) -> &Self::Output {
-        &self.registry[index] 
+        self.get(*index).expect("model handle is valid")
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct CameraProperty {
-    pub tf: geospace::Transform,
-}
 
-impl CameraProperty {
-    pub fn new() -> Self {
-        CameraProperty {
-            tf: geospace::Transform::new(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Color;
+    use crate::material::Material;
+    use crate::{col, transform, define_material};
+
+    fn ball(x: f32) -> ModelProperty {
+        ModelProperty {
+            t: ModelType::Sphere,
+            tf: transform!(x, 0.0, 0.0),
+            color: col!(white),
+            material: define_material!(1.0),
         }
     }
-}
 
+    #[test]
+    fn handles_are_generational() {
+        let mut mm = ModelManager::new();
+        let a = mm.add_new(ball(0.0));
+        let b = mm.add_new(ball(1.0));
+        assert_ne!(a, b);
+        assert_eq!(mm[&b].tf.x, 1.0);
+
+        assert_eq!(mm.remove(a), Some(ball(0.0)));
+        assert!(!mm.contains(a));
+        assert_eq!(mm.remove(a), None);
+
+        // the slot is reused, but the old handle stays invalid
+        let c = mm.add_new(ball(2.0));
+        assert!(mm.get(a).is_none());
+        assert_eq!(mm.update(a, ball(3.0)), None);
+        assert_eq!(mm.update(c, ball(3.0)), Some(ball(2.0)));
+        assert_eq!(mm.len(), 2);
+    }
+
+    #[test]
+    fn changes_are_partial_when_types_match() {
+        let mut mm = ModelManager::new();
+        let a = mm.add_new(ball(0.0));
+        let b = mm.add_new(ball(1.0));
+        let packed = mm.create_ss_buffers();
+        assert_eq!(packed.index, vec![SPHERE_ID, 0, SPHERE_ID, 1]);
+        assert_eq!(mm.take_changes(), BufferChanges::None);
+
+        mm.get_mut(b).unwrap().tf.y = 5.0;
+        mm.remove(a);
+        match mm.take_changes() {
+            BufferChanges::Partial(ranges) => {
+                assert_eq!(ranges.len(), 2);
+                assert_eq!(ranges[0].index, [NONE_ID, -1]);
+                assert_eq!(ranges[0].record, Record::None);
+                assert_eq!(ranges[1].slot, 1);
+                assert_eq!(ranges[1].index, [SPHERE_ID, 1]);
+                match ranges[1].record {
+                    Record::Sphere(data) => assert_eq!(data.model.position, [1.0, 5.0, 0.0]),
+                    ref other => panic!("expected a sphere, got {:?}", other),
+                }
+            }
+            other => panic!("expected partial changes, got {:?}", other),
+        }
+        assert!(!mm.is_modified());
+
+        // a box goes in another buffer than the sphere it replaces
+        mm.update(b, ModelProperty { t: ModelType::Box(1.0, 1.0, 1.0), ..ball(1.0) });
+        assert_eq!(mm.take_changes(), BufferChanges::Rebuild);
+        mm.create_ss_buffers();
+        mm.add_new(ball(4.0));
+        assert_eq!(mm.take_changes(), BufferChanges::Rebuild);
+    }
+
+    #[test]
+    fn lowest_free_slot_is_reused() {
+        let mut mm = ModelManager::new();
+        let ids: Vec<ModelId> = (0..4).map(|i| mm.add_new(ball(i as f32))).collect();
+        mm.remove(ids[3]);
+        mm.remove(ids[1]);
+
+        // independent of the order of removal
+        assert_eq!(mm.add_new(ball(5.0)).index(), 1);
+        assert_eq!(mm.add_new(ball(6.0)).index(), 3);
+        assert_eq!(mm.add_new(ball(7.0)).index(), 4);
+
+        let xs: Vec<f32> = mm.iter().map(|(_, m)| m.tf.x).collect();
+        assert_eq!(xs, vec![0.0, 5.0, 2.0, 6.0, 7.0]);
+    }
+
+    #[test]
+    fn reconcile_keeps_what_did_not_change() {
+        let mut mm = ModelManager::new();
+        let ids: Vec<ModelId> = (0..3).map(|i| mm.add_new(ball(i as f32))).collect();
+        mm.create_ss_buffers();
+
+        let mut reloaded = ModelManager::new();
+        reloaded.add_new(ball(0.0));
+        reloaded.add_new(ball(4.0));
+        assert_eq!(mm.reconcile(reloaded), 2);
+        assert_eq!(mm[&ids[0]], ball(0.0));
+        assert_eq!(mm[&ids[1]], ball(4.0));
+        assert!(!mm.contains(ids[2]));
+        match mm.take_changes() {
+            BufferChanges::Partial(ranges) => {
+                assert_eq!(ranges.iter().map(|r| r.slot).collect::<Vec<_>>(), vec![1, 2]);
+            }
+            other => panic!("expected partial changes, got {:?}", other),
+        }
+
+        let mut grown = ModelManager::new();
+        (0..3).for_each(|i| { grown.add_new(ball(i as f32)); });
+        assert_eq!(mm.reconcile(grown), 2);
+        assert_eq!(mm.len(), 3);
+    }
+}
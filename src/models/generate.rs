@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use cgmath::{InnerSpace, Vector3};
+use crate::geospace::Transform;
+use crate::transform;
+
+/// Small seeded generator (splitmix64), gives the same sequence on every
+/// platform so that generated scenes are reproducible
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform in [a, b)
+    pub fn range(&mut self, a: f32, b: f32) -> f32 {
+        a + (b - a) * self.next_f32()
+    }
+}
+
+/// Transforms laid out on a regular grid, starting at `origin`
+pub fn grid(count: [u32; 3], spacing: Vector3<f32>, origin: Vector3<f32>) -> Vec<Transform> {
+    let mut transforms = Vec::new();
+    for i in 0..count[0] {
+        for j in 0..count[1] {
+            for k in 0..count[2] {
+                transforms.push(transform!(
+                    origin.x + i as f32 * spacing.x,
+                    origin.y + j as f32 * spacing.y,
+                    origin.z + k as f32 * spacing.z
+                ));
+            }
+        }
+    }
+    transforms
+}
+
+/// Transforms placed uniformly at random inside the box `min..max`, with a
+/// random scale in `scale.0..scale.1` and a random heading
+pub fn scatter(count: u32, seed: u64, min: Vector3<f32>, max: Vector3<f32>, scale: (f32, f32)) -> Vec<Transform> {
+    scatter_apart(count, seed, min, max, scale, 0.0)
+}
+
+/// Like `scatter`, with positions at least `min_distance` apart. Places
+/// fewer than `count` when it keeps failing to find room for one.
+pub fn scatter_apart(
+    count: u32,
+    seed: u64,
+    min: Vector3<f32>,
+    max: Vector3<f32>,
+    scale: (f32, f32),
+    min_distance: f32,
+) -> Vec<Transform> {
+    let mut rng = Rng::new(seed);
+    let mut transforms: Vec<Transform> = Vec::new();
+    // placed positions by cell of side `min_distance`, so that only the
+    // neighbouring cells have to be checked. Cells far out saturate and share
+    // the last index, which only means more positions to compare.
+    let mut cells: HashMap<[i64; 3], Vec<Vector3<f32>>> = HashMap::new();
+    let cell = |p: Vector3<f32>| [p.x, p.y, p.z].map(|c| (c / min_distance).floor() as i64);
+    let mut attempts: u64 = 0;
+    while transforms.len() < count as usize && attempts < 32 * count as u64 {
+        attempts += 1;
+        let x = rng.range(min.x, max.x);
+        let y = rng.range(min.y, max.y);
+        let z = rng.range(min.z, max.z);
+        let s = rng.range(scale.0, scale.1);
+        let head = rng.range(0.0, std::f32::consts::TAU);
+        let position = Vector3::new(x, y, z);
+        if min_distance > 0.0 {
+            let [i, j, k] = cell(position);
+            let crowded = (-1..=1).any(|di| {
+                (-1..=1).any(|dj| {
+                    (-1..=1).any(|dk| match cells.get(&[i.saturating_add(di), j.saturating_add(dj), k.saturating_add(dk)]) {
+                        Some(near) => near.iter().any(|p| (p - position).magnitude() < min_distance),
+                        None => false,
+                    })
+                })
+            });
+            if crowded {
+                continue;
+            }
+            cells.entry([i, j, k]).or_default().push(position);
+        }
+        transforms.push(transform!(x, y, z, s, head, 0.0, 0.0));
+    }
+    transforms
+}
+
+/// Transforms evenly spaced on a horizontal circle around `center`, each
+/// turned by its angle on the circle
+pub fn ring(count: u32, radius: f32, center: Vector3<f32>) -> Vec<Transform> {
+    (0..count)
+        .map(|i| {
+            let angle = std::f32::consts::TAU * i as f32 / count as f32;
+            transform!(
+                center.x + radius * angle.cos(),
+                center.y,
+                center.z + radius * angle.sin(),
+                1.0, angle, 0.0, 0.0
+            )
+        })
+        .collect()
+}
+
+/// Transforms on a spiral going out from `center` to `radius` in `turns`
+/// turns, rising by `height` on the way
+pub fn spiral(count: u32, radius: f32, turns: f32, height: f32, center: Vector3<f32>) -> Vec<Transform> {
+    (0..count)
+        .map(|i| {
+            let t = if count > 1 { i as f32 / (count - 1) as f32 } else { 0.0 };
+            let angle = std::f32::consts::TAU * turns * t;
+            transform!(
+                center.x + radius * t * angle.cos(),
+                center.y + height * t,
+                center.z + radius * t * angle.sin(),
+                1.0, angle, 0.0, 0.0
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_layout() {
+        let tfs = grid([2, 1, 3], Vector3::new(1.0, 5.0, 2.0), Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!(tfs.len(), 6);
+        assert_eq!(tfs[0].position(), Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!(tfs[5].position(), Vector3::new(0.0, 0.0, 4.0));
+    }
+
+    #[test]
+    fn scatter_is_reproducible() {
+        let (min, max) = (Vector3::new(-1.0, 0.0, -1.0), Vector3::new(1.0, 0.0, 1.0));
+        let a = scatter(10, 7, min, max, (0.5, 1.0));
+        let b = scatter(10, 7, min, max, (0.5, 1.0));
+        assert_eq!(a, b);
+        assert_ne!(a, scatter(10, 8, min, max, (0.5, 1.0)));
+        assert!(a.iter().all(|tf| tf.x >= -1.0 && tf.x < 1.0 && tf.scale >= 0.5 && tf.scale < 1.0));
+    }
+
+    #[test]
+    fn rings_spirals_and_spacing() {
+        let center = Vector3::new(0.0, 1.0, 0.0);
+        let ring = ring(4, 2.0, center);
+        assert_eq!(ring.len(), 4);
+        assert!(ring.iter().all(|tf| ((tf.position() - center).magnitude() - 2.0).abs() < 1e-5));
+
+        let spiral = spiral(5, 3.0, 2.0, 4.0, center);
+        assert_eq!(spiral[0].position(), center);
+        assert!((spiral[4].position() - Vector3::new(3.0, 5.0, 0.0)).magnitude() < 1e-4);
+
+        let (min, max) = (Vector3::new(-5.0, 0.0, -5.0), Vector3::new(5.0, 0.0, 5.0));
+        assert_eq!(scatter_apart(10, 7, min, max, (0.5, 1.0), 0.0), scatter(10, 7, min, max, (0.5, 1.0)));
+        let apart = scatter_apart(20, 7, min, max, (1.0, 1.0), 1.5);
+        assert_eq!(apart.len(), 20);
+        for (i, a) in apart.iter().enumerate() {
+            assert!(apart[i + 1..].iter().all(|b| (a.position() - b.position()).magnitude() >= 1.5));
+        }
+        // no room for that many
+        assert!(scatter_apart(20, 7, min, max, (1.0, 1.0), 8.0).len() < 20);
+        // cells smaller than the box can index
+        assert_eq!(scatter_apart(20, 7, min, max, (1.0, 1.0), 1e-20).len(), 20);
+    }
+}
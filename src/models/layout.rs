@@ -0,0 +1,66 @@
+use crate::bvh;
+use crate::camera;
+use crate::ibl;
+use crate::lights;
+use crate::models::{std430, BOX_ID, NONE_ID, PLANE_ID, SDF_ID, SPHERE_ID};
+use crate::models::sdf::*;
+
+fn define(glsl: &mut String, name: &str, value: impl std::fmt::Display) {
+    glsl.push_str(&format!("#define {} {}\n", name, value));
+}
+
+/// Glsl declarations of the model structs of `std430` and of the lights, along with the
+/// constants the shader shares with the rust side
+pub fn glsl_header() -> String {
+    let mut glsl = String::from("// generated from `models::layout`, do not edit\n");
+
+    for (name, value) in [
+        ("NONE_ID", NONE_ID),
+        ("SPHERE_ID", SPHERE_ID),
+        ("BOX_ID", BOX_ID),
+        ("PLANE_ID", PLANE_ID),
+        ("SDF_ID", SDF_ID),
+        ("SDF_OP_SPHERE", SDF_OP_SPHERE),
+        ("SDF_OP_BOX", SDF_OP_BOX),
+        ("SDF_OP_ROUND_BOX", SDF_OP_ROUND_BOX),
+        ("SDF_OP_TORUS", SDF_OP_TORUS),
+        ("SDF_OP_CUSTOM", SDF_OP_CUSTOM),
+        ("SDF_OP_UNION", SDF_OP_UNION),
+        ("SDF_OP_INTERSECTION", SDF_OP_INTERSECTION),
+        ("SDF_OP_DIFFERENCE", SDF_OP_DIFFERENCE),
+        ("SDF_OP_SMOOTH_UNION", SDF_OP_SMOOTH_UNION),
+        ("SDF_OP_SMOOTH_INTERSECTION", SDF_OP_SMOOTH_INTERSECTION),
+        ("SDF_OP_SMOOTH_DIFFERENCE", SDF_OP_SMOOTH_DIFFERENCE),
+    ] {
+        define(&mut glsl, name, value);
+    }
+    define(&mut glsl, "SDF_STACK_SIZE", SDF_STACK_SIZE);
+    define(&mut glsl, "SDF_MAX_STEPS", SDF_MAX_STEPS);
+    define(&mut glsl, "SDF_EPSILON", format!("{:?}", SDF_EPSILON));
+    define(&mut glsl, "SDF_MIN_T", format!("{:?}", SDF_MIN_T));
+    define(&mut glsl, "BVH_STACK_SIZE", bvh::STACK_SIZE);
+    glsl.push_str(&camera::glsl_header());
+    glsl.push_str(&ibl::glsl_header());
+
+    glsl.push('\n');
+    glsl.push_str(&std430::glsl_structs());
+    glsl.push('\n');
+    glsl.push_str(&lights::glsl_header());
+    glsl.push('\n');
+    glsl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_declares_structs() {
+        let glsl = glsl_header();
+        assert!(glsl.contains("#define SDF_ID 3\n"));
+        assert!(glsl.contains("#define SDF_EPSILON 0.0001\n"));
+        assert!(glsl.contains("struct ModelData {\n    mat3 rotation;\n    vec3 position;\n    float scale;\n"));
+        // nested structs are declared before they are used
+        assert!(glsl.find("struct ModelData").unwrap() < glsl.find("struct Box").unwrap());
+    }
+}
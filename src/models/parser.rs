@@ -1,18 +1,33 @@
 extern crate yaml_rust;
 extern crate cgmath;
 
+mod expr;
+mod marks;
+
 use yaml_rust::{Yaml, YamlLoader};
+use yaml_rust::yaml::{Array, Hash};
 use yaml_rust::scanner::ScanError;
-use super::{ModelManager, ModelProperty, ModelType, CameraProperty};
+use super::{ModelManager, ModelProperty, ModelType};
+use super::sdf::{SdfNode, SDF_STACK_SIZE};
+use super::generate::{self, Rng};
 use crate::geospace::Transform;
 use crate::color::Color;
 use crate::material::Material;
+use crate::lights::{Light, LightManager, LightType};
+use crate::camera::{Camera, Cameras};
+use crate::scene::{Scene, Uniform};
+use crate::environment::{Environment, Skybox};
+use crate::sky::{Sky, SunPosition};
 use crate::{transform, col, define_material};
+use expr::{ExprError, Variables};
+use marks::{Marks, Position};
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::error;
 use std::str::FromStr;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use cgmath::Vector3;
 
@@ -75,23 +90,20 @@ fn display_yaml(value: &Yaml) -> String {
     }
 }
 
+/// What went wrong, `ParserError` tells where
 #[derive(Debug)]
-pub enum ParserError {
+pub enum ParserErrorKind {
     BadKey(String),
     BadType(String, YamlType, YamlType),
     BadValue(String, String),
     MissingKey(String),
     BadDocument(String),
+    BadExpression(String, String),
+    BadInclude(String, String),
     YamlError(ScanError),
 }
 
-impl From<ScanError> for ParserError {
-    fn from(se: ScanError) -> Self {
-        Self::YamlError(se)
-    }
-}
-
-impl Display for ParserError {
+impl Display for ParserErrorKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
         match self {
             Self::BadKey(s) => write!(f, "unknown key {}", s),
@@ -101,209 +113,1423 @@ impl Display for ParserError {
             Self::BadValue(s, reason) => write!(f, "bad value {}: {}", s, reason),
             Self::MissingKey(s) => write!(f, "necessary key {} not found", s),
             Self::BadDocument(s) => write!(f, "bad document: {}", s),
+            Self::BadExpression(s, reason) => write!(f, "bad expression \"{}\": {}", s, reason),
+            Self::BadInclude(s, reason) => write!(f, "can't include {}: {}", s, reason),
             Self::YamlError(se) => write!(f, "yaml-rust error: {}", se),
         }
     }
 }
 
+/// The line of the source an error points at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// from 1
+    pub line: usize,
+    /// from 0, in characters
+    pub col: usize,
+    /// number of characters pointed at
+    pub len: usize,
+    /// the whole line
+    pub text: String,
+}
+
+impl Location {
+    fn new(source: &str, position: Position) -> Option<Self> {
+        // errors at the end of the input can be on the line after the last
+        let text = source.lines().nth(position.line.checked_sub(1)?).unwrap_or("");
+        Some(Location {
+            line: position.line,
+            col: position.col,
+            len: node_len(text, position.col),
+            text: text.to_owned(),
+        })
+    }
+}
+
+/// Length of the node starting at `col` of the line: a quoted string, a flow
+/// collection up to its closing bracket or a plain scalar
+fn node_len(line: &str, col: usize) -> usize {
+    let rest: Vec<char> = line.chars().skip(col).collect();
+    let len = match rest.first() {
+        None => 1,
+        Some(&quote @ ('"' | '\'')) => rest[1..].iter().position(|&c| c == quote).map_or(rest.len(), |i| i + 2),
+        Some('[' | '{') => {
+            let mut depth = 0;
+            let mut len = rest.len();
+            for (i, c) in rest.iter().enumerate() {
+                match c {
+                    '[' | '{' => depth += 1,
+                    ']' | '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            len = i + 1;
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            len
+        }
+        Some(_) => {
+            // up to a flow indicator, a comment or the colon after a key
+            let end = rest
+                .iter()
+                .enumerate()
+                .position(|(i, &c)| {
+                    matches!(c, ',' | ']' | '}')
+                        || (c == '#' && i > 0 && rest[i - 1] == ' ')
+                        || (c == ':' && matches!(rest.get(i + 1), None | Some(' ')))
+                })
+                .unwrap_or(rest.len());
+            rest[..end].iter().rposition(|c| !c.is_whitespace()).map_or(1, |i| i + 1)
+        }
+    };
+    len.max(1)
+}
+
+/// An error, with the path to the node it is about, like
+/// `scene.models[3].transform.pos`, and where that node is in the source
+#[derive(Debug)]
+pub struct ParserError {
+    pub kind: ParserErrorKind,
+    pub path: String,
+    /// boxed to keep results small
+    pub location: Option<Box<Location>>,
+    /// the file the error is in, if the source was read from one
+    pub file: Option<String>,
+}
+
+impl ParserError {
+    /// Formats the error like rustc does, with the line of the source and a
+    /// caret under the node
+    pub fn render(&self) -> String {
+        let mut out = format!("error: {}\n", self.kind);
+        let number = self.location.as_ref().map_or(String::new(), |l| l.line.to_string());
+        let gutter = " ".repeat(number.len() + 1);
+        match self.location {
+            Some(ref l) => {
+                let file = self.file.as_ref().map_or(String::new(), |f| format!("{}:", f));
+                out += &format!("{}--> {}{}:{}\n", &gutter[1..], file, l.line, l.col + 1);
+                out += &format!("{}|\n", gutter);
+                out += &format!("{} | {}\n", number, l.text);
+                out += &format!("{}| {}{}\n", gutter, " ".repeat(l.col), "^".repeat(l.len));
+            }
+            None => {
+                if let Some(ref file) = self.file {
+                    out += &format!("{}--> {}\n", &gutter[1..], file);
+                }
+            }
+        }
+        if !self.path.is_empty() {
+            out += &format!("{}= note: in {}\n", gutter, self.path);
+        }
+        out
+    }
+}
+
+impl Display for ParserError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.render().trim_end())
+    }
+}
+
 impl error::Error for ParserError {}
 
+/// Every error found in a document and the files it includes
+#[derive(Debug)]
+pub struct ParserErrors {
+    pub errors: Vec<ParserError>,
+}
+
+impl ParserErrors {
+    /// Names the file of the errors that have none
+    pub fn in_file(mut self, file: &str) -> Self {
+        for e in self.errors.iter_mut().filter(|e| e.file.is_none()) {
+            e.file = Some(file.to_owned());
+        }
+        self
+    }
+}
+
+impl Display for ParserErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        let rendered: Vec<String> = self.errors.iter().map(|e| e.render()).collect();
+        write!(f, "{}", rendered.join("\n").trim_end())?;
+        if self.errors.len() > 1 {
+            write!(f, "\n\nerror: the scene has {} errors", self.errors.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ParserErrors {}
+
 pub type ParserResult<T> = Result<T, ParserError>;
 
-pub fn parse_scene(source: &str) -> ParserResult<(ModelManager, CameraProperty)> {
-    // load using yaml
-    let docs = YamlLoader::load_from_str(source)?;
+/// The key of yaml merges, `<<: *anchor`
+const MERGE_KEY: &str = "<<";
+
+/// A node of the document being parsed, its path and its marks point the
+/// errors about it at the source. Expressions in it can use `variables`.
+#[derive(Debug, Clone)]
+struct At<'a> {
+    path: String,
+    marks: Option<&'a Marks>,
+    source: &'a str,
+    file: Option<&'a str>,
+    variables: &'a Variables,
+}
+
+impl<'a> At<'a> {
+    /// The value under a key of this hash
+    fn key(&self, key: &str) -> At<'a> {
+        At {
+            path: if self.path.is_empty() { key.to_owned() } else { format!("{}.{}", self.path, key) },
+            marks: self.marks.and_then(|m| m.value(key)),
+            source: self.source,
+            file: self.file,
+            variables: self.variables,
+        }
+    }
+
+    /// An item of this array
+    fn index(&self, index: usize) -> At<'a> {
+        At {
+            path: format!("{}[{}]", self.path, index),
+            marks: self.marks.and_then(|m| m.item(index)),
+            source: self.source,
+            file: self.file,
+            variables: self.variables,
+        }
+    }
+
+    fn error(&self, kind: ParserErrorKind) -> ParserError {
+        ParserError {
+            kind,
+            path: self.path.clone(),
+            location: self.marks.and_then(|m| Location::new(self.source, m.position)).map(Box::new),
+            file: self.file.map(String::from),
+        }
+    }
+
+    fn bad_type(&self, node: &Yaml, expected: YamlType) -> ParserError {
+        self.error(ParserErrorKind::BadType(display_yaml(node), node.into(), expected))
+    }
+
+    fn bad_value(&self, shown: String, reason: &str) -> ParserError {
+        self.error(ParserErrorKind::BadValue(shown, reason.to_owned()))
+    }
+
+    fn missing_key(&self, key: &str) -> ParserError {
+        self.error(ParserErrorKind::MissingKey(key.to_owned()))
+    }
+
+    /// Points at a key of this hash rather than at its value, or at the merge
+    /// key it comes from
+    fn key_error(&self, key: &str, kind: ParserErrorKind) -> ParserError {
+        ParserError {
+            location: self
+                .marks
+                .and_then(|m| m.key(key).or_else(|| m.key(MERGE_KEY)))
+                .and_then(|m| Location::new(self.source, m.position))
+                .map(Box::new),
+            ..self.key(key).error(kind)
+        }
+    }
+
+    fn bad_key(&self, key: &str) -> ParserError {
+        self.key_error(key, ParserErrorKind::BadKey(key.to_owned()))
+    }
+
+    /// Points inside the expression when the string is written as is
+    fn bad_expression(&self, expression: &str, e: ExprError) -> ParserError {
+        let mut error = self.error(ParserErrorKind::BadExpression(expression.to_owned(), e.message));
+        if let Some(l) = error.location.as_mut() {
+            let quoted = matches!(l.text.chars().nth(l.col), Some('"' | '\''));
+            let start = l.col + quoted as usize;
+            let written: String = l.text.chars().skip(start).take(expression.chars().count()).collect();
+            if written == expression {
+                l.col = start + e.offset;
+                l.len = e.len;
+            }
+        }
+        error
+    }
+
+    fn hash<'y>(&self, node: &'y Yaml) -> ParserResult<&'y Hash> {
+        node.as_hash().ok_or_else(|| self.bad_type(node, YamlType::Hash))
+    }
+
+    fn array<'y>(&self, node: &'y Yaml) -> ParserResult<&'y Array> {
+        node.as_vec().ok_or_else(|| self.bad_type(node, YamlType::Array))
+    }
+
+    fn str<'y>(&self, node: &'y Yaml) -> ParserResult<&'y str> {
+        node.as_str().ok_or_else(|| self.bad_type(node, YamlType::String))
+    }
+
+    /// The entries of this hash, whose keys must be strings, with where their
+    /// values are. The hashes under a `<<` merge key add the entries it does
+    /// not have, the first of a list of them wins.
+    fn entries<'y>(&self, node: &'y Yaml) -> ParserResult<Vec<(&'y str, &'y Yaml, At<'a>)>> {
+        let mut entries = Vec::new();
+        let mut merged = Vec::new();
+        for (key, value) in self.hash(node)?.iter() {
+            let key_str = key.as_str().ok_or_else(|| self.bad_type(key, YamlType::String))?;
+            if key_str != MERGE_KEY {
+                entries.push((key_str, value, self.key(key_str)));
+                continue;
+            }
+            let merge_at = self.key(MERGE_KEY);
+            let sources = match value {
+                Yaml::Array(_) => merge_at.items(value)?,
+                _ => vec![(value, merge_at)],
+            };
+            for (source, source_at) in sources {
+                for (k, v, v_at) in source_at.entries(source)? {
+                    // found where it is written, named as part of this hash
+                    merged.push((k, v, At { path: self.key(k).path, ..v_at }));
+                }
+            }
+        }
+        for entry in merged {
+            if !entries.iter().any(|(k, _, _)| *k == entry.0) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The items of this array with where they are
+    fn items<'y>(&self, node: &'y Yaml) -> ParserResult<Vec<(&'y Yaml, At<'a>)>> {
+        Ok(self.array(node)?.iter().enumerate().map(|(i, item)| (item, self.index(i))).collect())
+    }
+}
+
+/// Keeps the error to go on with the rest of the document
+fn collect<T>(result: ParserResult<T>, errors: &mut Vec<ParserError>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+/// Parses a scene, reporting every error in the document rather than only
+/// the first one. Included files are relative to the working directory.
+pub fn parse_scene(source: &str) -> Result<Scene, ParserErrors> {
+    read_scene(source, None)
+}
+
+/// Parses the source of the scene file at `path`, included files are
+/// relative to it. The scene lists every file read in `Scene::files`.
+pub fn parse_scene_file(source: &str, path: &Path) -> Result<Scene, ParserErrors> {
+    read_scene(source, Some(path))
+}
+
+/// What the files read so far add up to
+struct Reader {
+    scene: Scene,
+    cameras: Option<Cameras>,
+    /// the files being read, as found and as given, to find include cycles
+    stack: Vec<(PathBuf, PathBuf)>,
+    files: Vec<PathBuf>,
+}
+
+fn read_scene(source: &str, file: Option<&Path>) -> Result<Scene, ParserErrors> {
+    let mut reader = Reader { scene: Scene::new(), cameras: None, stack: Vec::new(), files: Vec::new() };
+    if let Some(path) = file {
+        let found = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        reader.stack.push((found, path.to_path_buf()));
+        reader.files.push(path.to_path_buf());
+    }
+    let mut errors = Vec::new();
+    parse_document(source, file, &mut reader, &mut errors);
+
+    // a template used by many models reports its errors once
+    let mut rendered = Vec::new();
+    errors.retain(|e| {
+        let r = e.render();
+        let first = !rendered.contains(&r);
+        rendered.push(r);
+        first
+    });
+    if !errors.is_empty() {
+        return Err(ParserErrors { errors });
+    }
+    let mut scene = reader.scene;
+    scene.cameras = reader.cameras.unwrap_or_default();
+    scene.files = reader.files;
+    Ok(scene)
+}
+
+/// Adds what the document describes to the scene of the reader
+fn parse_document(source: &str, file: Option<&Path>, reader: &mut Reader, errors: &mut Vec<ParserError>) {
+    let file_name = file.map(|f| f.display().to_string());
+
+    // load using yaml, along with where each node is
+    let loaded = YamlLoader::load_from_str(source).and_then(|docs| Ok((docs, Marks::load(source)?)));
+    let (docs, marks) = match loaded {
+        Ok(loaded) => loaded,
+        Err(se) => {
+            errors.push(ParserError {
+                location: Location::new(source, se.marker().into()).map(Box::new),
+                kind: ParserErrorKind::YamlError(se),
+                path: String::new(),
+                file: file_name,
+            });
+            return;
+        }
+    };
+    let no_variables = Variables::new();
+    let root = At { path: String::new(), marks: marks.first(), source, file: file_name.as_deref(), variables: &no_variables };
 
     if docs.len() != 1 {
-        return Err(ParserError::BadDocument("only a single yaml document should be used".to_string()));
+        errors.push(root.error(ParserErrorKind::BadDocument("only a single yaml document should be used".to_string())));
+        return;
     }
 
     let doc = &docs[0];
-    let source_hash = doc
-                      .as_hash()
-                      .ok_or(ParserError::BadType(String::from("entire document"), doc.into(), YamlType::Hash))?;
-    
+    let source_hash = match collect(root.hash(doc), errors) {
+        Some(source_hash) => source_hash,
+        None => return,
+    };
     if source_hash.len() != 1 {
-        return Err(ParserError::BadDocument("only one key-value pair at top scope".to_string()));
+        errors.push(root.error(ParserErrorKind::BadDocument("only one key-value pair at top scope".to_string())));
+        return;
     }
 
     // get scene
-    let scene = source_hash
-                 .get(&Yaml::String("scene".to_owned()))
-                 .ok_or_else(|| 
-                        if let Some((key, _)) = source_hash.front() {
-                            match key {
-                                Yaml::String(s) => ParserError::BadKey(s.clone()),
-                                other => ParserError::BadType(display_yaml(other), other.into(), YamlType::String),
-                            }
-                        } else {
-                            unreachable!() // i guarantee that at least one element exists
-                        }
-                     )
-                 ?;
+    let scene = match source_hash.get(&Yaml::String("scene".to_owned())) {
+        Some(scene) => scene,
+        None => {
+            // i guarantee that at least one element exists
+            let (key, _) = source_hash.front().unwrap();
+            errors.push(match key {
+                Yaml::String(s) => root.bad_key(s),
+                other => root.bad_type(other, YamlType::String),
+            });
+            return;
+        }
+    };
+    let at = root.key("scene");
+    let entries = match collect(at.entries(scene), errors) {
+        Some(entries) => entries,
+        None => return,
+    };
+
+    // included files come first, what this one sets wins
+    if let Some((_, include, include_at)) = entries.iter().find(|(key, _, _)| *key == "include") {
+        parse_include(include, include_at, file, reader, errors);
+    }
+
+    // variables come first so that anything can use them
+    let defs = entries.iter().find(|(key, _, _)| *key == "defs");
+    let variables = match defs {
+        Some((_, defs, defs_at)) => parse_defs(defs, defs_at, errors),
+        None => Variables::new(),
+    };
+    let at = At { variables: &variables, ..at };
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, value, value_at)| (key, value, At { variables: &variables, ..value_at }))
+        .collect();
+    let scene = &mut reader.scene;
 
-    let scene_hash = scene.as_hash().ok_or(ParserError::BadType(display_yaml(scene), scene.into(), YamlType::Hash))?;
-    let mut mm = ModelManager::new();
-    let mut cp = CameraProperty::new();
-    for (key, val) in scene_hash.iter() {
-        let key_str = key.as_str().ok_or(ParserError::BadType(display_yaml(key), key.into(), YamlType::String))?;
+    // models refer to templates and materials by name, wherever they are defined
+    let templates = match entries.iter().find(|(key, _, _)| *key == "defs") {
+        Some((_, defs, defs_at)) => parse_templates(defs, defs_at, errors),
+        None => Vec::new(),
+    };
+    if let Some((_, materials, materials_at)) = entries.iter().find(|(key, _, _)| *key == "materials") {
+        for (name, material) in parse_materials(materials, materials_at, errors) {
+            match scene.materials.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, included)) => *included = material,
+                None => scene.materials.push((name, material)),
+            }
+        }
+    }
 
-        match key_str {
+    let mut own_cameras = false;
+    let mut cameras_failed = false;
+    // cameras of included files, which this file can replace
+    let mut included_cameras: Vec<String> = match &reader.cameras {
+        Some(cameras) => cameras.iter().map(|(name, _)| name.to_owned()).collect(),
+        None => Vec::new(),
+    };
+    let mut active_camera: Option<(&str, &At)> = None;
+    for (key, val, val_at) in entries.iter() {
+        match *key {
+            "include" | "materials" | "defs" => {}
             "models" => {
-                let models = parse_models(val)?;
-                models.into_iter().for_each(|m| { mm.add_new(m); });
+                let defs = Defs { materials: &scene.materials, templates: &templates };
+                parse_models(val, val_at, &mut scene.models, &defs, errors);
+            }
+            "lights" => parse_lights(val, val_at, &mut scene.lights, errors),
+            "environment" => {
+                if let Some(environment) = collect(parse_environment(val, val_at, file.and_then(Path::parent)), errors) {
+                    // a changed image reloads the scene like a changed file
+                    for path in environment.skybox.paths() {
+                        if !reader.files.contains(path) {
+                            reader.files.push(path.clone());
+                        }
+                    }
+                    scene.environment = environment;
+                }
+            }
+            "uniforms" => { scene.uniforms = parse_uniforms(val, val_at, errors); }
+            "camera" | "cameras" if own_cameras => {
+                let reason = String::from("camera and cameras are exclusive");
+                errors.push(at.key_error(key, ParserErrorKind::BadValue(String::from(*key), reason)));
             }
             "camera" => {
-                let (cpos, cpitch, cyaw) = parse_camera(val)?;    
-                cp.tf.x = cpos.x;
-                cp.tf.y = cpos.y;
-                cp.tf.z = cpos.z;
-                cp.tf.pitch = cpitch;
-                cp.tf.head = cyaw;
-            },
-            s => { return Err(ParserError::BadKey(String::from(s))); },
+                own_cameras = true;
+                match parse_camera(val, val_at) {
+                    Ok((name, camera)) => {
+                        let name = name.unwrap_or(Cameras::DEFAULT_NAME);
+                        add_camera(&mut reader.cameras, name, camera, &mut included_cameras, val_at, errors);
+                    }
+                    Err(e) => {
+                        errors.push(e);
+                        cameras_failed = true;
+                    }
+                }
+            }
+            "cameras" => {
+                own_cameras = true;
+                let before = errors.len();
+                parse_cameras(val, val_at, &mut reader.cameras, &mut included_cameras, errors);
+                cameras_failed |= errors.len() > before;
+            }
+            "active_camera" => {
+                if let Some(name) = collect(val_at.str(val), errors) {
+                    active_camera = Some((name, val_at));
+                }
+            }
+            s => errors.push(at.bad_key(s)),
+        }
+    }
+    if let Some((name, name_at)) = active_camera {
+        let cameras = reader.cameras.get_or_insert_with(Cameras::default);
+        match cameras.index_of(name) {
+            Some(index) => { cameras.set_active(index); }
+            // the camera may be one that could not be parsed
+            None if cameras_failed => {}
+            None => errors.push(name_at.bad_value(format!("\"{}\"", name), "no camera has this name")),
+        }
+    }
+}
+
+/// Reads a file or a list of files, relative to the including one
+fn parse_include(node: &Yaml, at: &At, file: Option<&Path>, reader: &mut Reader, errors: &mut Vec<ParserError>) {
+    let list = match node {
+        Yaml::String(_) => vec![(node, at.clone())],
+        _ => collect(at.items(node), errors).unwrap_or_default(),
+    };
+    for (item, item_at) in list {
+        let name = match collect(item_at.str(item), errors) {
+            Some(name) => name,
+            None => continue,
+        };
+        let path = match file.and_then(Path::parent) {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        };
+        let bad_include = |reason: String| item_at.error(ParserErrorKind::BadInclude(format!("\"{}\"", name), reason));
+
+        let found = match path.canonicalize() {
+            Ok(found) => found,
+            Err(e) => {
+                errors.push(bad_include(e.to_string()));
+                continue;
+            }
+        };
+        if let Some(start) = reader.stack.iter().position(|(f, _)| *f == found) {
+            let cycle: Vec<String> = reader.stack[start..]
+                .iter()
+                .map(|(_, given)| given)
+                .chain(Some(&path))
+                .map(|p| p.display().to_string())
+                .collect();
+            errors.push(bad_include(format!("files include each other, {}", cycle.join(" -> "))));
+            continue;
+        }
+        let source = match fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(e) => {
+                errors.push(bad_include(e.to_string()));
+                continue;
+            }
+        };
+
+        if !reader.files.iter().any(|f| f.canonicalize().ok().as_ref() == Some(&found)) {
+            reader.files.push(path.clone());
+        }
+        reader.stack.push((found, path.clone()));
+        parse_document(&source, Some(&path), reader, errors);
+        reader.stack.pop();
+    }
+}
+
+/// Checks the definitions and returns the variables, templates are read
+/// once the variables are known
+fn parse_defs(node: &Yaml, at: &At, errors: &mut Vec<ParserError>) -> Variables {
+    let mut variables = Variables::new();
+    for (key, value, value_at) in collect(at.entries(node), errors).unwrap_or_default() {
+        match key {
+            "variables" => { variables = parse_variables(value, &value_at, errors); }
+            "templates" => {}
+            _ => errors.push(at.bad_key(key)),
+        }
+    }
+    variables
+}
+
+/// Numbers by name, each can use the ones before it
+fn parse_variables(node: &Yaml, at: &At, errors: &mut Vec<ParserError>) -> Variables {
+    let mut variables = Variables::new();
+    for (name, value, value_at) in collect(at.entries(node), errors).unwrap_or_default() {
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            let reason = "variable names are made of letters, digits and _";
+            errors.push(at.key_error(name, ParserErrorKind::BadValue(format!("\"{}\"", name), reason.to_owned())));
+            continue;
+        }
+        let value = parse_real(value, &At { variables: &variables, ..value_at });
+        if let Some(value) = collect(value, errors) {
+            variables.insert(name.to_owned(), value);
+        }
+    }
+    variables
+}
+
+/// The templates of `defs`, each is the hash of a partial model
+fn parse_templates<'a>(node: &'a Yaml, at: &At<'a>, errors: &mut Vec<ParserError>) -> Vec<Template<'a>> {
+    let mut templates = Vec::new();
+    for (key, value, value_at) in collect(at.entries(node), errors).unwrap_or_default() {
+        if key != "templates" {
+            continue;
+        }
+        for (name, template, template_at) in collect(value_at.entries(value), errors).unwrap_or_default() {
+            if collect(template_at.hash(template), errors).is_some() {
+                templates.push((name, template, template_at));
+            }
+        }
+    }
+    templates
+}
+
+/// Named materials, the names given to models
+type Materials = [(String, Material)];
+
+/// A model template by name, which models apply with `use: name`
+type Template<'a> = (&'a str, &'a Yaml, At<'a>);
+
+/// What models refer to by name
+struct Defs<'d, 'a> {
+    materials: &'d Materials,
+    templates: &'d [Template<'a>],
+}
+
+fn parse_models(node: &Yaml, at: &At, mm: &mut ModelManager, defs: &Defs, errors: &mut Vec<ParserError>) {
+    let models = match collect(at.items(node), errors) {
+        Some(models) => models,
+        None => return,
+    };
+    for (model, model_at) in models {
+        collect(parse_model_entry(model, &model_at, mm, defs), errors);
+    }
+}
+
+/// A model or a prototype with its instances
+fn parse_model_entry(model: &Yaml, at: &At, mm: &mut ModelManager, defs: &Defs) -> ParserResult<()> {
+    let entries = at.entries(model)?;
+
+    match entries.iter().find(|(key, _, _)| *key == "instances" || *key == "generate") {
+        Some((key, _, _)) if entries.len() != 1 => {
+            Err(at.bad_value(display_yaml(model), &format!("{} can't be combined with other keys", key)))
+        }
+        Some(&("instances", instances, ref instances_at)) => {
+            let (prototype, transforms) = parse_instances(instances, instances_at, defs)?;
+            mm.add_instances(prototype, transforms);
+            Ok(())
+        }
+        Some((_, generate, generate_at)) => {
+            for model in parse_generate(generate, generate_at, defs)? {
+                mm.add_new(model);
+            }
+            Ok(())
+        }
+        None => {
+            mm.add_new(parse_model(model, at, defs)?);
+            Ok(())
+        }
+    }
+}
+
+/// The model and the templates it uses, from the most basic one
+fn model_layers<'a>(model: &'a Yaml, at: &At<'a>, defs: &Defs<'_, 'a>) -> ParserResult<Vec<(&'a Yaml, At<'a>)>> {
+    let mut layers = vec![(model, at.clone())];
+    loop {
+        let (layer, layer_at) = layers.last().unwrap();
+        let used = layer_at.entries(layer)?.into_iter().find(|(key, _, _)| *key == "use");
+        let (name, name_at) = match used {
+            Some((_, name, name_at)) => (name_at.str(name)?, name_at),
+            None => break,
+        };
+        let (_, template, template_at) = defs
+            .templates
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .ok_or_else(|| name_at.bad_value(format!("\"{}\"", name), "no template has this name"))?;
+        if layers.iter().any(|(l, _)| std::ptr::eq(*l, *template)) {
+            return Err(name_at.bad_value(format!("\"{}\"", name), "templates can't use themselves"));
+        }
+        layers.push((template, template_at.clone()));
+    }
+    layers.reverse();
+    Ok(layers)
+}
+
+fn parse_model(model: &Yaml, at: &At, defs: &Defs) -> ParserResult<ModelProperty> {
+    let mut model_type: Option<ModelType> = None;
+    let mut model_tf = transform!();
+    let mut model_color = col!();
+    let mut model_mat = define_material!(1.0);
+    let mut model_shape: Option<(SdfNode, At)> = None;
+    let mut model_size: Option<(Vector3<f32>, At)> = None;
+
+    // what the model sets overrides its templates
+    for (layer, layer_at) in model_layers(model, at, defs)? {
+        for (property, value, value_at) in layer_at.entries(layer)? {
+            match property {
+                "use" => {}
+                "type" => { model_type = Some(parse_type(value, &value_at)?); }
+                "transform" => { model_tf = parse_transform(value, &value_at, model_tf)?; }
+                "color" => { model_color = parse_color(value, &value_at)?; }
+                "material" => { model_mat = parse_model_material(value, &value_at, defs.materials)?; }
+                "shape" => { model_shape = Some((parse_shape(value, &value_at)?, value_at)); }
+                "size" => { model_size = Some((parse_vector3(value, &value_at)?, value_at)); }
+                badkey => { return Err(layer_at.bad_key(badkey)); },
+            }
+        }
+    }
+
+    let mut model_type = model_type.ok_or_else(|| at.missing_key("type"))?;
+    if let Some((shape, shape_at)) = model_shape {
+        match model_type {
+            ModelType::Sdf(_) => { model_type = ModelType::Sdf(shape); }
+            _ => { return Err(shape_at.bad_value(String::from("shape"), "only sdf models have a shape")); }
+        }
+    }
+    if let Some((size, size_at)) = model_size {
+        match model_type {
+            ModelType::Box(_, _, _) if size.x > 0.0 && size.y > 0.0 && size.z > 0.0 => {
+                model_type = ModelType::Box(size.x, size.y, size.z);
+            }
+            ModelType::Box(_, _, _) => { return Err(size_at.bad_value(format!("{:?}", size), "box size must be positive")); }
+            _ => { return Err(size_at.bad_value(String::from("size"), "only box models have a size")); }
+        }
+    }
+
+    Ok(ModelProperty {
+        t: model_type,
+        tf: model_tf,
+        color: model_color,
+        material: model_mat,
+    })
+}
+
+fn parse_lights(node: &Yaml, at: &At, lm: &mut LightManager, errors: &mut Vec<ParserError>) {
+    let lights = match collect(at.items(node), errors) {
+        Some(lights) => lights,
+        None => return,
+    };
+    for (light, light_at) in lights {
+        if let Some(light) = collect(parse_light(light, &light_at), errors) {
+            lm.add_new(light);
+        }
+    }
+}
+
+fn parse_light(node: &Yaml, at: &At) -> ParserResult<Light> {
+    let entries = at.entries(node)?;
+
+    let (_, light_type, type_at) = entries.iter().find(|(key, _, _)| *key == "type").ok_or_else(|| at.missing_key("type"))?;
+    let type_str = type_at.str(light_type)?;
+    let mut light = Light::new(
+        type_str.try_into().map_err(|_| type_at.bad_value(type_str.to_owned(), "light type does not exist"))?
+    );
+
+    for (property, value, value_at) in entries {
+        match (property, &mut light.t) {
+            ("type", _) => {}
+            ("pos", _) => { light.position = parse_vector3(value, &value_at)?; }
+            ("direction", _) => {
+                light.direction = parse_vector3(value, &value_at)?;
+                if light.direction == Vector3::new(0.0, 0.0, 0.0) {
+                    return Err(value_at.bad_value(String::from("direction"), "must not be zero"));
+                }
+            }
+            ("color", _) => { light.color = parse_color(value, &value_at)?; }
+            ("intensity", _) => { light.intensity = parse_real(value, &value_at)?; }
+            ("inner", LightType::Spot { inner, .. }) => { *inner = parse_real(value, &value_at)?; }
+            ("outer", LightType::Spot { outer, .. }) => { *outer = parse_real(value, &value_at)?; }
+            ("radius", LightType::Sphere { radius }) => { *radius = parse_real(value, &value_at)?; }
+            ("width", LightType::Rect { width, .. }) => { *width = parse_real(value, &value_at)?; }
+            ("height", LightType::Rect { height, .. }) => { *height = parse_real(value, &value_at)?; }
+            (badkey, _) => { return Err(at.bad_key(badkey)); },
+        }
+    }
+
+    if let LightType::Spot { inner, outer } = light.t {
+        // the shader blends between the two angles, they can't be equal
+        if inner >= outer {
+            return Err(at.key("inner").bad_value(inner.to_string(), "must be narrower than the outer angle"));
+        }
+    }
+    Ok(light)
+}
+
+fn parse_instances(node: &Yaml, at: &At, defs: &Defs) -> ParserResult<(ModelProperty, Vec<Transform>)> {
+    let mut prototype: Option<ModelProperty> = None;
+    let mut transforms = Vec::new();
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "prototype" => { prototype = Some(parse_model(value, &value_at, defs)?); }
+            "transforms" => {
+                for (tf, tf_at) in value_at.items(value)? {
+                    let tf = parse_transform(tf, &tf_at, transform!())?;
+                    // instances are intersected in the space of the prototype
+                    if tf.scale <= 0.0 {
+                        return Err(tf_at.key("scale").bad_value(tf.scale.to_string(), "scale must be positive"));
+                    }
+                    transforms.push(tf);
+                }
+            }
+            "grid" => { transforms.extend(parse_grid(value, &value_at)?); }
+            "scatter" => { transforms.extend(parse_scatter(value, &value_at)?); }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
+
+    let prototype = prototype.ok_or_else(|| at.missing_key("prototype"))?;
+    Ok((prototype, transforms))
+}
+
+fn parse_grid(node: &Yaml, at: &At) -> ParserResult<Vec<Transform>> {
+    let mut count = [1u32; 3];
+    let mut spacing = Vector3::<f32>::new(1.0, 1.0, 1.0);
+    let mut origin = Vector3::<f32>::new(0.0, 0.0, 0.0);
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "count" => {
+                let v = value_at.items(value)?;
+                if v.len() != 3 {
+                    return Err(value_at.bad_value(display_yaml(value), "count must be a 3-component vector"));
+                }
+                for (c, (n, n_at)) in count.iter_mut().zip(v) {
+                    *c = parse_generated_count(n, &n_at)?;
+                }
+                let total: u64 = count.iter().map(|&c| c as u64).product();
+                if total > MAX_GENERATED as u64 {
+                    return Err(value_at.bad_value(display_yaml(value), &too_many_generated()));
+                }
+            }
+            "spacing" => { spacing = parse_vector3(value, &value_at)?; }
+            "origin" => { origin = parse_vector3(value, &value_at)?; }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
+    Ok(generate::grid(count, spacing, origin))
+}
+
+/// Copies of a model laid out by a grid, a ring, a spiral or a scatter,
+/// with their scale, color and material picked at random
+fn parse_generate(node: &Yaml, at: &At, defs: &Defs) -> ParserResult<Vec<ModelProperty>> {
+    let mut layout: Option<Vec<Transform>> = None;
+    let mut model = None;
+    let mut seed: u64 = 0;
+    let mut colors = Vec::new();
+    let mut scale = None;
+    let mut reflectance = None;
+    let mut roughness = None;
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "grid" | "ring" | "spiral" | "scatter" => {
+                if layout.is_some() {
+                    return Err(at.bad_value(property.to_owned(), "grid, ring, spiral and scatter are exclusive"));
+                }
+                layout = Some(match property {
+                    "grid" => parse_grid(value, &value_at)?,
+                    "ring" => parse_ring(value, &value_at)?,
+                    "spiral" => parse_spiral(value, &value_at)?,
+                    _ => parse_scatter(value, &value_at)?,
+                });
+            }
+            "model" => { model = Some((value, value_at)); }
+            "seed" => { seed = parse_count(value, &value_at)? as u64; }
+            "colors" => {
+                for (color, color_at) in value_at.items(value)? {
+                    colors.push(parse_color(color, &color_at)?);
+                }
+                if colors.is_empty() {
+                    return Err(value_at.bad_value(display_yaml(value), "at least one color is needed"));
+                }
+            }
+            "scale" => {
+                let range = parse_range(value, &value_at)?;
+                if range.0 <= 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "scale must be positive"));
+                }
+                scale = Some(range);
+            }
+            "reflectance" => {
+                let range = parse_range(value, &value_at)?;
+                if range.0 < 0.0 || range.1 > 1.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "reflectance goes from 0 to 1"));
+                }
+                reflectance = Some(range);
+            }
+            "roughness" => {
+                let range = parse_range(value, &value_at)?;
+                if range.0 < 0.0 || range.1 > 1.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "roughness goes from 0 to 1"));
+                }
+                roughness = Some(range);
+            }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
+
+    let layout = layout.ok_or_else(|| at.missing_key("grid, ring, spiral or scatter"))?;
+    let (model, model_at) = model.ok_or_else(|| at.missing_key("model"))?;
+    let model = parse_model(model, &model_at, defs)?;
+    let mut rng = Rng::new(seed);
+    Ok(layout
+        .into_iter()
+        .map(|tf| {
+            // the transform of the model is relative to its place
+            let mut m = model.clone();
+            m.tf.x += tf.x;
+            m.tf.y += tf.y;
+            m.tf.z += tf.z;
+            m.tf.scale *= tf.scale;
+            m.tf.head += tf.head;
+            m.tf.pitch += tf.pitch;
+            m.tf.rotate += tf.rotate;
+            if let Some((a, b)) = scale {
+                m.tf.scale *= rng.range(a, b);
+            }
+            if !colors.is_empty() {
+                m.color = colors[(rng.next_u64() % colors.len() as u64) as usize].clone();
+            }
+            if let Some((a, b)) = reflectance {
+                m.material.reflectance = rng.range(a, b);
+            }
+            if let Some((a, b)) = roughness {
+                m.material.roughness = rng.range(a, b);
+            }
+            m
+        })
+        .collect())
+}
+
+/// A single value or a range `[min, max]`
+fn parse_range(value: &Yaml, at: &At) -> ParserResult<(f32, f32)> {
+    if value.as_vec().is_none() {
+        let x = parse_real(value, at)?;
+        return Ok((x, x));
+    }
+    let v = parse_vector(value, at)?;
+    if v.len() != 2 || v[0] > v[1] {
+        return Err(at.bad_value(display_yaml(value), "must be a value or a range [min, max]"));
+    }
+    Ok((v[0], v[1]))
+}
+
+fn parse_ring(node: &Yaml, at: &At) -> ParserResult<Vec<Transform>> {
+    let mut count: Option<u32> = None;
+    let mut radius = 1.0;
+    let mut center = Vector3::<f32>::new(0.0, 0.0, 0.0);
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "count" => { count = Some(parse_generated_count(value, &value_at)?); }
+            "radius" => { radius = parse_real(value, &value_at)?; }
+            "center" => { center = parse_vector3(value, &value_at)?; }
+            _ => { return Err(at.bad_key(property)); },
         }
     }
-    Ok((mm, cp))
+
+    let count = count.ok_or_else(|| at.missing_key("count"))?;
+    Ok(generate::ring(count, radius, center))
 }
 
-fn parse_models(node: &Yaml) -> ParserResult<Vec<ModelProperty>> {
-    let mut modelprops = Vec::new();
-    let models = node.as_vec().ok_or(ParserError::BadType(display_yaml(node), node.into(), YamlType::Array))?;
+fn parse_spiral(node: &Yaml, at: &At) -> ParserResult<Vec<Transform>> {
+    let mut count: Option<u32> = None;
+    let mut radius = 1.0;
+    let mut turns = 1.0;
+    let mut height = 0.0;
+    let mut center = Vector3::<f32>::new(0.0, 0.0, 0.0);
 
-    for model in models.iter() {
-        let mut model_type: Option<ModelType> = None;
-        let mut model_tf = transform!();
-        let mut model_color = col!();
-        let mut model_mat = define_material!(1.0);
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "count" => { count = Some(parse_generated_count(value, &value_at)?); }
+            "radius" => { radius = parse_real(value, &value_at)?; }
+            "turns" => { turns = parse_real(value, &value_at)?; }
+            "height" => { height = parse_real(value, &value_at)?; }
+            "center" => { center = parse_vector3(value, &value_at)?; }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
 
-        // verify that model is a hash
-        let model_hash = model.as_hash().ok_or(ParserError::BadType(display_yaml(model), model.into(), YamlType::Hash))?;
-        for (property, value) in model_hash.iter() {
-            // verify that property is a string type
-            let property_str = property
-                               .as_str()
-                               .ok_or(ParserError::BadType(display_yaml(property), property.into(), YamlType::String))?;
+    let count = count.ok_or_else(|| at.missing_key("count"))?;
+    Ok(generate::spiral(count, radius, turns, height, center))
+}
 
-            match property_str {
-                "type" => { model_type = Some(parse_type(value)?); }
-                "transform" => { model_tf = parse_transform(value)?; }
-                "color" => { model_color = parse_color(value)?; }
-                "material" => { model_mat = parse_material(value)?; }
-                badkey => { return Err(ParserError::BadKey(badkey.to_owned())); },
+/// The box is given by `min` and `max` or by `bounds: [min, max]`
+fn parse_scatter(node: &Yaml, at: &At) -> ParserResult<Vec<Transform>> {
+    let mut count: Option<u32> = None;
+    let mut seed: u64 = 0;
+    let mut min = Vector3::<f32>::new(-1.0, -1.0, -1.0);
+    let mut max = Vector3::<f32>::new(1.0, 1.0, 1.0);
+    let mut scale = (1.0, 1.0);
+    let mut min_distance = 0.0;
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "count" => { count = Some(parse_generated_count(value, &value_at)?); }
+            "seed" => { seed = parse_count(value, &value_at)? as u64; }
+            "min" => { min = parse_vector3(value, &value_at)?; }
+            "max" => { max = parse_vector3(value, &value_at)?; }
+            "bounds" => {
+                let corners = value_at.items(value)?;
+                if corners.len() != 2 {
+                    return Err(value_at.bad_value(display_yaml(value), "bounds are two corners [min, max]"));
+                }
+                min = parse_vector3(corners[0].0, &corners[0].1)?;
+                max = parse_vector3(corners[1].0, &corners[1].1)?;
+            }
+            "scale" => {
+                scale = parse_range(value, &value_at)?;
+                if scale.0 <= 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "scale must be positive"));
+                }
+            }
+            "min_distance" => {
+                min_distance = parse_real(value, &value_at)?;
+                if !(min_distance >= 0.0 && min_distance.is_finite()) {
+                    return Err(value_at.bad_value(display_yaml(value), "must be a non-negative distance"));
+                }
             }
+            _ => { return Err(at.bad_key(property)); },
         }
+    }
+
+    let count = count.ok_or_else(|| at.missing_key("count"))?;
+    let transforms = generate::scatter_apart(count, seed, min, max, scale, min_distance);
+    if transforms.len() < count as usize {
+        let reason = format!("only {} of {} fit at that distance", transforms.len(), count);
+        return Err(at.key("min_distance").bad_value(min_distance.to_string(), &reason));
+    }
+    Ok(transforms)
+}
 
-        modelprops.push(ModelProperty {
-            t: model_type.ok_or(ParserError::MissingKey(String::from("type")))?,
-            tf: model_tf,
-            color: model_color,
-            material: model_mat,
-        });
+/// A non-negative integer
+fn parse_count(value: &Yaml, at: &At) -> ParserResult<u32> {
+    if let Yaml::Integer(i) = value {
+        u32::try_from(*i).map_err(|_| at.bad_value(i.to_string(), "must be a non-negative integer"))
+    } else {
+        Err(at.bad_type(value, YamlType::Integer))
+    }
+}
+
+/// Most models a single layout can generate
+const MAX_GENERATED: u32 = 100_000;
+
+fn too_many_generated() -> String {
+    format!("at most {} models can be generated at once", MAX_GENERATED)
+}
+
+/// A count of generated models, up to `MAX_GENERATED`
+fn parse_generated_count(value: &Yaml, at: &At) -> ParserResult<u32> {
+    let count = parse_count(value, at)?;
+    if count > MAX_GENERATED {
+        return Err(at.bad_value(count.to_string(), &too_many_generated()));
     }
-    Ok(modelprops)
+    Ok(count)
 }
 
-fn parse_type(value: &Yaml) -> ParserResult<ModelType> {
-    let s = value.as_str().ok_or(ParserError::BadType(display_yaml(value), value.into(), YamlType::String))?;
-    s.try_into().or(Err(ParserError::BadValue(s.to_owned(), String::from("type does not exist"))))
+fn parse_type(value: &Yaml, at: &At) -> ParserResult<ModelType> {
+    let s = at.str(value)?;
+    s.try_into().map_err(|_| at.bad_value(s.to_owned(), "type does not exist"))
 }
 
-fn parse_transform(value: &Yaml) -> ParserResult<Transform> {
-    let transform_hash = value.as_hash().ok_or(ParserError::BadType(display_yaml(value), value.into(), YamlType::Hash))?;    
-    let mut tf = transform!();
+/// Starts from `tf`, so that a model keeps what its template set
+fn parse_transform(value: &Yaml, at: &At, mut tf: Transform) -> ParserResult<Transform> {
 
-    for (property, value) in transform_hash.iter() {
-        let property_str = property
-                           .as_str()
-                           .ok_or(ParserError::BadType(display_yaml(property), property.into(), YamlType::String))?;
-        match property_str {
+    for (property, value, value_at) in at.entries(value)? {
+        match property {
             "pos" => {
-                let v = parse_vector(value)?;
+                let v = parse_vector(value, &value_at)?;
                 if v.len() != 3 {
-                    return Err(ParserError::BadValue(format!("{:?}", v), String::from("position must be a 3-component vector")));
+                    return Err(value_at.bad_value(format!("{:?}", v), "position must be a 3-component vector"));
                 }
                 tf.x = v[0];
                 tf.y = v[1];
                 tf.z = v[2];
             }
-            "scale" => { tf.scale = parse_real(value)?; }
+            "scale" => { tf.scale = parse_real(value, &value_at)?; }
             "orientation" => {
-                let (head, pitch, roll) = parse_orientation(value)?;
+                let (head, pitch, roll) = parse_orientation(value, &value_at)?;
                 tf.head = head;
                 tf.pitch = pitch;
                 tf.rotate = roll;
             }
-            _ => { return Err(ParserError::BadKey(property_str.to_owned())); },
+            _ => { return Err(at.bad_key(property)); },
         }
     }
     Ok(tf)
 }
 
-fn parse_real(value: &Yaml) -> ParserResult<f32> {
-    if let Yaml::Real(s) = value {
-        f32::from_str(s.as_str())
-        .or(Err(ParserError::BadValue(
-                    s.clone(),
-                    String::from("value can't fit into mediump float"))))
-    } else {
-        Err(ParserError::BadType(display_yaml(value), value.into(), YamlType::Real))
+fn parse_shape(value: &Yaml, at: &At) -> ParserResult<SdfNode> {
+    let shape = parse_sdf_node(value, at)?;
+    if shape.stack_depth() > SDF_STACK_SIZE {
+        return Err(at.bad_value(display_yaml(value), &format!("shape is nested deeper than {} levels", SDF_STACK_SIZE)));
     }
+    Ok(shape)
 }
 
-fn parse_vector(value: &Yaml) -> ParserResult<Vec<f32>> {
-    let arr = value.as_vec().ok_or(ParserError::BadType(display_yaml(value), value.into(), YamlType::Array))?;
-    let mut v_out = Vec::new();
-    for v in arr {
-        v_out.push(parse_real(v)?);
+fn parse_sdf_node(value: &Yaml, at: &At) -> ParserResult<SdfNode> {
+    let node_hash = at.hash(value)?;
+    if node_hash.len() != 1 {
+        return Err(at.bad_value(display_yaml(value), "a shape is a single primitive or operation"));
     }
-    Ok(v_out)
+    let (name_str, args, args_at) = at.entries(value)?.remove(0);
+
+    match name_str {
+        "union" | "intersection" | "difference" => {
+            let combine = match name_str {
+                "union" => SdfNode::Union,
+                "intersection" => SdfNode::Intersection,
+                _ => SdfNode::Difference,
+            };
+            fold_sdf_operands(args, &args_at, combine)
+        }
+        "smooth_union" | "smooth_intersection" | "smooth_difference" => {
+            let mut k = None;
+            let mut operands = None;
+            for (property, value, value_at) in args_at.entries(args)? {
+                match property {
+                    "k" => {
+                        let x = parse_real(value, &value_at)?;
+                        if x <= 0.0 {
+                            return Err(value_at.bad_value(x.to_string(), "blend radius must be positive"));
+                        }
+                        k = Some(x);
+                    }
+                    "of" => { operands = Some((value, value_at)); }
+                    _ => { return Err(args_at.bad_key(property)); },
+                }
+            }
+            let k = k.ok_or_else(|| args_at.missing_key("k"))?;
+            let (operands, operands_at) = operands.ok_or_else(|| args_at.missing_key("of"))?;
+            match name_str {
+                "smooth_union" => fold_sdf_operands(operands, &operands_at, |a, b| SdfNode::SmoothUnion(a, b, k)),
+                "smooth_intersection" => fold_sdf_operands(operands, &operands_at, |a, b| SdfNode::SmoothIntersection(a, b, k)),
+                _ => fold_sdf_operands(operands, &operands_at, |a, b| SdfNode::SmoothDifference(a, b, k)),
+            }
+        }
+        "sphere" | "box" | "round_box" | "torus" | "glsl" => parse_sdf_primitive(name_str, args, &args_at),
+        _ => Err(at.bad_key(name_str)),
+    }
+}
+
+/// Combines a list of shapes left to right, `[a, b, c]` becomes `(a op b) op c`
+fn fold_sdf_operands<F>(value: &Yaml, at: &At, combine: F) -> ParserResult<SdfNode>
+where
+    F: Fn(Box<SdfNode>, Box<SdfNode>) -> SdfNode
+{
+    let operands = at.items(value)?;
+    if operands.len() < 2 {
+        return Err(at.bad_value(display_yaml(value), "operations need at least two shapes"));
+    }
+
+    let mut shape = parse_sdf_node(operands[0].0, &operands[0].1)?;
+    for (operand, operand_at) in &operands[1..] {
+        shape = combine(Box::new(shape), Box::new(parse_sdf_node(operand, operand_at)?));
+    }
+    Ok(shape)
 }
 
-fn parse_orientation(value: &Yaml) -> ParserResult<(f32, f32, f32)> {
-    let mut head: f32 = 0.0; 
-    let mut pitch: f32 = 0.0; 
-    let mut roll: f32 = 0.0; 
+fn parse_sdf_primitive(name: &str, value: &Yaml, at: &At) -> ParserResult<SdfNode> {
+    let mut pos = Vector3::<f32>::new(0.0, 0.0, 0.0);
+    let mut size = Vector3::<f32>::new(1.0, 1.0, 1.0);
+    let mut radius: f32 = if name == "round_box" { 0.1 } else { 1.0 };
+    let mut major: f32 = 1.0;
+    let mut minor: f32 = 0.25;
+    let mut code: Option<String> = None;
+    let mut bound: f32 = 1.0;
 
-    let orient_hash = value.as_hash().ok_or(ParserError::BadType(display_yaml(value), value.into(), YamlType::Hash))?;    
-    for (property, value) in orient_hash.iter() {
-        let property_str = property
-                           .as_str()
-                           .ok_or(ParserError::BadType(display_yaml(property), property.into(), YamlType::String))?;
-        match property_str {
-            "head" => { head = parse_real(value)?; }
-            "pitch" => { pitch = parse_real(value)?; }
-            "roll" => { roll = parse_real(value)?; }
-            _ => { return Err(ParserError::BadKey(property_str.to_owned())); },
+    for (property, value, value_at) in at.entries(value)? {
+        match (name, property) {
+            (_, "pos") => { pos = parse_vector3(value, &value_at)?; }
+            ("sphere", "radius") | ("round_box", "radius") => { radius = parse_real(value, &value_at)?; }
+            ("box", "size") | ("round_box", "size") => { size = parse_vector3(value, &value_at)?; }
+            ("torus", "major") => { major = parse_real(value, &value_at)?; }
+            ("torus", "minor") => { minor = parse_real(value, &value_at)?; }
+            ("glsl", "code") => { code = Some(value_at.str(value)?.to_owned()); }
+            ("glsl", "bound") => { bound = parse_real(value, &value_at)?; }
+            _ => { return Err(at.bad_key(property)); },
         }
     }
-    Ok((head, pitch, roll))
+
+    Ok(match name {
+        "sphere" => SdfNode::Sphere { pos, radius },
+        "box" => SdfNode::Box { pos, size },
+        "round_box" => SdfNode::RoundBox { pos, size, radius },
+        "torus" => SdfNode::Torus { pos, major, minor },
+        _ => SdfNode::Custom {
+            pos,
+            code: code.ok_or_else(|| at.missing_key("code"))?,
+            bound,
+            id: 0,
+        },
+    })
+}
+
+fn parse_vector3(value: &Yaml, at: &At) -> ParserResult<Vector3<f32>> {
+    let v = parse_vector(value, at)?;
+    if v.len() != 3 {
+        return Err(at.bad_value(display_yaml(value), "must be a 3-component vector"));
+    }
+    Ok(Vector3::new(v[0], v[1], v[2]))
+}
+
+/// A real, an integer or an expression like `"pi/2"` or `"$radius * 2"`
+fn parse_real(value: &Yaml, at: &At) -> ParserResult<f32> {
+    match value {
+        Yaml::Real(s) => match f32::from_str(s.as_str()) {
+            Ok(x) if x.is_finite() => Ok(x),
+            _ => Err(at.bad_value(s.clone(), "value is too large for a float")),
+        },
+        Yaml::Integer(i) => Ok(*i as f32),
+        Yaml::String(s) => expr::evaluate(s, at.variables).map_err(|e| at.bad_expression(s, e)),
+        _ => Err(at.bad_type(value, YamlType::Real)),
+    }
+}
+
+fn parse_vector(value: &Yaml, at: &At) -> ParserResult<Vec<f32>> {
+    let mut v_out = Vec::new();
+    for (v, v_at) in at.items(value)? {
+        v_out.push(parse_real(v, &v_at)?);
+    }
+    Ok(v_out)
+}
+
+fn parse_orientation(value: &Yaml, at: &At) -> ParserResult<(f32, f32, f32)> {
+    let mut v = [0.0; 3];
+    parse_reals(value, at, &["head", "pitch", "roll"], &mut v)?;
+    Ok((v[0], v[1], v[2]))
 }
 
-fn parse_material(value: &Yaml) -> ParserResult<Material> {
-    let material_hash = value.as_hash().ok_or(ParserError::BadType(display_yaml(value), value.into(), YamlType::Hash))?;    
+fn parse_material(value: &Yaml, at: &At) -> ParserResult<Material> {
     let mut m = define_material!(1.0);
 
-    for (property, value) in material_hash.iter() {
-        let property_str = property
-                           .as_str()
-                           .ok_or(ParserError::BadType(display_yaml(property), property.into(), YamlType::String))?;
-        match property_str {
-            "reflectance" => { m.reflectance = parse_real(value)?; }
-            _ => { return Err(ParserError::BadKey(property_str.to_owned())); },
+    for (property, value, value_at) in at.entries(value)? {
+        match property {
+            "reflectance" => { m.reflectance = parse_real(value, &value_at)?; }
+            "roughness" => {
+                m.roughness = parse_real(value, &value_at)?;
+                if !(0.0..=1.0).contains(&m.roughness) {
+                    return Err(value_at.bad_value(display_yaml(value), "roughness goes from 0 to 1"));
+                }
+            }
+            _ => { return Err(at.bad_key(property)); },
         }
     }
     Ok(m)
 
 }
 
-fn parse_color(value: &Yaml) -> ParserResult<Color> {
+fn parse_materials(node: &Yaml, at: &At, errors: &mut Vec<ParserError>) -> Vec<(String, Material)> {
+    let mut materials = Vec::new();
+    for (name, value, value_at) in collect(at.entries(node), errors).unwrap_or_default() {
+        if let Some(material) = collect(parse_material(value, &value_at), errors) {
+            materials.push((name.to_owned(), material));
+        }
+    }
+    materials
+}
+
+/// Either the name of a material or an inline material
+fn parse_model_material(value: &Yaml, at: &At, materials: &Materials) -> ParserResult<Material> {
+    match value.as_str() {
+        Some(name) => materials
+                      .iter()
+                      .find(|(n, _)| n == name)
+                      .map(|(_, m)| m.clone())
+                      .ok_or_else(|| at.bad_value(format!("\"{}\"", name), "no material has this name")),
+        None => parse_material(value, at),
+    }
+}
+
+/// Image paths are relative to `dir`, the directory of the scene file
+fn parse_environment(node: &Yaml, at: &At, dir: Option<&Path>) -> ParserResult<Environment> {
+    let mut environment = Environment::new();
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "skybox" => { environment.skybox = parse_skybox(value, &value_at, dir)?; }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
+    Ok(environment)
+}
+
+/// `debug`, a color, or a hash with one of `gradient`, `faces` and
+/// `equirectangular`
+fn parse_skybox(value: &Yaml, at: &At, dir: Option<&Path>) -> ParserResult<Skybox> {
+    if value.as_str() == Some("debug") {
+        return Ok(Skybox::Debug);
+    }
+    let skybox_hash = match value.as_hash() {
+        Some(h) => h,
+        None => { return Ok(Skybox::Color(parse_color(value, at)?)); }
+    };
+    if skybox_hash.len() != 1 {
+        return Err(at.bad_value(display_yaml(value), "a skybox is either a gradient, faces or an equirectangular image"));
+    }
+
+    let (kind_str, args, args_at) = at.entries(value)?.remove(0);
+    let parse_path = |path: &Yaml, path_at: &At| -> ParserResult<PathBuf> {
+        let name = path_at.str(path)?;
+        Ok(match dir {
+            Some(dir) => dir.join(name),
+            None => PathBuf::from(name),
+        })
+    };
+
+    match kind_str {
+        "gradient" => {
+            let mut top = col!(white);
+            let mut bottom = col!(black);
+            for (property, value, value_at) in args_at.entries(args)? {
+                match property {
+                    "top" => { top = parse_color(value, &value_at)?; }
+                    "bottom" => { bottom = parse_color(value, &value_at)?; }
+                    _ => { return Err(args_at.bad_key(property)); },
+                }
+            }
+            Ok(Skybox::Gradient { top, bottom })
+        }
+        "faces" => {
+            let list = args_at.items(args)?;
+            if list.len() != 6 {
+                return Err(args_at.bad_value(display_yaml(args), "a cube has 6 faces: +x, -x, +y, -y, +z, -z"));
+            }
+            let paths = list.iter().map(|(path, path_at)| parse_path(path, path_at)).collect::<ParserResult<Vec<PathBuf>>>()?;
+            Ok(Skybox::Faces(Box::new(paths.try_into().unwrap())))
+        }
+        "equirectangular" => Ok(Skybox::Equirectangular(parse_path(args, &args_at)?)),
+        "sky" => Ok(Skybox::Sky(parse_sky(args, &args_at)?)),
+        _ => Err(at.bad_key(kind_str)),
+    }
+}
+
+/// A hash of reals with the given keys, missing keys keep their default
+fn parse_reals(node: &Yaml, at: &At, keys: &[&str], values: &mut [f32]) -> ParserResult<()> {
+    for (property, value, value_at) in at.entries(node)? {
+        let i = keys.iter().position(|k| *k == property).ok_or_else(|| at.bad_key(property))?;
+        values[i] = parse_real(value, &value_at)?;
+    }
+    Ok(())
+}
+
+/// The sun is placed by `sun: {elevation, azimuth}` or by `time: {hour,
+/// latitude, day, hours_per_second}`
+fn parse_sky(node: &Yaml, at: &At) -> ParserResult<Sky> {
+    let mut sun = None;
+    let mut turbidity = None;
+    let mut intensity = None;
+
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
+            "sun" | "time" => {
+                if sun.is_some() {
+                    return Err(at.bad_value(property.to_owned(), "sun and time are exclusive"));
+                }
+                sun = Some(if property == "sun" {
+                    let mut v = [0.5, 0.0];
+                    parse_reals(value, &value_at, &["elevation", "azimuth"], &mut v)?;
+                    SunPosition::Angles { elevation: v[0], azimuth: v[1] }
+                } else {
+                    let mut v = [12.0, 0.8, 172.0, 0.0];
+                    parse_reals(value, &value_at, &["hour", "latitude", "day", "hours_per_second"], &mut v)?;
+                    SunPosition::Time { hour: v[0], latitude: v[1], day: v[2], hours_per_second: v[3] }
+                });
+            }
+            "turbidity" => {
+                let t = parse_real(value, &value_at)?;
+                if t < 1.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "turbidity is at least 1"));
+                }
+                turbidity = Some(t);
+            }
+            "intensity" => {
+                let x = parse_real(value, &value_at)?;
+                if x < 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "must not be negative"));
+                }
+                intensity = Some(x);
+            }
+            _ => { return Err(at.bad_key(property)); },
+        }
+    }
+
+    let sun = sun.ok_or_else(|| at.missing_key("sun"))?;
+    let mut sky = Sky::new(sun);
+    sky.turbidity = turbidity.unwrap_or(sky.turbidity);
+    sky.sun_intensity = intensity.unwrap_or(sky.sun_intensity);
+    Ok(sky)
+}
+
+/// A float, an integer or a vector of 2 to 4 floats for every name
+fn parse_uniforms(node: &Yaml, at: &At, errors: &mut Vec<ParserError>) -> Vec<(String, Uniform)> {
+    let mut uniforms = Vec::new();
+    for (name, value, value_at) in collect(at.entries(node), errors).unwrap_or_default() {
+        if let Some(uniform) = collect(parse_uniform(value, &value_at), errors) {
+            uniforms.push((name.to_owned(), uniform));
+        }
+    }
+    uniforms
+}
+
+fn parse_uniform(value: &Yaml, at: &At) -> ParserResult<Uniform> {
+    Ok(match value {
+        Yaml::Integer(i) => Uniform::Int(
+            i32::try_from(*i).map_err(|_| at.bad_value(i.to_string(), "integer is too large"))?
+        ),
+        Yaml::Real(_) | Yaml::String(_) => Uniform::Float(parse_real(value, at)?),
+        other => {
+            let v = parse_vector(other, at)?;
+            match v.len() {
+                2 => Uniform::Vec2([v[0], v[1]]),
+                3 => Uniform::Vec3([v[0], v[1], v[2]]),
+                4 => Uniform::Vec4([v[0], v[1], v[2], v[3]]),
+                _ => { return Err(at.bad_value(display_yaml(value), "uniform vectors have 2 to 4 components")); }
+            }
+        }
+    })
+}
+
+fn parse_color(value: &Yaml, at: &At) -> ParserResult<Color> {
     match value {
-        Yaml::String(col) => Color::from_str(col.as_str()).or(Err(ParserError::BadValue(display_yaml(value), String::from("color is invalid")))),
+        Yaml::String(col) => Color::from_str(col.as_str()).map_err(|_| at.bad_value(display_yaml(value), "color is invalid")),
         other => {
-            let v = parse_vector(other)?;
+            let v = parse_vector(other, at)?;
             if v.len() != 3 {
-                Err(ParserError::BadValue(display_yaml(value), String::from("color must be a 3-component vector")))
+                Err(at.bad_value(display_yaml(value), "color must be a 3-component vector"))
             } else {
                 Ok(Color {
                     r: v[0],
@@ -315,35 +1541,121 @@ fn parse_color(value: &Yaml) -> ParserResult<Color> {
     }
 }
 
-fn parse_camera(node: &Yaml) -> ParserResult<(Vector3<f32>, f32, f32)> {
-    let mut position = Vector3::<f32>::new(0.0, 0.0, 0.0);
-    let mut pitch = 0.0;
-    let mut yaw = 0.0;
+/// Adds a camera, whose name must not be taken
+/// A camera named like one in `included` replaces it, once
+fn add_camera(
+    cameras: &mut Option<Cameras>,
+    name: &str,
+    camera: Camera,
+    included: &mut Vec<String>,
+    at: &At,
+    errors: &mut Vec<ParserError>,
+) {
+    let cameras = match cameras.as_mut() {
+        Some(cameras) => cameras,
+        None => {
+            *cameras = Some(Cameras::new(name, camera));
+            return;
+        }
+    };
+    match cameras.index_of(name) {
+        Some(index) if included.iter().any(|n| n == name) => {
+            included.retain(|n| n != name);
+            *cameras.get_mut(index).unwrap() = camera;
+        }
+        Some(_) => errors.push(at.bad_value(format!("\"{}\"", name), "camera names must be unique")),
+        None => { cameras.add(name, camera); }
+    }
+}
+
+fn parse_cameras(
+    node: &Yaml,
+    at: &At,
+    cameras: &mut Option<Cameras>,
+    included: &mut Vec<String>,
+    errors: &mut Vec<ParserError>,
+) {
+    let list = match collect(at.items(node), errors) {
+        Some(list) => list,
+        None => return,
+    };
+
+    for (entry, entry_at) in list.iter() {
+        let (name, camera) = match collect(parse_camera(entry, entry_at), errors) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        let name = match name {
+            Some(name) => name,
+            None => {
+                errors.push(entry_at.missing_key("name"));
+                continue;
+            }
+        };
+        add_camera(cameras, name, camera, included, &entry_at.key("name"), errors);
+    }
+    if list.is_empty() {
+        errors.push(at.bad_value(display_yaml(node), "at least one camera is needed"));
+    }
+}
 
-    let camera_hash = node.as_hash().ok_or(ParserError::BadType(display_yaml(node), node.into(), YamlType::Hash))?;
+/// Returns the optional name along with the camera
+fn parse_camera<'y>(node: &'y Yaml, at: &At) -> ParserResult<(Option<&'y str>, Camera)> {
+    let mut name = None;
+    let mut camera = Camera::new();
+    let mut fov_given = false;
 
-    for (property, value) in camera_hash.iter() {
-        let property_str = property
-                           .as_str()
-                           .ok_or(ParserError::BadType(display_yaml(property), property.into(), YamlType::String))?;
-        match property_str {
+    for (property, value, value_at) in at.entries(node)? {
+        match property {
             "position" => {
-                let v = parse_vector(value)?;
+                let v = parse_vector(value, &value_at)?;
                 if v.len() != 3 {
-                    return Err(ParserError::BadValue(display_yaml(value), String::from("camera position must be a 3-component vector")));
+                    return Err(value_at.bad_value(display_yaml(value), "camera position must be a 3-component vector"));
+                } else {
+                    camera.tf.x = v[0];
+                    camera.tf.y = v[1];
+                    camera.tf.z = v[2];
+                }
+            }
+            "name" => { name = Some(value_at.str(value)?); }
+            "pitch" => { camera.tf.pitch = parse_real(value, &value_at)?; }
+            "yaw" => { camera.tf.head = parse_real(value, &value_at)?; }
+            "projection" => {
+                let s = value_at.str(value)?;
+                camera.projection = s.try_into().map_err(|_| value_at.bad_value(s.to_owned(), "projection does not exist"))?;
+            }
+            "fov" | "focal_length" => {
+                if fov_given {
+                    return Err(at.bad_value(property.to_owned(), "fov and focal_length are exclusive"));
+                }
+                fov_given = true;
+                let x = parse_real(value, &value_at)?;
+                if x <= 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "must be positive"));
+                }
+                if property == "fov" {
+                    camera.fov = x;
                 } else {
-                    position.x = v[0];
-                    position.y = v[1];
-                    position.z = v[2];
+                    camera.set_focal_length(x);
+                }
+            }
+            "aperture" => {
+                camera.aperture = parse_real(value, &value_at)?;
+                if camera.aperture < 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "must not be negative"));
+                }
+            }
+            "focus_distance" => {
+                camera.focus_distance = parse_real(value, &value_at)?;
+                if camera.focus_distance <= 0.0 {
+                    return Err(value_at.bad_value(display_yaml(value), "must be positive"));
                 }
             }
-            "pitch" => { pitch = parse_real(value)?; }
-            "yaw" => { yaw = parse_real(value)?; }
-            _ => { return Err(ParserError::BadKey(property_str.to_owned())); },
+            _ => { return Err(at.bad_key(property)); },
         }
     }
 
-    Ok((position, pitch, yaw))
+    Ok((name, camera))
 }
 
 #[cfg(test)]
@@ -387,12 +1699,12 @@ mod tests {
         use crate::material::Material;
         use crate::geospace::Transform;
         use crate::color::Color;
-        use crate::models::{ModelManager, ModelProperty, ModelType};
+        use crate::models::{ModelId, ModelProperty, ModelType};
         use std::fs::read_to_string;
         use std::str::FromStr;
         
         let source = read_to_string(TESTFILE).expect("file should exists");
-        let (models, _)  = parse_scene(source.as_str()).expect("parse is successfull");
+        let models = parse_scene(source.as_str()).expect("parse is successfull").models;
         assert_eq!(models.len(), 2);
 
         let sphere = ModelProperty {
@@ -409,9 +1721,521 @@ mod tests {
         };
         
 
-        assert_eq!(models[&0], sphere);
-        assert_eq!(models[&1], plane);
+        let ids: Vec<ModelId> = models.iter().map(|(id, _)| id).collect();
+        assert_eq!(models[&ids[0]], sphere);
+        assert_eq!(models[&ids[1]], plane);
+    }
+
+    #[test]
+    fn verify_parse_sdf() {
+        use super::parse_scene;
+        use crate::models::ModelType;
+        use crate::models::sdf::SdfNode;
+        use cgmath::Vector3;
+
+        let source = "
+scene:
+  models:
+    - type: sdf
+      shape:
+        smooth_union:
+          k: 0.5
+          of:
+            - sphere: {radius: 1.0}
+            - torus: {major: 2.0, minor: 0.5, pos: [0.0, 1.0, 0.0]}
+            - glsl: {code: \"length(p.xy) - 0.5\", bound: 3.0}
+";
+        let models = parse_scene(source).expect("parse is successfull").models;
+        let origin = Vector3::new(0.0, 0.0, 0.0);
+        let expected = SdfNode::SmoothUnion(
+            Box::new(SdfNode::SmoothUnion(
+                Box::new(SdfNode::Sphere { pos: origin, radius: 1.0 }),
+                Box::new(SdfNode::Torus { pos: Vector3::new(0.0, 1.0, 0.0), major: 2.0, minor: 0.5 }),
+                0.5,
+            )),
+            Box::new(SdfNode::Custom { pos: origin, code: String::from("length(p.xy) - 0.5"), bound: 3.0, id: 0 }),
+            0.5,
+        );
+        let (_, model) = models.iter().next().unwrap();
+        assert_eq!(model.t, ModelType::Sdf(expected));
+        assert!(models.sdf_glsl().contains("case 0: return length(p.xy) - 0.5;"));
+
+        let bad = "
+scene:
+  models:
+    - type: sphere
+      shape:
+        sphere: {radius: 1.0}
+";
+        assert!(parse_scene(bad).is_err());
     }
-}
 
+    #[test]
+    fn verify_parse_instances() {
+        use super::parse_scene;
+
+        let source = "
+scene:
+  models:
+    - type: plane
+    - instances:
+        prototype:
+          type: sphere
+          color: red
+        transforms:
+          - pos: [0.0, 2.0, 0.0]
+        grid: {count: [3, 1, 2], spacing: [2.0, 1.0, 2.0]}
+        scatter: {count: 4, seed: 3, min: [-1.0, 0.0, -1.0], max: [1.0, 0.0, 1.0]}
+";
+        let models = parse_scene(source).expect("parse is successfull").models;
+        assert_eq!(models.len(), 2);
+
+        let instances = models.create_instance_buffer();
+        assert_eq!(instances.len(), 1 + 6 + 4);
+        assert_eq!(instances[0].position, [0.0, 2.0, 0.0]);
+        // the prototype is only drawn through its instances
+        assert_eq!(models.primitives().iter().flatten().count(), 1 + 11);
+
+        let flat = "
+scene:
+  models:
+    - instances:
+        prototype: {type: sphere}
+        transforms: [{scale: 1}, {scale: 0}]
+";
+        let errors = parse_scene(flat).expect_err("zero scale").errors;
+        assert_eq!(errors[0].path, "scene.models[0].instances.transforms[1].scale");
+    }
 
+    #[test]
+    fn verify_parse_lights() {
+        use super::parse_scene;
+        use crate::lights::LightType;
+
+        let source = "
+scene:
+  lights:
+    - type: point
+      pos: [0.0, 4.0, 0.0]
+      intensity: 20.0
+    - type: spot
+      direction: [0.0, -1.0, 0.0]
+      inner: 0.2
+      outer: 0.4
+      color: [1.0, 0.5, 0.0]
+    - type: rect
+      width: 2.0
+";
+        let lights = parse_scene(source).expect("parse is successfull").lights;
+        assert_eq!(lights.len(), 3);
+        let parsed: Vec<_> = lights.iter().map(|(_, l)| l).collect();
+        assert_eq!(parsed[0].intensity, 20.0);
+        assert_eq!(parsed[1].t, LightType::Spot { inner: 0.2, outer: 0.4 });
+        assert_eq!(parsed[2].t, LightType::Rect { width: 2.0, height: 1.0 });
+
+        // sizes only apply to the light types that have them
+        let bad = "
+scene:
+  lights:
+    - type: point
+      radius: 1.0
+";
+        assert!(parse_scene(bad).is_err());
+        let sharp = "
+scene:
+  lights:
+    - type: spot
+      inner: 0.4
+      outer: 0.4
+";
+        assert_eq!(parse_scene(sharp).expect_err("equal angles").errors[0].path, "scene.lights[0].inner");
+    }
+
+    #[test]
+    fn verify_parse_camera() {
+        use super::parse_scene;
+        use crate::camera::Projection;
+
+        let source = "
+scene:
+  camera:
+    position: [0.0, 1.0, 5.0]
+    projection: fisheye
+    focal_length: 18.0
+    aperture: 0.1
+    focus_distance: 3.0
+";
+        let cameras = parse_scene(source).expect("parse is successfull").cameras;
+        let camera = cameras.active();
+        assert_eq!(cameras.active_name(), "default");
+        assert_eq!(camera.tf.y, 1.0);
+        assert_eq!(camera.projection, Projection::Fisheye);
+        assert!((camera.fov - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+        assert_eq!(camera.aperture, 0.1);
+        assert_eq!(camera.focus_distance, 3.0);
+
+        let bad = "
+scene:
+  camera:
+    fov: 1.0
+    focal_length: 35.0
+";
+        assert!(parse_scene(bad).is_err());
+    }
+
+    #[test]
+    fn verify_parse_cameras() {
+        use super::parse_scene;
+
+        let source = "
+scene:
+  active_camera: side
+  cameras:
+    - name: front
+      position: [0.0, 0.0, 10.0]
+    - name: side
+      position: [10.0, 0.0, 0.0]
+      yaw: 1.5
+";
+        let cameras = parse_scene(source).expect("parse is successfull").cameras;
+        assert_eq!(cameras.len(), 2);
+        assert_eq!(cameras.active_name(), "side");
+        assert_eq!(cameras.active().tf.head, 1.5);
+
+        let unknown = "
+scene:
+  active_camera: top
+  camera:
+    position: [0.0, 0.0, 10.0]
+";
+        assert!(parse_scene(unknown).is_err());
+
+        let duplicate = "
+scene:
+  cameras:
+    - name: front
+    - name: front
+";
+        assert!(parse_scene(duplicate).is_err());
+
+        let both = "
+scene:
+  camera: {}
+  cameras:
+    - name: front
+";
+        let errors = parse_scene(both).expect_err("exclusive").errors;
+        assert_eq!(errors[0].path, "scene.cameras");
+        let location = errors[0].location.as_ref().unwrap();
+        assert_eq!((location.line, location.col), (4, 2));
+    }
+
+    #[test]
+    fn every_error_is_located() {
+        use super::*;
+
+        let source = "
+scene:
+  models:
+    - type: sphere
+      color: [1.0, 0.0, 0.0]
+    - type: sphere
+      transform:
+        pos: [0.0, true, 0.0]
+  lights:
+    - type: point
+      brightness: 2.0
+";
+        let mut errors = parse_scene(source).expect_err("two errors").errors;
+        assert_eq!(errors.len(), 2);
+
+        assert_eq!(errors[0].path, "scene.models[1].transform.pos[1]");
+        let location = errors[0].location.as_ref().unwrap();
+        assert_eq!((location.line, location.col, location.len), (8, 19, 4));
+        assert!(matches!(errors[0].kind, ParserErrorKind::BadType(_, YamlType::Boolean, YamlType::Real)));
+
+        // unknown keys point at the key
+        assert_eq!(errors[1].path, "scene.lights[0].brightness");
+        let location = errors[1].location.as_ref().unwrap();
+        assert_eq!((location.line, location.col, location.len), (11, 6, 10));
+
+        errors[1].file = Some(String::from("scene.yaml"));
+        let rendered = errors[1].render();
+        assert_eq!(rendered, "\
+error: unknown key brightness
+  --> scene.yaml:11:7
+   |
+11 |       brightness: 2.0
+   |       ^^^^^^^^^^
+   = note: in scene.lights[0].brightness
+");
+
+        let errors = parse_scene("scene: [1.0, 2.0").expect_err("not yaml").errors;
+        assert!(matches!(errors[0].kind, ParserErrorKind::YamlError(_)));
+        assert_eq!(errors[0].location.as_ref().unwrap().line, 2);
+    }
+
+    #[test]
+    fn numbers_can_be_integers_or_expressions() {
+        use super::*;
+
+        let source = "
+scene:
+  defs:
+    variables:
+      radius: 2
+      diameter: $radius * 2
+  models:
+    - type: sphere
+      transform:
+        pos: [0, 1, \"-$diameter\"]
+        scale: $radius
+        orientation:
+          head: pi/2
+";
+        let scene = parse_scene(source).expect("parse is successfull");
+        let tf = &scene.models.iter().next().unwrap().1.tf;
+        assert_eq!((tf.x, tf.y, tf.z), (0.0, 1.0, -4.0));
+        assert_eq!(tf.scale, 2.0);
+        assert_eq!(tf.head, std::f32::consts::FRAC_PI_2);
+
+        let source = "
+scene:
+  models:
+    - type: sphere
+      transform:
+        pos: [0, \"2 * $heigth\", 0]
+";
+        let errors = parse_scene(source).expect_err("unknown variable").errors;
+        assert!(matches!(errors[0].kind, ParserErrorKind::BadExpression(_, _)));
+        let location = errors[0].location.as_ref().unwrap();
+        assert_eq!((location.line, location.col, location.len), (6, 22, 7));
+
+        let errors = parse_scene("scene: {models: [{type: sphere, transform: {scale: 1e400}}]}").expect_err("infinite").errors;
+        assert_eq!(errors[0].path, "scene.models[0].transform.scale");
+    }
+
+    #[test]
+    fn templates_aliases_and_merges() {
+        use super::*;
+
+        let source = "
+scene:
+  defs:
+    templates:
+      ball:
+        type: sphere
+        transform:
+          scale: 2
+      red_ball:
+        use: ball
+        color: red
+  lights:
+    - &lamp
+      type: point
+      pos: [0, 5, 0]
+    - <<: *lamp
+      intensity: 3
+    - *lamp
+  models:
+    - use: red_ball
+      transform:
+        pos: [1, 0, 0]
+    - use: red_ball
+      color: blue
+";
+        let scene = parse_scene(source).expect("parse is successfull");
+        let models: Vec<_> = scene.models.iter().map(|(_, m)| m).collect();
+        assert_eq!((models[0].tf.x, models[0].tf.scale), (1.0, 2.0));
+        assert_eq!(models[0].color, col!(red));
+        assert_eq!(models[1].color, col!(blue));
+        assert!(matches!(models[1].t, ModelType::Sphere));
+
+        let lights: Vec<_> = scene.lights.iter().map(|(_, l)| l).collect();
+        assert_eq!(lights.len(), 3);
+        assert_eq!(lights[1].position, lights[0].position);
+        assert_eq!(lights[1].intensity, 3.0);
+
+        // errors in a template point at it, once
+        let source = "
+scene:
+  defs:
+    templates:
+      a:
+        use: b
+        colour: red
+      b:
+        use: a
+      c:
+        colour: red
+  models:
+    - use: a
+    - use: c
+    - use: c
+";
+        let errors = parse_scene(source).expect_err("bad templates").errors;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].path, "scene.defs.templates.b.use");
+        assert!(errors[0].to_string().contains("templates can't use themselves"));
+        assert_eq!(errors[1].path, "scene.defs.templates.c.colour");
+        assert_eq!(errors[1].location.as_ref().unwrap().line, 11);
+    }
+
+    #[test]
+    fn includes_are_relative_and_checked_for_cycles() {
+        use super::*;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("hugengine-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        let write = |name: &str, source: &str| fs::write(dir.join(name), source).unwrap();
+        write("sky.hdr", "");
+        write("main.yaml", "
+scene:
+  include: [parts/walls.yaml]
+  camera:
+    position: [0, 0, 10]
+  active_camera: side
+  models:
+    - type: sphere
+      material: stone
+");
+        write("parts/walls.yaml", "
+scene:
+  include: ../props.yaml
+  environment:
+    skybox: {equirectangular: ../sky.hdr}
+  materials:
+    stone:
+      reflectance: 0.1
+  models:
+    - type: plane
+");
+        write("props.yaml", "
+scene:
+  cameras:
+    - name: side
+      position: [10, 0, 0]
+    - name: default
+  models:
+    - type: box
+");
+        let path = dir.join("main.yaml");
+        let scene = parse_scene_file(&fs::read_to_string(&path).unwrap(), &path).expect("parse is successfull");
+        assert_eq!(scene.models.len(), 3);
+        // the camera of the including file replaces the included one
+        assert_eq!(scene.cameras.len(), 2);
+        assert_eq!(scene.cameras.active_name(), "side");
+        let default = scene.cameras.index_of(Cameras::DEFAULT_NAME).unwrap();
+        assert_eq!(scene.cameras.get(default).unwrap().tf.z, 10.0);
+        assert_eq!(scene.materials.len(), 1);
+        let files: Vec<_> = scene.files.iter().map(|f| f.canonicalize().unwrap()).collect();
+        let expected: Vec<_> = ["main.yaml", "parts/walls.yaml", "props.yaml", "sky.hdr"].iter().map(|f| dir.join(f).canonicalize().unwrap()).collect();
+        assert_eq!(files, expected);
+
+        // images are found from the file that names them, and saved
+        // relative to where the scene goes
+        assert_eq!(scene.environment.skybox.paths(), vec![&dir.join("parts/../sky.hdr")]);
+        scene.save(dir.join("parts/saved.yaml")).unwrap();
+        let saved = crate::scene::Scene::load(dir.join("parts/saved.yaml")).expect("saved scene is valid");
+        assert_eq!(saved.environment.skybox.paths()[0].canonicalize().unwrap(), dir.join("sky.hdr").canonicalize().unwrap());
+
+        // the error is reported in the file that closes the cycle
+        write("props.yaml", "
+scene:
+  include: main.yaml
+  cameras:
+    - name: side
+");
+        let errors = parse_scene_file(&fs::read_to_string(&path).unwrap(), &path).expect_err("cycle").errors;
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0].kind, ParserErrorKind::BadInclude(_, _)));
+        assert!(errors[0].file.as_ref().unwrap().ends_with("props.yaml"));
+        assert_eq!(errors[0].path, "scene.include");
+    }
+
+    #[test]
+    fn generated_models() {
+        use super::*;
+        use cgmath::InnerSpace;
+        use std::fs::read_to_string;
+
+        let source = read_to_string("scenes/spheres.yaml").expect("file should exists");
+        let scene = parse_scene(&source).expect("parse is successfull");
+        assert_eq!(scene.models.len(), 201);
+        let again = parse_scene(&source).unwrap();
+        assert!(scene.models.iter().zip(again.models.iter()).all(|((_, a), (_, b))| a == b));
+
+        let source = "
+scene:
+  defs:
+    templates:
+      ball: {type: sphere, color: red, transform: {pos: [0, 1, 0]}}
+  models:
+    - generate:
+        ring: {count: 8, radius: 4}
+        model: {use: ball}
+        scale: [0.5, 1]
+        roughness: 0.2
+    - generate:
+        grid: {count: [2, 1, 2]}
+        model: {type: box}
+    - generate:
+        spiral: {count: 3, radius: 2, height: 1}
+        model: {type: sphere}
+        colors: [blue]
+";
+        let scene = parse_scene(source).expect("parse is successfull");
+        let models: Vec<_> = scene.models.iter().map(|(_, m)| m).collect();
+        assert_eq!(models.len(), 15);
+        assert!(models[..8].iter().all(|m| m.tf.y == 1.0 && m.tf.scale >= 0.5 && m.tf.scale < 1.0));
+        assert!(models[..8].iter().all(|m| m.color == col!(red) && m.material.roughness == 0.2));
+        assert!((models[14].tf.position() - Vector3::new(2.0, 1.0, 0.0)).magnitude() < 1e-5);
+        assert_eq!(models[14].color, col!(blue));
+
+        let crowded = "
+scene:
+  models:
+    - generate:
+        scatter: {count: 10, bounds: [[0, 0, 0], [1, 0, 1]], min_distance: 2}
+        model: {type: sphere}
+";
+        let errors = parse_scene(crowded).expect_err("no room").errors;
+        assert_eq!(errors[0].path, "scene.models[0].generate.scatter.min_distance");
+
+        let huge = "
+scene:
+  models:
+    - generate:
+        scatter: {count: 200000000}
+        model: {type: sphere}
+    - generate:
+        grid: {count: [1000, 1000, 1000]}
+        model: {type: sphere}
+";
+        let errors = parse_scene(huge).expect_err("too many").errors;
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].path, "scene.models[0].generate.scatter.count");
+        assert_eq!(errors[1].path, "scene.models[1].generate.grid.count");
+
+        let out_of_range = "
+scene:
+  models:
+    - generate:
+        scatter: {count: 2, scale: [-1, 1]}
+        model: {type: sphere}
+    - generate:
+        ring: {count: 2}
+        model: {type: sphere}
+        reflectance: [0.5, 2]
+    - generate:
+        scatter: {count: 2, min_distance: -1}
+        model: {type: sphere}
+";
+        let errors = parse_scene(out_of_range).expect_err("out of range").errors;
+        assert_eq!(errors[0].path, "scene.models[0].generate.scatter.scale");
+        assert_eq!(errors[1].path, "scene.models[1].generate.reflectance");
+        assert_eq!(errors[2].path, "scene.models[2].generate.scatter.min_distance");
+    }
+}
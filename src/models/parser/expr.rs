@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::f64::consts;
+
+/// Values of the `$name` variables of a scene
+pub type Variables = BTreeMap<String, f32>;
+
+/// What is wrong with an expression and where, in characters from its start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    pub message: String,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Evaluates arithmetic like `pi/2`, `2*sqrt(3)` or `$radius * 2`, with
+/// `+ - * / % ^`, parentheses, the constants `pi`, `tau` and `e` and a few
+/// functions
+pub fn evaluate(expression: &str, variables: &Variables) -> Result<f32, ExprError> {
+    let tokens = tokenize(expression)?;
+    let end = expression.chars().count();
+    let mut parser = Parser { tokens, next: 0, end, variables };
+    let value = parser.sum()?;
+    if let Some(token) = parser.tokens.get(parser.next) {
+        return Err(token.error("expected an operator"));
+    }
+    if !value.is_finite() || !(value as f32).is_finite() {
+        return Err(ExprError { message: String::from("value is too large for a float"), offset: 0, len: end.max(1) });
+    }
+    Ok(value as f32)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Number(f64),
+    Name(String),
+    Variable(String),
+    Symbol(char),
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: Kind,
+    offset: usize,
+    len: usize,
+}
+
+impl Token {
+    fn error(&self, message: &str) -> ExprError {
+        ExprError { message: message.to_owned(), offset: self.offset, len: self.len }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ExprError> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let kind = if c.is_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // an exponent, as in 1.5e-3
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let digits = if matches!(chars.get(i + 1), Some('+' | '-')) { i + 2 } else { i + 1 };
+                if matches!(chars.get(digits), Some(d) if d.is_ascii_digit()) {
+                    i = digits;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.parse() {
+                Ok(x) => Kind::Number(x),
+                Err(_) => return Err(ExprError { message: String::from("bad number"), offset: start, len: i - start }),
+            }
+        } else if c == '$' {
+            i += 1;
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            if i == start + 1 {
+                return Err(ExprError { message: String::from("expected a variable name after $"), offset: start, len: 1 });
+            }
+            Kind::Variable(chars[start + 1..i].iter().collect())
+        } else if is_name_char(c) {
+            while i < chars.len() && is_name_char(chars[i]) {
+                i += 1;
+            }
+            Kind::Name(chars[start..i].iter().collect())
+        } else if "+-*/%^(),".contains(c) {
+            i += 1;
+            Kind::Symbol(c)
+        } else {
+            return Err(ExprError { message: format!("unexpected character '{}'", c), offset: start, len: 1 });
+        };
+        tokens.push(Token { kind, offset: start, len: i - start });
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent, evaluating as it goes
+struct Parser<'v> {
+    tokens: Vec<Token>,
+    next: usize,
+    /// length of the expression, where errors about a missing end point
+    end: usize,
+    variables: &'v Variables,
+}
+
+impl<'v> Parser<'v> {
+    fn peek_symbol(&self) -> Option<char> {
+        match self.tokens.get(self.next) {
+            Some(Token { kind: Kind::Symbol(c), .. }) => Some(*c),
+            _ => None,
+        }
+    }
+
+    fn at_end(&self, message: &str) -> ExprError {
+        ExprError { message: message.to_owned(), offset: self.end, len: 1 }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ExprError> {
+        match self.tokens.get(self.next) {
+            Some(Token { kind: Kind::Symbol(c), .. }) if *c == symbol => {
+                self.next += 1;
+                Ok(())
+            }
+            Some(token) => Err(token.error(&format!("expected '{}'", symbol))),
+            None => Err(self.at_end(&format!("expected '{}'", symbol))),
+        }
+    }
+
+    fn sum(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek_symbol() {
+            self.next += 1;
+            let rhs = self.product()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, ExprError> {
+        let mut value = self.unary()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek_symbol() {
+            self.next += 1;
+            let start = self.next;
+            let rhs = self.unary()?;
+            if op == '*' {
+                value *= rhs;
+            } else if rhs == 0.0 {
+                return Err(self.span_error(start, "division by zero"));
+            } else if op == '/' {
+                value /= rhs;
+            } else {
+                value %= rhs;
+            }
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, ExprError> {
+        match self.peek_symbol() {
+            Some('-') => {
+                self.next += 1;
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.next += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    /// `^` binds tighter than a sign on its left and groups to the right
+    fn power(&mut self) -> Result<f64, ExprError> {
+        let start = self.next;
+        let base = self.atom()?;
+        if self.peek_symbol() == Some('^') {
+            self.next += 1;
+            let value = base.powf(self.unary()?);
+            if value.is_nan() {
+                return Err(self.span_error(start, "power is undefined here"));
+            }
+            return Ok(value);
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64, ExprError> {
+        let token = match self.tokens.get(self.next) {
+            Some(token) => token.clone(),
+            None => return Err(self.at_end("expected a value")),
+        };
+        self.next += 1;
+        match token.kind {
+            Kind::Number(x) => Ok(x),
+            Kind::Variable(ref name) => match self.variables.get(name) {
+                Some(x) => Ok(*x as f64),
+                None => Err(token.error(&format!("unknown variable ${}", name))),
+            },
+            Kind::Symbol('(') => {
+                let value = self.sum()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Kind::Name(ref name) if self.peek_symbol() == Some('(') => self.call(&token, name),
+            Kind::Name(ref name) => match name.as_str() {
+                "pi" => Ok(consts::PI),
+                "tau" => Ok(consts::TAU),
+                "e" => Ok(consts::E),
+                _ => Err(token.error(&format!("unknown constant {}", name))),
+            },
+            Kind::Symbol(_) => Err(token.error("expected a value")),
+        }
+    }
+
+    fn call(&mut self, token: &Token, name: &str) -> Result<f64, ExprError> {
+        let start = self.next - 1;
+        self.expect('(')?;
+        let mut args = Vec::new();
+        if self.peek_symbol() != Some(')') {
+            args.push(self.sum()?);
+            while self.peek_symbol() == Some(',') {
+                self.next += 1;
+                args.push(self.sum()?);
+            }
+        }
+        self.expect(')')?;
+
+        let value = match (name, &args[..]) {
+            ("sqrt", [x]) => x.sqrt(),
+            ("abs", [x]) => x.abs(),
+            ("sin", [x]) => x.sin(),
+            ("cos", [x]) => x.cos(),
+            ("tan", [x]) => x.tan(),
+            ("asin", [x]) => x.asin(),
+            ("acos", [x]) => x.acos(),
+            ("atan", [x]) => x.atan(),
+            ("atan2", [y, x]) => y.atan2(*x),
+            ("exp", [x]) => x.exp(),
+            ("ln", [x]) => x.ln(),
+            ("pow", [x, y]) => x.powf(*y),
+            ("floor", [x]) => x.floor(),
+            ("ceil", [x]) => x.ceil(),
+            ("round", [x]) => x.round(),
+            ("radians", [x]) => x.to_radians(),
+            ("degrees", [x]) => x.to_degrees(),
+            ("min", [x, rest @ ..]) if !rest.is_empty() => rest.iter().fold(*x, |a, b| a.min(*b)),
+            ("max", [x, rest @ ..]) if !rest.is_empty() => rest.iter().fold(*x, |a, b| a.max(*b)),
+            (
+                "sqrt" | "abs" | "sin" | "cos" | "tan" | "asin" | "acos" | "atan" | "atan2" | "exp" | "ln" | "pow"
+                | "floor" | "ceil" | "round" | "radians" | "degrees" | "min" | "max",
+                _,
+            ) => return Err(self.span_error(start, &format!("wrong number of arguments for {}", name))),
+            _ => return Err(token.error(&format!("unknown function {}", name))),
+        };
+        if value.is_nan() {
+            return Err(self.span_error(start, &format!("{} is undefined here", name)));
+        }
+        Ok(value)
+    }
+
+    /// An error spanning the tokens from `start` to the last one read
+    fn span_error(&self, start: usize, message: &str) -> ExprError {
+        let first = &self.tokens[start];
+        let last = &self.tokens[self.next - 1];
+        ExprError { message: message.to_owned(), offset: first.offset, len: last.offset + last.len - first.offset }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let mut variables = Variables::new();
+        variables.insert(String::from("radius"), 1.5);
+        let eval = |s: &str| evaluate(s, &variables);
+
+        assert_eq!(eval("pi/2"), Ok(std::f32::consts::FRAC_PI_2));
+        assert_eq!(eval("2*sqrt(4) - 1"), Ok(3.0));
+        assert_eq!(eval("$radius * 2"), Ok(3.0));
+        assert_eq!(eval("-2^2 + 2^3^2"), Ok(508.0));
+        assert_eq!(eval("max(1, $radius, 1e-1) % 1"), Ok(0.5));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9.0));
+    }
+
+    #[test]
+    fn errors_point_inside() {
+        let variables = Variables::new();
+        let error = |s: &str| evaluate(s, &variables).unwrap_err();
+
+        assert_eq!(error("2 * $width"), ExprError { message: String::from("unknown variable $width"), offset: 4, len: 6 });
+        assert_eq!(error("1 / (2 - 2)").offset, 4);
+        assert_eq!(error("1 / (2 - 2)").len, 7);
+        assert_eq!(error("sqrt(-1)").len, 8);
+        assert_eq!(error("min(1)").message, "wrong number of arguments for min");
+        assert_eq!(error("(1 + 2").offset, 6);
+        assert_eq!(error("1 2").offset, 2);
+        assert_eq!(error("2 # 3").offset, 2);
+        assert_eq!(error("foo").message, "unknown constant foo");
+        assert_eq!(error("(-8)^(1/3)"), ExprError { message: String::from("power is undefined here"), offset: 0, len: 10 });
+        assert_eq!(error("1e39").message, "value is too large for a float");
+    }
+}
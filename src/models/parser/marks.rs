@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, ScanError};
+
+/// Where a node starts in the source, the line counts from 1 and the column
+/// from 0, in characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl From<&Marker> for Position {
+    fn from(marker: &Marker) -> Self {
+        Position { line: marker.line(), col: marker.col() }
+    }
+}
+
+/// The positions of the nodes of a yaml document, in the same shape as the
+/// `Yaml` loaded from it. Aliases get the positions of their anchor.
+#[derive(Debug, Clone)]
+pub struct Marks {
+    pub position: Position,
+    children: Children,
+}
+
+#[derive(Debug, Clone)]
+enum Children {
+    None,
+    Sequence(Vec<Marks>),
+    /// the text of scalar keys, the key and the value
+    Mapping(Vec<(Option<String>, Marks, Marks)>),
+}
+
+impl Marks {
+    /// One per document, like `YamlLoader::load_from_str`
+    pub fn load(source: &str) -> Result<Vec<Marks>, ScanError> {
+        let mut builder = Builder::default();
+        Parser::new(source.chars()).load(&mut builder, true)?;
+        Ok(builder.docs)
+    }
+
+    pub fn item(&self, index: usize) -> Option<&Marks> {
+        match self.children {
+            Children::Sequence(ref items) => items.get(index),
+            _ => None,
+        }
+    }
+
+    fn entry(&self, key: &str) -> Option<&(Option<String>, Marks, Marks)> {
+        match self.children {
+            // a repeated key counts with its last value, like in the hash
+            Children::Mapping(ref entries) => entries.iter().rev().find(|(k, _, _)| k.as_deref() == Some(key)),
+            _ => None,
+        }
+    }
+
+    /// The key itself
+    pub fn key(&self, key: &str) -> Option<&Marks> {
+        self.entry(key).map(|(_, k, _)| k)
+    }
+
+    /// The value under the key
+    pub fn value(&self, key: &str) -> Option<&Marks> {
+        self.entry(key).map(|(_, _, v)| v)
+    }
+}
+
+/// Builds the marks the way `YamlLoader` builds the nodes
+#[derive(Default)]
+struct Builder {
+    docs: Vec<Marks>,
+    stack: Vec<(Marks, usize)>,
+    /// the pending key of each mapping on the stack
+    keys: Vec<Option<(Option<String>, Marks)>>,
+    anchors: BTreeMap<usize, Marks>,
+}
+
+impl Builder {
+    fn insert(&mut self, node: Marks, anchor: usize, text: Option<String>) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, node.clone());
+        }
+        let parent = match self.stack.last_mut() {
+            Some((parent, _)) => parent,
+            None => {
+                self.stack.push((node, 0));
+                return;
+            }
+        };
+        match parent.children {
+            Children::Sequence(ref mut items) => items.push(node),
+            Children::Mapping(ref mut entries) => {
+                let key = self.keys.last_mut().expect("a key slot for every mapping");
+                match key.take() {
+                    Some((key_text, key_marks)) => entries.push((key_text, key_marks, node)),
+                    None => *key = Some((text, node)),
+                }
+            }
+            Children::None => unreachable!("scalars have no children"),
+        }
+    }
+}
+
+impl MarkedEventReceiver for Builder {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        let position = Position::from(&marker);
+        let leaf = Marks { position, children: Children::None };
+        match event {
+            Event::DocumentEnd => {
+                let doc = self.stack.pop().map_or(leaf, |(doc, _)| doc);
+                self.docs.push(doc);
+            }
+            Event::SequenceStart(anchor) => {
+                self.stack.push((Marks { position, children: Children::Sequence(Vec::new()) }, anchor));
+            }
+            Event::MappingStart(anchor) => {
+                self.stack.push((Marks { position, children: Children::Mapping(Vec::new()) }, anchor));
+                self.keys.push(None);
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                if matches!(event, Event::MappingEnd) {
+                    self.keys.pop();
+                }
+                let (node, anchor) = self.stack.pop().expect("ends follow starts");
+                self.insert(node, anchor, None);
+            }
+            Event::Scalar(text, _, anchor, _) => self.insert(leaf, anchor, Some(text)),
+            Event::Alias(anchor) => {
+                let node = self.anchors.get(&anchor).cloned().unwrap_or(leaf);
+                self.insert(node, 0, None);
+            }
+            _ => {}
+        }
+    }
+}
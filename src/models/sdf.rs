@@ -0,0 +1,305 @@
+use cgmath::{InnerSpace, Vector2, Vector3};
+use crate::bvh::Aabb;
+use crate::ray::Ray;
+
+// Opcodes of the postfix program, exported to the shader as the `SDF_OP_*`
+// defines. Primitives push a distance, operators pop two and push one.
+pub const SDF_OP_SPHERE: i32 = 0;
+pub const SDF_OP_BOX: i32 = 1;
+pub const SDF_OP_ROUND_BOX: i32 = 2;
+pub const SDF_OP_TORUS: i32 = 3;
+pub const SDF_OP_CUSTOM: i32 = 4;
+pub const SDF_OP_UNION: i32 = 10;
+pub const SDF_OP_INTERSECTION: i32 = 11;
+pub const SDF_OP_DIFFERENCE: i32 = 12;
+pub const SDF_OP_SMOOTH_UNION: i32 = 13;
+pub const SDF_OP_SMOOTH_INTERSECTION: i32 = 14;
+pub const SDF_OP_SMOOTH_DIFFERENCE: i32 = 15;
+
+/// Size of the evaluation stack, `SDF_STACK_SIZE` in the shader
+pub const SDF_STACK_SIZE: usize = 16;
+/// Maximum number of marching steps, `SDF_MAX_STEPS` in the shader
+pub const SDF_MAX_STEPS: usize = 128;
+/// Distance at which the surface counts as hit, `SDF_EPSILON` in the shader
+pub const SDF_EPSILON: f32 = 0.0001;
+/// Marching starts this far along the ray to not hit the surface it left
+pub const SDF_MIN_T: f32 = 0.001;
+
+/// A shape described by a signed distance field, either a primitive or a
+/// constructive solid geometry operation on two shapes
+#[derive(Debug, Clone, PartialEq)]
+pub enum SdfNode {
+    Sphere { pos: Vector3<f32>, radius: f32 },
+    /// `size` is the full extent of the box, like `ModelType::Box`
+    Box { pos: Vector3<f32>, size: Vector3<f32> },
+    RoundBox { pos: Vector3<f32>, size: Vector3<f32>, radius: f32 },
+    Torus { pos: Vector3<f32>, major: f32, minor: f32 },
+    /// A glsl expression of `vec3 p`, evaluating to the distance. These are
+    /// injected into the shader and given an `id` by the `ModelManager`. The
+    /// cpu can't evaluate them and uses the bounding sphere instead.
+    Custom { pos: Vector3<f32>, code: String, bound: f32, id: i32 },
+    Union(Box<SdfNode>, Box<SdfNode>),
+    Intersection(Box<SdfNode>, Box<SdfNode>),
+    Difference(Box<SdfNode>, Box<SdfNode>),
+    SmoothUnion(Box<SdfNode>, Box<SdfNode>, f32),
+    SmoothIntersection(Box<SdfNode>, Box<SdfNode>, f32),
+    SmoothDifference(Box<SdfNode>, Box<SdfNode>, f32),
+}
+
+impl SdfNode {
+    /// Flattens the tree into a postfix program
+    pub fn compile(&self) -> Vec<f32> {
+        let mut program = Vec::new();
+        self.emit(&mut |x| program.push(x));
+        program
+    }
+
+    /// Number of floats in the program
+    pub fn program_len(&self) -> usize {
+        let mut len = 0;
+        self.emit(&mut |_| len += 1);
+        len
+    }
+
+    /// Writes the program to the start of `out`, without allocating
+    pub fn write_program(&self, out: &mut [f32]) {
+        let mut at = 0;
+        self.emit(&mut |x| {
+            out[at] = x;
+            at += 1;
+        });
+    }
+
+    fn emit<F: FnMut(f32)>(&self, push: &mut F) {
+        let op = |push: &mut F, op: i32, pos: &Vector3<f32>, args: &[f32]| {
+            push(op as f32);
+            [pos.x, pos.y, pos.z].iter().chain(args).for_each(|x| push(*x));
+        };
+
+        match self {
+            SdfNode::Sphere { pos, radius } => op(push, SDF_OP_SPHERE, pos, &[*radius]),
+            SdfNode::Box { pos, size } => {
+                op(push, SDF_OP_BOX, pos, &[size.x * 0.5, size.y * 0.5, size.z * 0.5])
+            }
+            SdfNode::RoundBox { pos, size, radius } => {
+                op(push, SDF_OP_ROUND_BOX, pos, &[size.x * 0.5, size.y * 0.5, size.z * 0.5, *radius])
+            }
+            SdfNode::Torus { pos, major, minor } => op(push, SDF_OP_TORUS, pos, &[*major, *minor]),
+            SdfNode::Custom { pos, bound, id, .. } => op(push, SDF_OP_CUSTOM, pos, &[*id as f32, *bound]),
+            SdfNode::Union(a, b) => binary(push, a, b, SDF_OP_UNION, None),
+            SdfNode::Intersection(a, b) => binary(push, a, b, SDF_OP_INTERSECTION, None),
+            SdfNode::Difference(a, b) => binary(push, a, b, SDF_OP_DIFFERENCE, None),
+            SdfNode::SmoothUnion(a, b, k) => binary(push, a, b, SDF_OP_SMOOTH_UNION, Some(*k)),
+            SdfNode::SmoothIntersection(a, b, k) => binary(push, a, b, SDF_OP_SMOOTH_INTERSECTION, Some(*k)),
+            SdfNode::SmoothDifference(a, b, k) => binary(push, a, b, SDF_OP_SMOOTH_DIFFERENCE, Some(*k)),
+        }
+
+        fn binary<F: FnMut(f32)>(push: &mut F, a: &SdfNode, b: &SdfNode, op: i32, k: Option<f32>) {
+            a.emit(push);
+            b.emit(push);
+            push(op as f32);
+            if let Some(k) = k {
+                push(k);
+            }
+        }
+    }
+
+    /// Number of stack slots needed to evaluate the program
+    pub fn stack_depth(&self) -> usize {
+        match self.children() {
+            Some((a, b)) => a.stack_depth().max(1 + b.stack_depth()),
+            None => 1,
+        }
+    }
+
+    fn children(&self) -> Option<(&SdfNode, &SdfNode)> {
+        match self {
+            SdfNode::Union(a, b) |
+            SdfNode::Intersection(a, b) |
+            SdfNode::Difference(a, b) |
+            SdfNode::SmoothUnion(a, b, _) |
+            SdfNode::SmoothIntersection(a, b, _) |
+            SdfNode::SmoothDifference(a, b, _) => Some((a, b)),
+            _ => None,
+        }
+    }
+
+    /// Calls `f` for every custom snippet in the tree
+    pub fn for_each_custom<F: FnMut(&str, &mut i32)>(&mut self, f: &mut F) {
+        match self {
+            SdfNode::Custom { code, id, .. } => f(code, id),
+            SdfNode::Union(a, b) |
+            SdfNode::Intersection(a, b) |
+            SdfNode::Difference(a, b) |
+            SdfNode::SmoothUnion(a, b, _) |
+            SdfNode::SmoothIntersection(a, b, _) |
+            SdfNode::SmoothDifference(a, b, _) => {
+                a.for_each_custom(f);
+                b.for_each_custom(f);
+            }
+            _ => {}
+        }
+    }
+
+    /// Bounds in the local space of the model
+    pub fn bounds(&self) -> Aabb {
+        let half = |pos: &Vector3<f32>, e: Vector3<f32>| Aabb { min: pos - e, max: pos + e };
+        match self {
+            SdfNode::Sphere { pos, radius } => Aabb::around(*pos, radius.abs()),
+            SdfNode::Box { pos, size } => half(pos, size * 0.5),
+            SdfNode::RoundBox { pos, size, .. } => half(pos, size * 0.5),
+            SdfNode::Torus { pos, major, minor } => {
+                half(pos, Vector3::new(major + minor, *minor, major + minor))
+            }
+            SdfNode::Custom { pos, bound, .. } => Aabb::around(*pos, bound.abs()),
+            SdfNode::Union(a, b) => {
+                let mut bounds = a.bounds();
+                bounds.grow(&b.bounds());
+                bounds
+            }
+            // the smooth union may bulge by up to k / 4 outside of the operands
+            SdfNode::SmoothUnion(a, b, k) => {
+                let mut bounds = a.bounds();
+                bounds.grow(&b.bounds());
+                let bulge = k.abs() * 0.25;
+                half(&bounds.centroid(), (bounds.max - bounds.min) * 0.5 + Vector3::new(bulge, bulge, bulge))
+            }
+            SdfNode::Intersection(a, _) |
+            SdfNode::Difference(a, _) |
+            SdfNode::SmoothIntersection(a, _, _) |
+            SdfNode::SmoothDifference(a, _, _) => a.bounds(),
+        }
+    }
+}
+
+fn smin(a: f32, b: f32, k: f32) -> f32 {
+    let h = (k - (a - b).abs()).max(0.0) / k;
+    a.min(b) - h * h * k * 0.25
+}
+
+/// Evaluates a postfix program at `p`, same as `sdf_evaluate` in the shader
+pub fn evaluate(program: &[f32], p: Vector3<f32>) -> f32 {
+    let mut stack = [0.0f32; SDF_STACK_SIZE];
+    let mut top = 0;
+    let mut pc = 0;
+
+    let abs = |v: Vector3<f32>| Vector3::new(v.x.abs(), v.y.abs(), v.z.abs());
+    let max0 = |v: Vector3<f32>| Vector3::new(v.x.max(0.0), v.y.max(0.0), v.z.max(0.0));
+    let sd_box = |q: Vector3<f32>, b: Vector3<f32>| {
+        let q = abs(q) - b;
+        max0(q).magnitude() + q.x.max(q.y.max(q.z)).min(0.0)
+    };
+
+    while pc < program.len() {
+        let op = program[pc] as i32;
+        if op < SDF_OP_UNION {
+            let q = p - Vector3::new(program[pc + 1], program[pc + 2], program[pc + 3]);
+            let args = &program[pc + 4..];
+            let (d, len) = match op {
+                SDF_OP_SPHERE => (q.magnitude() - args[0], 5),
+                SDF_OP_BOX => (sd_box(q, Vector3::new(args[0], args[1], args[2])), 7),
+                SDF_OP_ROUND_BOX => {
+                    let r = args[3];
+                    (sd_box(q, Vector3::new(args[0] - r, args[1] - r, args[2] - r)) - r, 8)
+                }
+                SDF_OP_TORUS => {
+                    let t = Vector2::new(Vector2::new(q.x, q.z).magnitude() - args[0], q.y);
+                    (t.magnitude() - args[1], 6)
+                }
+                // custom snippets are approximated by their bounding sphere
+                _ => (q.magnitude() - args[1], 6),
+            };
+            stack[top] = d;
+            top += 1;
+            pc += len;
+        } else {
+            top -= 1;
+            let (a, b) = (stack[top - 1], stack[top]);
+            let k = program.get(pc + 1).copied().unwrap_or(0.0);
+            let (d, len) = match op {
+                SDF_OP_UNION => (a.min(b), 1),
+                SDF_OP_INTERSECTION => (a.max(b), 1),
+                SDF_OP_DIFFERENCE => (a.max(-b), 1),
+                SDF_OP_SMOOTH_UNION => (smin(a, b, k), 2),
+                SDF_OP_SMOOTH_INTERSECTION => (-smin(-a, -b, k), 2),
+                _ => (-smin(-a, b, k), 2),
+            };
+            stack[top - 1] = d;
+            pc += len;
+        }
+    }
+
+    stack[0]
+}
+
+/// Sphere-traces `distance` along the ray, same as `draw_sdf` in the shader
+pub fn march<F: Fn(Vector3<f32>) -> f32>(ray: &Ray, t_max: f32, distance: F) -> Option<f32> {
+    let mut t = SDF_MIN_T;
+    for _ in 0..SDF_MAX_STEPS {
+        if t >= t_max {
+            return None;
+        }
+        let d = distance(ray.at(t)).abs();
+        if d < SDF_EPSILON {
+            return Some(t);
+        }
+        t += d;
+    }
+    None
+}
+
+/// Gradient of `distance` at `p` by central differences
+pub fn normal<F: Fn(Vector3<f32>) -> f32>(p: Vector3<f32>, distance: F) -> Vector3<f32> {
+    let h = SDF_MIN_T;
+    let (dx, dy, dz) = (Vector3::new(h, 0.0, 0.0), Vector3::new(0.0, h, 0.0), Vector3::new(0.0, 0.0, h));
+    Vector3::new(
+        distance(p + dx) - distance(p - dx),
+        distance(p + dy) - distance(p - dy),
+        distance(p + dz) - distance(p - dz),
+    ).normalize()
+}
+
+/// Generates the glsl function `sdf_custom` dispatching to every snippet
+pub fn custom_glsl(snippets: &[String]) -> String {
+    let mut glsl = String::from("float sdf_custom(in int id, in vec3 p) {\n    switch (id) {\n");
+    for (id, code) in snippets.iter().enumerate() {
+        glsl.push_str(&format!("        case {}: return {};\n", id, code));
+    }
+    glsl.push_str("        default: return length(p) - 1.0;\n    }\n}\n");
+    glsl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_matches_tree() {
+        let tree = SdfNode::SmoothUnion(
+            Box::new(SdfNode::Sphere { pos: Vector3::new(0.0, 0.0, 0.0), radius: 1.0 }),
+            Box::new(SdfNode::Difference(
+                Box::new(SdfNode::Box { pos: Vector3::new(1.0, 0.0, 0.0), size: Vector3::new(2.0, 2.0, 2.0) }),
+                Box::new(SdfNode::Torus { pos: Vector3::new(1.0, 0.0, 0.0), major: 1.0, minor: 0.25 }),
+            )),
+            0.5,
+        );
+
+        let program = tree.compile();
+        assert_eq!(program[0] as i32, SDF_OP_SPHERE);
+        assert_eq!(program[program.len() - 2] as i32, SDF_OP_SMOOTH_UNION);
+        assert_eq!(tree.stack_depth(), 3);
+
+        // far from the blend the smooth union is an ordinary union
+        assert!((evaluate(&program, Vector3::new(-3.0, 0.0, 0.0)) - 2.0).abs() < 1e-6);
+        // inside the box but outside the torus tube
+        assert!(evaluate(&program, Vector3::new(1.0, 0.0, 0.0)) < 0.0);
+        // inside the torus tube, cut away from the box
+        assert!(evaluate(&program, Vector3::new(2.0, 0.0, 0.0)) > 0.0);
+    }
+
+    #[test]
+    fn custom_snippets_are_dispatched() {
+        let glsl = custom_glsl(&[String::from("length(p) - 2.0")]);
+        assert!(glsl.contains("case 0: return length(p) - 2.0;"));
+    }
+}
@@ -0,0 +1,134 @@
+use crate::gpu::{self, GpuLayout, Mat3};
+use crate::models::ModelProperty;
+
+/// Transform and surface of a model, every model type starts with it. The
+/// rotation is precomputed so that the shader doesn't evaluate it per ray.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+pub struct ModelData {
+    /// local to world rotation
+    pub rotation: Mat3,
+    pub position: [f32; 3],
+    pub scale: f32,
+    pub color: [f32; 3],
+    pub reflectance: f32,
+    pub roughness: f32,
+}
+
+impl From<&ModelProperty> for ModelData {
+    fn from(model: &ModelProperty) -> Self {
+        let tf = &model.tf;
+        ModelData {
+            rotation: tf.rotation().into(),
+            position: [tf.x, tf.y, tf.z],
+            scale: tf.scale,
+            color: [model.color.r, model.color.g, model.color.b],
+            reflectance: model.material.reflectance,
+            roughness: model.material.roughness,
+        }
+    }
+}
+
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+#[gpu(glsl = "Sphere")]
+pub struct SphereData {
+    pub model: ModelData,
+}
+
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+#[gpu(glsl = "Plane")]
+pub struct PlaneData {
+    pub model: ModelData,
+}
+
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+#[gpu(glsl = "Box")]
+pub struct BoxData {
+    pub model: ModelData,
+    pub dims: [f32; 3],
+}
+
+/// The program of the shape is stored in a buffer shared by all sdf models
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, PartialEq, GpuLayout)]
+#[gpu(glsl = "Sdf")]
+pub struct SdfData {
+    pub model: ModelData,
+    /// offset into the program buffer
+    pub program: i32,
+    pub program_len: i32,
+}
+
+/// The data of one model, in the form it takes in the buffer of its type
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// a removed model, it has no data
+    None,
+    Sphere(SphereData),
+    Plane(PlaneData),
+    Box(BoxData),
+    /// along with the program
+    Sdf(SdfData, Vec<f32>),
+}
+
+/// The content of the model buffers, one array per model type
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackedModels {
+    /// `[type, index]` per slot, the index points into the array of the type
+    pub index: Vec<i32>,
+    pub spheres: Vec<SphereData>,
+    pub planes: Vec<PlaneData>,
+    pub boxes: Vec<BoxData>,
+    pub sdfs: Vec<SdfData>,
+    pub sdf_programs: Vec<f32>,
+}
+
+/// Glsl declarations of the structs above
+pub fn glsl_structs() -> String {
+    [
+        gpu::glsl_struct::<ModelData>(),
+        gpu::glsl_struct::<SphereData>(),
+        gpu::glsl_struct::<PlaneData>(),
+        gpu::glsl_struct::<BoxData>(),
+        gpu::glsl_struct::<SdfData>(),
+    ].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn structs_match_std430() {
+        assert_eq!(mem::size_of::<ModelData>(), ModelData::SIZE);
+        assert_eq!(mem::size_of::<SphereData>(), SphereData::SIZE);
+        assert_eq!(mem::size_of::<PlaneData>(), PlaneData::SIZE);
+        assert_eq!(mem::size_of::<BoxData>(), BoxData::SIZE);
+        assert_eq!(mem::size_of::<SdfData>(), SdfData::SIZE);
+        assert_eq!(SdfData::SIZE, 112);
+
+        let data = SdfData {
+            model: ModelData {
+                rotation: Mat3([[0.0; 4]; 3]),
+                position: [0.0; 3],
+                scale: 1.0,
+                color: [0.0; 3],
+                reflectance: 0.0,
+                roughness: 0.0,
+            },
+            program: 0,
+            program_len: 0,
+        };
+        let base = &data as *const SdfData as usize;
+        let offset = |field: *const f32| field as usize - base;
+        assert_eq!(offset(&data.model.scale), gpu::offset_of(SdfData::FIELDS, &["model", "scale"]).unwrap());
+        assert_eq!(offset(data.model.color.as_ptr()), gpu::offset_of(SdfData::FIELDS, &["model", "color"]).unwrap());
+        assert_eq!(offset(&data.program as *const i32 as *const f32), gpu::offset_of(SdfData::FIELDS, &["program"]).unwrap());
+
+        assert!(glsl_structs().contains("struct Sdf {\n    ModelData model;\n    int program;\n    int program_len;\n};\n"));
+    }
+}
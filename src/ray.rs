@@ -0,0 +1,69 @@
+use cgmath::{InnerSpace, Vector3};
+
+/// A ray in world space, `origin + t * dir`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub dir: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, dir: Vector3<f32>) -> Self {
+        Ray { origin, dir }
+    }
+
+    pub fn at(&self, t: f32) -> Vector3<f32> {
+        self.origin + self.dir * t
+    }
+}
+
+/// Result of a successful intersection test
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    pub normal: Vector3<f32>,
+}
+
+// The routines below mirror the ones in `shaders/shader.frag` so that the cpu
+// and the gpu agree on what a ray hits.
+
+/// Solves a quadratic equation, not accepting complex solutions.
+/// Returns `(x1, x2)` where `x1` uses the positive square root.
+fn quadratic_solve(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let det = b * b - 4.0 * a * c;
+    if det >= 0.0 {
+        let sdet = det.sqrt();
+        Some(((-b + sdet) / (2.0 * a), (-b - sdet) / (2.0 * a)))
+    } else {
+        None
+    }
+}
+
+/// Same as `intersection_sphere`, returns the entry distance
+pub fn intersect_sphere(ray: &Ray, center: Vector3<f32>, radius: f32, t_max: f32) -> Option<f32> {
+    // source: physically based rendering, s3.2, p135
+    let o = ray.origin - center;
+    let a = ray.dir.dot(ray.dir);
+    let b = 2.0 * ray.dir.dot(o);
+    let c = o.dot(o) - radius * radius;
+
+    let (_t_exit, t_entry) = quadratic_solve(a, b, c)?;
+    if 0.0 < t_entry && t_entry < t_max {
+        Some(t_entry)
+    } else {
+        None
+    }
+}
+
+/// Same as `intersection_plane`
+pub fn intersect_plane(ray: &Ray, point: Vector3<f32>, normal: Vector3<f32>, t_max: f32) -> Option<f32> {
+    let a = normal.dot(ray.origin - point);
+    let b = normal.dot(ray.dir);
+    if b != 0.0 {
+        let t = -a / b;
+        if 0.0 < t && t < t_max {
+            return Some(t);
+        }
+    }
+    None
+}
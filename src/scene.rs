@@ -0,0 +1,480 @@
+mod writer;
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use cgmath::Vector3;
+use crate::bvh::Bvh;
+use crate::camera::{Cameras, Projection};
+use crate::environment::{CubeMap, Environment, Skybox};
+use crate::image::ImageError;
+use crate::lights::{LightData, LightManager, LightType};
+use crate::material::Material;
+use crate::models::{layout, InstanceData, ModelId, ModelManager, ModelType, PackedModels};
+use crate::models::parser::{parse_scene, parse_scene_file, ParserErrors};
+use crate::models::sdf::SDF_STACK_SIZE;
+use crate::ray::Ray;
+
+/// Uniforms the shader declares itself, custom uniforms can't use these names
+pub const BUILTIN_UNIFORMS: &[&str] = &[
+    "uTime", "uRatio", "uScreenResolution", "uCamPitch", "uCamYaw", "uCamPos",
+    "uCamProjection", "uCamFov", "uCamAperture", "uCamFocusDistance", "uSkyBoxSampler",
+    "uIrradianceSampler", "uSpecularSampler", "uBrdfLutSampler", "uSelectedModel",
+];
+
+/// Value of a custom uniform, declared in the generated shader code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Uniform {
+    Int(i32),
+    Float(f32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+}
+
+impl Uniform {
+    pub fn glsl_type(&self) -> &'static str {
+        match self {
+            Uniform::Int(_) => "int",
+            Uniform::Float(_) => "float",
+            Uniform::Vec2(_) => "vec2",
+            Uniform::Vec3(_) => "vec3",
+            Uniform::Vec4(_) => "vec4",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(io::Error),
+    Parser(ParserErrors),
+    Image(ImageError),
+    Invalid(String),
+}
+
+impl From<io::Error> for SceneError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ParserErrors> for SceneError {
+    fn from(e: ParserErrors) -> Self {
+        Self::Parser(e)
+    }
+}
+
+impl From<ImageError> for SceneError {
+    fn from(e: ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl Display for SceneError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Parser(e) => write!(f, "{}", e),
+            Self::Image(e) => write!(f, "{}", e),
+            Self::Invalid(s) => write!(f, "invalid scene: {}", s),
+        }
+    }
+}
+
+impl error::Error for SceneError {}
+
+pub type SceneResult<T> = Result<T, SceneError>;
+
+/// The data of every buffer and texture of a scene, see `Scene::upload_data`
+pub struct SceneData {
+    pub models: PackedModels,
+    pub instances: Vec<InstanceData>,
+    pub bvh: Bvh,
+    pub lights: Vec<LightData>,
+    pub skybox: CubeMap,
+}
+
+/// The closest model along a ray, see `Scene::intersect`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    /// instances report their prototype
+    pub model: ModelId,
+    /// distance along the ray, in lengths of its direction
+    pub t: f32,
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+/// Everything a scene file describes
+#[derive(Debug, Default)]
+pub struct Scene {
+    pub models: ModelManager,
+    pub lights: LightManager,
+    pub cameras: Cameras,
+    /// named materials, models refer to them by name in the scene file
+    pub materials: Vec<(String, Material)>,
+    pub environment: Environment,
+    /// custom uniforms, in the order they are declared
+    pub uniforms: Vec<(String, Uniform)>,
+    /// the files it was read from, the scene file and the files it includes
+    pub files: Vec<PathBuf>,
+}
+
+impl Scene {
+    /// Farthest distance a ray can hit a model, the same as in the shader
+    pub const MAX_DISTANCE: f32 = 1000.0;
+
+    pub fn new() -> Self {
+        Scene::default()
+    }
+
+    /// Parses yaml source, see `scenes/template.yaml`
+    pub fn parse(source: &str) -> SceneResult<Self> {
+        let scene = parse_scene(source)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Parses a file and the files it includes, parser errors name them
+    pub fn load<P: AsRef<Path>>(path: P) -> SceneResult<Self> {
+        let path = path.as_ref();
+        let scene = parse_scene_file(&fs::read_to_string(path)?, path)?;
+        scene.validate()?;
+        Ok(scene)
+    }
+
+    /// Yaml source that parses to the same scene. Generated instances are
+    /// written out one by one.
+    pub fn to_yaml(&self) -> String {
+        writer::write_scene(self, None)
+    }
+
+    /// Skybox images are written relative to the saved file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> SceneResult<()> {
+        self.validate()?;
+        let path = path.as_ref();
+        fs::write(path, writer::write_scene(self, path.parent()))?;
+        Ok(())
+    }
+
+    /// Takes over a new version of the scene, as read again from its file.
+    /// Unchanged models keep their handles and their place in the buffers,
+    /// and the active camera keeps where it was moved to if it still exists.
+    /// Returns the number of models that changed.
+    pub fn reload(&mut self, reloaded: Scene) -> usize {
+        let Scene { models, lights, mut cameras, materials, environment, uniforms, files } = reloaded;
+        let live = self.cameras.active();
+        if let Some(index) = cameras.index_of(self.cameras.active_name()) {
+            cameras.set_active(index);
+            cameras.active_mut().tf = live.tf.clone();
+        }
+        self.cameras = cameras;
+        self.lights = lights;
+        self.materials = materials;
+        self.environment = environment;
+        self.uniforms = uniforms;
+        self.files = files;
+        self.models.reconcile(models)
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|(n, _)| n == name).map(|(_, m)| m)
+    }
+
+    pub fn uniform(&self, name: &str) -> Option<&Uniform> {
+        self.uniforms.iter().find(|(n, _)| n == name).map(|(_, u)| u)
+    }
+
+    /// Checks what the parser checks, for scenes that were changed in code
+    pub fn validate(&self) -> SceneResult<()> {
+        let invalid = |s: String| Err(SceneError::Invalid(s));
+
+        for (id, model) in self.models.iter() {
+            if model.tf.scale <= 0.0 {
+                return invalid(format!("model {} has a non-positive scale", id.index()));
+            }
+            if let Some(i) = self.models.instances(id).and_then(|tfs| tfs.iter().position(|tf| tf.scale <= 0.0)) {
+                return invalid(format!("instance {} of model {} has a non-positive scale", i, id.index()));
+            }
+            if !(0.0..=1.0).contains(&model.material.roughness) {
+                return invalid(format!("model {} has a roughness outside of 0 to 1", id.index()));
+            }
+            match &model.t {
+                ModelType::Box(w, h, d) if *w <= 0.0 || *h <= 0.0 || *d <= 0.0 => {
+                    return invalid(format!("box {} has a non-positive size", id.index()));
+                }
+                ModelType::Sdf(shape) if shape.stack_depth() > SDF_STACK_SIZE => {
+                    return invalid(format!("shape of model {} is nested too deep", id.index()));
+                }
+                _ => {}
+            }
+        }
+
+        for (i, (_, light)) in self.lights.iter().enumerate() {
+            if light.direction == Vector3::new(0.0, 0.0, 0.0) {
+                return invalid(format!("light {} has no direction", i));
+            }
+            if light.intensity < 0.0 {
+                return invalid(format!("light {} has a negative intensity", i));
+            }
+            if let LightType::Spot { inner, outer } = light.t {
+                if inner >= outer {
+                    return invalid(format!("spot light {} is not narrower inside than outside", i));
+                }
+            }
+        }
+
+        for (name, camera) in self.cameras.iter() {
+            if camera.fov <= 0.0 || camera.focus_distance <= 0.0 || camera.aperture < 0.0 {
+                return invalid(format!("camera {} has a non-positive fov, focus distance or aperture", name));
+            }
+            if camera.projection == Projection::Perspective && camera.fov >= std::f32::consts::PI {
+                return invalid(format!("perspective camera {} can't see 180° or more", name));
+            }
+        }
+
+        if let Skybox::Sky(sky) = &self.environment.skybox {
+            if sky.turbidity < 1.0 || sky.sun_intensity < 0.0 {
+                return invalid(String::from("the sky has a turbidity below 1 or a negative sun intensity"));
+            }
+        }
+
+        for path in self.environment.skybox.paths() {
+            if !path.is_file() {
+                return invalid(format!("skybox image {} does not exist", path.display()));
+            }
+        }
+
+        for (i, (name, material)) in self.materials.iter().enumerate() {
+            if self.materials[..i].iter().any(|(n, _)| n == name) {
+                return invalid(format!("material {} is defined twice", name));
+            }
+            if !(0.0..=1.0).contains(&material.roughness) {
+                return invalid(format!("material {} has a roughness outside of 0 to 1", name));
+            }
+        }
+
+        for (i, (name, _)) in self.uniforms.iter().enumerate() {
+            let mut chars = name.chars();
+            let identifier = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !identifier || name.starts_with("gl_") {
+                return invalid(format!("uniform {} is not a glsl identifier", name));
+            }
+            if BUILTIN_UNIFORMS.contains(&name.as_str()) || self.uniforms[..i].iter().any(|(n, _)| n == name) {
+                return invalid(format!("uniform {} is defined twice", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The code shaderdev puts in place of the `<generated>` marker: the
+    /// layout of the buffers, custom sdf code and custom uniforms
+    pub fn glsl_header(&self) -> String {
+        let mut glsl = layout::glsl_header();
+        glsl.push_str(&self.models.sdf_glsl());
+        for (name, uniform) in &self.uniforms {
+            glsl.push_str(&format!("uniform {} {};\n", uniform.glsl_type(), name));
+        }
+        glsl
+    }
+
+    /// The model the shader draws first along `ray`, e.g. one from
+    /// `Camera::generate_ray`. Builds a bvh, which is fine for picking but
+    /// not for tracing many rays.
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (model, hit) = self.models.intersect(&self.models.build_bvh(), ray, Scene::MAX_DISTANCE)?;
+        Some(Hit {
+            model,
+            t: hit.t,
+            position: ray.at(hit.t),
+            normal: hit.normal,
+        })
+    }
+
+    /// Content of the light buffer: the lights followed by the sun of the sky
+    pub fn light_data(&mut self) -> Vec<LightData> {
+        let mut lights = self.lights.create_ss_buffer();
+        lights.extend(self.environment.sun_light().as_ref().map(LightData::from));
+        lights
+    }
+
+    /// Creates the content of every buffer and of the skybox, fails if an
+    /// image of the skybox can't be loaded
+    pub fn upload_data(&mut self) -> SceneResult<SceneData> {
+        let skybox = self.environment.cube_map()?;
+        Ok(SceneData {
+            models: self.models.create_ss_buffers(),
+            instances: self.models.create_instance_buffer(),
+            bvh: self.models.build_bvh(),
+            lights: self.light_data(),
+            skybox,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+    use crate::color::Color;
+    use crate::models::BufferChanges;
+
+    const SOURCE: &str = "
+scene:
+  materials:
+    mirror: {reflectance: 0.9}
+  models:
+    - type: sphere
+      material: mirror
+      color: red
+    - type: box
+      size: [1.0, 2.0, 3.0]
+      transform: {pos: [0.0, 1.0, 0.0], orientation: {head: 0.5}}
+    - instances:
+        prototype:
+          type: sdf
+          shape:
+            smooth_union:
+              k: 0.2
+              of:
+                - sphere: {radius: 0.5}
+                - torus: {pos: [0.0, 0.5, 0.0], major: 1.0, minor: 0.1}
+        transforms:
+          - pos: [2.0, 0.0, 0.0]
+          - pos: [4.0, 0.0, 0.0]
+  lights:
+    - type: spot
+      pos: [0.0, 5.0, 0.0]
+      intensity: 10.0
+  cameras:
+    - name: front
+      position: [0.0, 0.0, 10.0]
+    - name: wide
+      projection: fisheye
+      fov: 3.0
+  active_camera: wide
+  environment:
+    skybox:
+      gradient: {top: [0.1, 0.2, 0.3], bottom: black}
+  uniforms:
+    uGlow: 0.5
+    uTint: [1.0, 0.5, 0.0]
+    uMode: 2
+";
+
+    #[test]
+    fn saved_scene_parses_to_the_same_scene() {
+        let scene = Scene::parse(SOURCE).expect("scene is valid");
+        assert_eq!(scene.models.len(), 3);
+        assert_eq!(scene.material("mirror").unwrap().reflectance, 0.9);
+        assert_eq!(scene.uniform("uMode"), Some(&Uniform::Int(2)));
+        assert!(matches!(scene.environment.skybox, Skybox::Gradient { .. }));
+
+        let yaml = scene.to_yaml();
+        let reparsed = Scene::parse(&yaml).expect("saved scene is valid");
+        assert_eq!(reparsed.to_yaml(), yaml);
+        assert_eq!(reparsed.cameras, scene.cameras);
+        assert_eq!(reparsed.models.iter().map(|(_, m)| m).collect::<Vec<_>>(), scene.models.iter().map(|(_, m)| m).collect::<Vec<_>>());
+        assert!(scene.glsl_header().contains("uniform vec3 uTint;\n"));
+    }
+
+    #[test]
+    fn reload_keeps_the_live_camera() {
+        let mut scene = Scene::parse(SOURCE).unwrap();
+        let ids: Vec<ModelId> = scene.models.iter().map(|(id, _)| id).collect();
+        scene.models.create_ss_buffers();
+        scene.cameras.active_mut().tf.x = 7.0;
+
+        let edited = SOURCE.replace("color: red", "color: blue").replace("fov: 3.0", "fov: 2.0");
+        assert_eq!(scene.reload(Scene::parse(&edited).unwrap()), 1);
+        assert_eq!(scene.models[&ids[0]].color, Color::from((0, 0, 255)));
+        assert!(matches!(scene.models.take_changes(), BufferChanges::Partial(ref ranges) if ranges.len() == 1));
+        assert_eq!(scene.cameras.active_name(), "wide");
+        assert_eq!(scene.cameras.active().tf.x, 7.0);
+        assert_eq!(scene.cameras.active().fov, 2.0);
+    }
+
+    #[test]
+    fn validate_rejects_bad_uniforms() {
+        let mut scene = Scene::new();
+        assert!(scene.validate().is_ok());
+        scene.uniforms.push((String::from("uTime"), Uniform::Float(0.0)));
+        assert!(scene.validate().is_err());
+        scene.uniforms[0].0 = String::from("2fast");
+        assert!(scene.validate().is_err());
+        scene.uniforms[0].0 = String::from("uFast");
+        assert!(scene.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_flat_instances() {
+        let mut scene = Scene::parse("scene: {models: [{instances: {prototype: {type: sphere}, transforms: [{}]}}]}").unwrap();
+        let (id, _) = scene.models.iter().next().unwrap();
+        scene.models.instances_mut(id).unwrap()[0].scale = 0.0;
+        assert!(matches!(scene.validate(), Err(SceneError::Invalid(_))));
+    }
+
+    #[test]
+    fn picking() {
+        let mut scene = Scene::parse(SOURCE).expect("scene is valid");
+        let front = scene.cameras.get(scene.cameras.index_of("front").unwrap()).unwrap();
+        let center = front.generate_ray(0.5, 0.5).unwrap();
+
+        // the sphere at the origin fills the center of the image
+        let hit = scene.intersect(&center).expect("sphere is hit");
+        let sphere = scene.models.iter().next().unwrap().0;
+        assert_eq!(hit.model, sphere);
+        assert!((hit.t - 9.0).abs() < 1e-4);
+        assert!((hit.position - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-4);
+        assert!((hit.normal - Vector3::unit_z()).magnitude() < 1e-4);
+
+        // the second instance reports its prototype
+        let prototype = scene.models.iter().nth(2).unwrap().0;
+        let ray = Ray::new(Vector3::new(4.0, 0.0, 10.0), -Vector3::unit_z());
+        assert_eq!(scene.intersect(&ray).map(|h| h.model), Some(prototype));
+
+        assert_eq!(scene.intersect(&Ray::new(Vector3::new(0.0, 0.0, 10.0), Vector3::unit_z())), None);
+        scene.models.remove(sphere);
+        assert_ne!(scene.intersect(&center).map(|h| h.model), Some(sphere));
+    }
+
+    #[test]
+    fn sky_lights_the_scene() {
+        let source = "
+scene:
+  environment:
+    skybox:
+      sky:
+        time: {hour: 15.0, latitude: 0.8, hours_per_second: 0.5}
+        turbidity: 4.0
+";
+        let mut scene = Scene::parse(source).expect("scene is valid");
+        assert_eq!(Scene::parse(&scene.to_yaml()).unwrap().environment, scene.environment);
+        // the sun comes after the lights of the scene
+        let lights = scene.light_data();
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].kind, crate::lights::DIRECTIONAL_LIGHT_ID);
+
+        assert!(scene.environment.is_animated());
+        let before = scene.environment.sun_light().unwrap().direction;
+        assert!(scene.environment.advance(1.0));
+        assert_ne!(scene.environment.sun_light().unwrap().direction, before);
+        assert!(Scene::parse("scene: {environment: {skybox: {sky: {turbidity: 2.0}}}}").is_err());
+    }
+
+    #[test]
+    fn missing_skybox_image_is_an_error() {
+        let source = "
+scene:
+  environment:
+    skybox:
+      equirectangular: does/not/exist.png
+";
+        assert!(matches!(Scene::parse(source), Err(SceneError::Invalid(_))));
+    }
+}
@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+use yaml_rust::{Yaml, YamlEmitter};
+use yaml_rust::yaml::Hash;
+
+use cgmath::Vector3;
+use super::{Scene, Uniform};
+use crate::camera::{Camera, Cameras, Projection};
+use crate::color::Color;
+use crate::environment::Skybox;
+use crate::geospace::Transform;
+use crate::lights::{Light, LightType};
+use crate::material::Material;
+use crate::models::{ModelProperty, ModelType};
+use crate::models::sdf::SdfNode;
+use crate::sky::SunPosition;
+
+// The inverse of `models::parser`, every value is written the way the parser
+// expects it.
+
+fn real(x: f32) -> Yaml {
+    Yaml::Real(format!("{:?}", x))
+}
+
+fn string(s: &str) -> Yaml {
+    Yaml::String(s.to_owned())
+}
+
+fn reals(xs: &[f32]) -> Yaml {
+    Yaml::Array(xs.iter().map(|x| real(*x)).collect())
+}
+
+fn vector3(v: Vector3<f32>) -> Yaml {
+    reals(&[v.x, v.y, v.z])
+}
+
+fn color(c: &Color) -> Yaml {
+    reals(&[c.r, c.g, c.b])
+}
+
+fn hash(entries: Vec<(&str, Yaml)>) -> Yaml {
+    let mut h = Hash::new();
+    for (key, value) in entries {
+        h.insert(string(key), value);
+    }
+    Yaml::Hash(h)
+}
+
+/// Image paths are written relative to `dir`, where the scene is saved
+pub fn write_scene(scene: &Scene, dir: Option<&Path>) -> String {
+    let image = |path: &PathBuf| {
+        let relative = dir.and_then(|dir| path.strip_prefix(dir).ok()).unwrap_or(path);
+        string(&relative.to_string_lossy())
+    };
+    let mut entries = Vec::new();
+
+    if !scene.materials.is_empty() {
+        let mut materials = Hash::new();
+        for (name, m) in &scene.materials {
+            materials.insert(string(name), material(m));
+        }
+        entries.push(("materials", Yaml::Hash(materials)));
+    }
+
+    let models = scene.models.iter().map(|(id, m)| {
+        let model = model(m, scene);
+        match scene.models.instances(id) {
+            Some(transforms) => hash(vec![(
+                "instances",
+                hash(vec![
+                    ("prototype", model),
+                    ("transforms", Yaml::Array(transforms.iter().map(transform).collect())),
+                ]),
+            )]),
+            None => model,
+        }
+    });
+    entries.push(("models", Yaml::Array(models.collect())));
+
+    if !scene.lights.is_empty() {
+        entries.push(("lights", Yaml::Array(scene.lights.iter().map(|(_, l)| light(l)).collect())));
+    }
+
+    entries.extend(cameras(&scene.cameras));
+
+    let skybox = match &scene.environment.skybox {
+        Skybox::Debug => string("debug"),
+        Skybox::Color(c) => color(c),
+        Skybox::Gradient { top, bottom } => hash(vec![("gradient", hash(vec![("top", color(top)), ("bottom", color(bottom))]))]),
+        Skybox::Faces(paths) => {
+            hash(vec![("faces", Yaml::Array(paths.iter().map(image).collect()))])
+        }
+        Skybox::Equirectangular(path) => hash(vec![("equirectangular", image(path))]),
+        Skybox::Sky(sky) => {
+            let sun = match sky.sun {
+                SunPosition::Angles { elevation, azimuth } => {
+                    ("sun", hash(vec![("elevation", real(elevation)), ("azimuth", real(azimuth))]))
+                }
+                SunPosition::Time { hour, latitude, day, hours_per_second } => (
+                    "time",
+                    hash(vec![
+                        ("hour", real(hour)),
+                        ("latitude", real(latitude)),
+                        ("day", real(day)),
+                        ("hours_per_second", real(hours_per_second)),
+                    ]),
+                ),
+            };
+            hash(vec![(
+                "sky",
+                hash(vec![sun, ("turbidity", real(sky.turbidity)), ("intensity", real(sky.sun_intensity))]),
+            )])
+        }
+    };
+    entries.push(("environment", hash(vec![("skybox", skybox)])));
+
+    if !scene.uniforms.is_empty() {
+        let mut uniforms = Hash::new();
+        for (name, u) in &scene.uniforms {
+            let value = match u {
+                Uniform::Int(i) => Yaml::Integer(*i as i64),
+                Uniform::Float(x) => real(*x),
+                Uniform::Vec2(v) => reals(v),
+                Uniform::Vec3(v) => reals(v),
+                Uniform::Vec4(v) => reals(v),
+            };
+            uniforms.insert(string(name), value);
+        }
+        entries.push(("uniforms", Yaml::Hash(uniforms)));
+    }
+
+    let doc = hash(vec![("scene", hash(entries))]);
+    let mut out = String::new();
+    YamlEmitter::new(&mut out).dump(&doc).expect("writing to a string can't fail");
+    out.push('\n');
+    out
+}
+
+fn material(m: &Material) -> Yaml {
+    hash(vec![("reflectance", real(m.reflectance)), ("roughness", real(m.roughness))])
+}
+
+fn transform(tf: &Transform) -> Yaml {
+    let mut entries = vec![("pos", reals(&[tf.x, tf.y, tf.z]))];
+    if tf.scale != 1.0 {
+        entries.push(("scale", real(tf.scale)));
+    }
+    if tf.head != 0.0 || tf.pitch != 0.0 || tf.rotate != 0.0 {
+        entries.push((
+            "orientation",
+            hash(vec![("head", real(tf.head)), ("pitch", real(tf.pitch)), ("roll", real(tf.rotate))]),
+        ));
+    }
+    hash(entries)
+}
+
+fn model(m: &ModelProperty, scene: &Scene) -> Yaml {
+    let mut entries = vec![("type", string(m.t.name()))];
+    match &m.t {
+        ModelType::Box(w, h, d) => entries.push(("size", reals(&[*w, *h, *d]))),
+        ModelType::Sdf(shape) => entries.push(("shape", sdf_node(shape))),
+        ModelType::Sphere | ModelType::Plane => {}
+    }
+    entries.push(("transform", transform(&m.tf)));
+    entries.push(("color", color(&m.color)));
+
+    // refer to a named material if there is an equal one
+    let material = match scene.materials.iter().find(|(_, named)| *named == m.material) {
+        Some((name, _)) => string(name),
+        None => material(&m.material),
+    };
+    entries.push(("material", material));
+    hash(entries)
+}
+
+fn sdf_node(node: &SdfNode) -> Yaml {
+    let operation = |name: &str, a: &SdfNode, b: &SdfNode| hash(vec![(name, Yaml::Array(vec![sdf_node(a), sdf_node(b)]))]);
+    let smooth = |name: &str, a: &SdfNode, b: &SdfNode, k: f32| {
+        hash(vec![(name, hash(vec![("k", real(k)), ("of", Yaml::Array(vec![sdf_node(a), sdf_node(b)]))]))])
+    };
+
+    match node {
+        SdfNode::Sphere { pos, radius } => {
+            hash(vec![("sphere", hash(vec![("pos", vector3(*pos)), ("radius", real(*radius))]))])
+        }
+        SdfNode::Box { pos, size } => {
+            hash(vec![("box", hash(vec![("pos", vector3(*pos)), ("size", vector3(*size))]))])
+        }
+        SdfNode::RoundBox { pos, size, radius } => hash(vec![(
+            "round_box",
+            hash(vec![("pos", vector3(*pos)), ("size", vector3(*size)), ("radius", real(*radius))]),
+        )]),
+        SdfNode::Torus { pos, major, minor } => hash(vec![(
+            "torus",
+            hash(vec![("pos", vector3(*pos)), ("major", real(*major)), ("minor", real(*minor))]),
+        )]),
+        SdfNode::Custom { pos, code, bound, .. } => hash(vec![(
+            "glsl",
+            hash(vec![("pos", vector3(*pos)), ("code", string(code)), ("bound", real(*bound))]),
+        )]),
+        SdfNode::Union(a, b) => operation("union", a, b),
+        SdfNode::Intersection(a, b) => operation("intersection", a, b),
+        SdfNode::Difference(a, b) => operation("difference", a, b),
+        SdfNode::SmoothUnion(a, b, k) => smooth("smooth_union", a, b, *k),
+        SdfNode::SmoothIntersection(a, b, k) => smooth("smooth_intersection", a, b, *k),
+        SdfNode::SmoothDifference(a, b, k) => smooth("smooth_difference", a, b, *k),
+    }
+}
+
+fn light(l: &Light) -> Yaml {
+    let type_name = match l.t {
+        LightType::Point => "point",
+        LightType::Directional => "directional",
+        LightType::Spot { .. } => "spot",
+        LightType::Sphere { .. } => "sphere",
+        LightType::Rect { .. } => "rect",
+    };
+    let mut entries = vec![
+        ("type", string(type_name)),
+        ("pos", vector3(l.position)),
+        ("direction", vector3(l.direction)),
+        ("color", color(&l.color)),
+        ("intensity", real(l.intensity)),
+    ];
+    match l.t {
+        LightType::Point | LightType::Directional => {}
+        LightType::Spot { inner, outer } => {
+            entries.push(("inner", real(inner)));
+            entries.push(("outer", real(outer)));
+        }
+        LightType::Sphere { radius } => entries.push(("radius", real(radius))),
+        LightType::Rect { width, height } => {
+            entries.push(("width", real(width)));
+            entries.push(("height", real(height)));
+        }
+    }
+    hash(entries)
+}
+
+fn camera(name: Option<&str>, c: &Camera) -> Yaml {
+    let projection = match c.projection {
+        Projection::Perspective => "perspective",
+        Projection::Orthographic => "orthographic",
+        Projection::Fisheye => "fisheye",
+        Projection::Equirectangular => "equirectangular",
+    };
+    let mut entries = Vec::new();
+    if let Some(name) = name {
+        entries.push(("name", string(name)));
+    }
+    entries.extend([
+        ("position", reals(&[c.tf.x, c.tf.y, c.tf.z])),
+        ("pitch", real(c.tf.pitch)),
+        ("yaw", real(c.tf.head)),
+        ("projection", string(projection)),
+        ("fov", real(c.fov)),
+        ("aperture", real(c.aperture)),
+        ("focus_distance", real(c.focus_distance)),
+    ]);
+    hash(entries)
+}
+
+/// A single unnamed camera is written as `camera`
+fn cameras(cameras: &Cameras) -> Vec<(&'static str, Yaml)> {
+    if cameras.len() == 1 && cameras.active_name() == Cameras::DEFAULT_NAME {
+        return vec![("camera", camera(None, cameras.active()))];
+    }
+    vec![
+        ("cameras", Yaml::Array(cameras.iter().map(|(name, c)| camera(Some(name), c)).collect())),
+        ("active_camera", string(cameras.active_name())),
+    ]
+}
@@ -0,0 +1,228 @@
+use std::f32::consts::PI;
+
+use cgmath::{InnerSpace, Vector3};
+use crate::color::Color;
+use crate::lights::{Light, LightType};
+
+/// Scales the luminance of the model, in kcd/m², to the range of the other
+/// skyboxes
+pub const SKY_EXPOSURE: f32 = 0.05;
+/// Angular radius of the sun disk in the sky, several times the real one so
+/// that it covers a few texels of the cube map
+pub const SUN_ANGULAR_RADIUS: f32 = 0.02;
+/// Radiance of the sun disk before the atmosphere
+pub const SUN_DISK_RADIANCE: f32 = 20.0;
+/// Optical depth of one air mass per rgb channel at turbidity 3, shorter
+/// wavelengths are scattered more
+const OPTICAL_DEPTH: [f32; 3] = [0.06, 0.12, 0.25];
+/// Fraction of the horizon light reflected by the ground below it
+pub const GROUND_ALBEDO: f32 = 0.3;
+
+/// Where the sun is. Azimuths are measured from -z (north) towards +x
+/// (east), elevations from the horizon, both in radians.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SunPosition {
+    Angles { elevation: f32, azimuth: f32 },
+    /// `hour` in local solar time, `latitude` in radians, `day` of the year
+    /// from 1. `hours_per_second` lets the day go by while rendering.
+    Time { hour: f32, latitude: f32, day: f32, hours_per_second: f32 },
+}
+
+impl SunPosition {
+    /// Elevation and azimuth
+    pub fn angles(&self) -> (f32, f32) {
+        match *self {
+            SunPosition::Angles { elevation, azimuth } => (elevation, azimuth),
+            SunPosition::Time { hour, latitude, day, .. } => {
+                let declination = 23.44f32.to_radians() * (2.0 * PI * (284.0 + day) / 365.0).sin();
+                let hour_angle = (15.0 * (hour - 12.0)).to_radians();
+                let sin_elevation = latitude.sin() * declination.sin()
+                    + latitude.cos() * declination.cos() * hour_angle.cos();
+                let elevation = sin_elevation.clamp(-1.0, 1.0).asin();
+
+                let cos_azimuth = (declination.sin() - sin_elevation * latitude.sin())
+                    / (elevation.cos() * latitude.cos()).max(1e-6);
+                let azimuth = cos_azimuth.clamp(-1.0, 1.0).acos();
+                // the sun is in the west in the afternoon
+                (elevation, if hour_angle > 0.0 { 2.0 * PI - azimuth } else { azimuth })
+            }
+        }
+    }
+
+    /// Unit vector towards the sun
+    pub fn direction(&self) -> Vector3<f32> {
+        let (elevation, azimuth) = self.angles();
+        Vector3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        )
+    }
+
+    /// Moves the time of day forward, returns whether the sun moved
+    pub fn advance(&mut self, seconds: f32) -> bool {
+        match self {
+            SunPosition::Time { hour, hours_per_second, .. } if *hours_per_second != 0.0 => {
+                *hour = (*hour + *hours_per_second * seconds).rem_euclid(24.0);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The analytic daylight model of Preetham, Shirley and Smits
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sky {
+    pub sun: SunPosition,
+    /// haziness of the air, from 2 for a clear sky to 10
+    pub turbidity: f32,
+    /// intensity of the sun as a directional light
+    pub sun_intensity: f32,
+}
+
+/// Perez distribution of the luminance over the sky, relative to the zenith
+fn perez(coefficients: [f32; 5], cos_theta: f32, gamma: f32) -> f32 {
+    let [a, b, c, d, e] = coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+impl Sky {
+    pub fn new(sun: SunPosition) -> Self {
+        Sky { sun, turbidity: 3.0, sun_intensity: 3.0 }
+    }
+
+    /// Fraction of the sunlight per rgb channel that makes it through the
+    /// atmosphere, zero once the sun has set
+    pub fn sun_transmittance(&self) -> [f32; 3] {
+        let (elevation, _) = self.sun.angles();
+        if elevation <= 0.0 {
+            return [0.0; 3];
+        }
+        // air mass of kasten and young
+        let zenith_degrees = 90.0 - elevation.to_degrees();
+        let air_mass = 1.0 / (elevation.sin() + 0.50572 * (96.07995 - zenith_degrees).powf(-1.6364));
+        let sunset = (elevation / 0.05).min(1.0);
+        OPTICAL_DEPTH.map(|tau| (-tau * self.turbidity / 3.0 * air_mass).exp() * sunset)
+    }
+
+    /// Linear rgb radiance seen in the direction `d`. Below the horizon is
+    /// the ground, lit by the horizon.
+    pub fn radiance(&self, d: Vector3<f32>) -> [f32; 3] {
+        let sun = self.sun.direction();
+        let (elevation, _) = self.sun.angles();
+        // the model holds for a sun above the horizon, the sky fades out
+        // during the twilight
+        let theta_s = (PI / 2.0 - elevation).min(PI / 2.0 - 0.01);
+        let twilight = ((elevation + 0.1) / 0.15).clamp(0.0, 1.0);
+        let twilight = twilight * twilight * (3.0 - 2.0 * twilight);
+        if twilight == 0.0 {
+            return [0.0; 3];
+        }
+
+        let below = d.y < 0.0;
+        let d = if below { Vector3::new(d.x, 0.0, d.z).normalize() } else { d.normalize() };
+        let cos_theta = d.y.max(0.01);
+        let gamma = d.dot(sun).clamp(-1.0, 1.0).acos();
+
+        let t = self.turbidity;
+        let coefficients_y = [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703];
+        let coefficients_x = [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452];
+        let coefficients_yc = [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (s1, s2, s3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * s3 - 0.00375 * s2 + 0.00209 * s1)
+            + t * (-0.02903 * s3 + 0.06377 * s2 - 0.03202 * s1 + 0.00394)
+            + (0.11693 * s3 - 0.21196 * s2 + 0.06052 * s1 + 0.25886);
+        let zenith_yc = t * t * (0.00275 * s3 - 0.00610 * s2 + 0.00317 * s1)
+            + t * (-0.04214 * s3 + 0.08970 * s2 - 0.04153 * s1 + 0.00516)
+            + (0.15346 * s3 - 0.26756 * s2 + 0.06670 * s1 + 0.26688);
+
+        let relative = |c: [f32; 5]| perez(c, cos_theta, gamma) / perez(c, 1.0, theta_s);
+        let mut luminance = zenith_y * relative(coefficients_y) * SKY_EXPOSURE * twilight;
+        let x = zenith_x * relative(coefficients_x);
+        let y = zenith_yc * relative(coefficients_yc);
+
+        if below {
+            luminance *= GROUND_ALBEDO;
+        }
+
+        // xyY to xyz to linear srgb
+        let big_x = x / y * luminance;
+        let big_z = (1.0 - x - y) / y * luminance;
+        let mut rgb = [
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        ]
+        .map(|c| c.max(0.0));
+
+        if !below && gamma < SUN_ANGULAR_RADIUS {
+            let sun = self.sun_transmittance();
+            for (c, s) in rgb.iter_mut().zip(sun) {
+                *c += s * SUN_DISK_RADIANCE;
+            }
+        }
+        rgb
+    }
+
+    /// The sun as a directional light, reddening and dimming as it sets
+    pub fn sun_light(&self) -> Light {
+        let rgb = self.sun_transmittance();
+        let brightest = rgb[0].max(rgb[1]).max(rgb[2]);
+        let mut light = Light::new(LightType::Directional);
+        light.direction = -self.sun.direction();
+        if brightest > 0.0 {
+            light.color = Color { r: rgb[0] / brightest, g: rgb[1] / brightest, b: rgb[2] / brightest };
+        }
+        light.intensity = self.sun_intensity * brightest;
+        light
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_follows_the_day() {
+        // equator at the equinox: rises in the east, overhead at noon
+        let at = |hour: f32| SunPosition::Time { hour, latitude: 0.0, day: 80.0, hours_per_second: 0.0 };
+        let (elevation, azimuth) = at(6.0).angles();
+        assert!(elevation.abs() < 0.02 && (azimuth - PI / 2.0).abs() < 0.02);
+        assert!((at(12.0).angles().0 - PI / 2.0).abs() < 0.02);
+        assert!((at(18.0).angles().1 - 3.0 * PI / 2.0).abs() < 0.02);
+        assert!((at(18.0).direction() - Vector3::new(-1.0, 0.0, 0.0)).magnitude() < 0.02);
+
+        let mut sun = SunPosition::Time { hour: 23.5, latitude: 0.8, day: 172.0, hours_per_second: 1.0 };
+        assert!(sun.advance(1.0));
+        assert!(matches!(sun, SunPosition::Time { hour, .. } if (hour - 0.5).abs() < 1e-4));
+        assert!(!SunPosition::Angles { elevation: 1.0, azimuth: 0.0 }.advance(1.0));
+    }
+
+    #[test]
+    fn daylight() {
+        let sky = Sky::new(SunPosition::Angles { elevation: 1.2, azimuth: 0.0 });
+        let zenith = sky.radiance(Vector3::unit_y());
+        // blue, and about as bright as the other skyboxes
+        assert!(zenith[2] > zenith[0] && zenith[1] > zenith[0]);
+        assert!(zenith[2] > 0.2 && zenith[2] < 2.0);
+        // the sun disk and the ground
+        assert!(sky.radiance(sky.sun.direction())[0] > 5.0 * zenith[0]);
+        assert!(sky.radiance(-Vector3::unit_y())[2] < zenith[2]);
+
+        let light = sky.sun_light();
+        assert!((light.direction + sky.sun.direction()).magnitude() < 1e-6);
+        assert!(light.intensity > 0.0);
+        // white at noon, red at sunset
+        assert!(light.color.b > 0.7);
+        let sunset = Sky::new(SunPosition::Angles { elevation: 0.05, azimuth: 0.0 }).sun_light();
+        assert!(sunset.color.b < 0.3 && sunset.intensity < light.intensity);
+
+        let night = Sky::new(SunPosition::Angles { elevation: -0.5, azimuth: 0.0 });
+        assert_eq!(night.radiance(Vector3::unit_y()), [0.0; 3]);
+        assert_eq!(night.sun_light().intensity, 0.0);
+    }
+}